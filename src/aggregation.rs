@@ -0,0 +1,255 @@
+use crate::flow::{FlowMethod, FlowModel};
+use crate::traversal;
+
+/// Statistic of raster values over the upstream area or along the downstream path of a cell.
+///
+/// Sums, means and fractions weight every cell by the share of flow connecting it to the
+/// cell being evaluated (always 1 for D8); minima and maxima take every cell with a
+/// non-zero share. Cells with non-finite raster values are left out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reducer {
+    Sum,
+    Mean,
+    Min,
+    Max,
+    /// Area-weighted share of the cells whose value equals the class code (e.g. a land cover class)
+    Fraction(f32),
+}
+
+impl Reducer {
+    /// Parse a reducer name: "sum", "mean", "min", "max" or "fraction <class>"
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim().to_lowercase();
+        let mut words = text.split_whitespace();
+        let reducer = match words.next()? {
+            "sum" => Reducer::Sum,
+            "mean" => Reducer::Mean,
+            "min" => Reducer::Min,
+            "max" => Reducer::Max,
+            "fraction" => Reducer::Fraction(words.next()?.parse().ok()?),
+            _ => return None,
+        };
+        words.next().is_none().then_some(reducer)
+    }
+}
+
+impl FlowModel {
+    /// Reduce a raster over the upstream area of every cell, the cell itself included.
+    ///
+    /// `values` must be aligned with the DEM (row-major, one value per cell). Cells
+    /// without elevation data, and cells without any valid value for means and extremes,
+    /// get NaN. For MFD the proportions of the last `compute_flow_accumulation` are reused.
+    pub fn aggregate_upstream(&self, values: &[f32], reducer: Reducer) -> Result<Vec<f32>, String> {
+        self.check_aggregation_input(values)?;
+        let width = self.dem.width;
+        let is_valid = |idx: usize| self.dem.get_elevation(idx % width, idx / width).is_some();
+
+        let aggregated = self.aggregate(
+            values,
+            reducer,
+            |idx| self.receivers(idx).map(|(downstream_idx, _)| downstream_idx),
+            |idx| self.donors(idx).filter(move |&(upstream_idx, _)| is_valid(upstream_idx)),
+        );
+        println!("Aggregated {:?} over the upstream area of {} cells", reducer, aggregated.len());
+        Ok(aggregated)
+    }
+
+    /// Reduce a raster along the downstream flow path of every cell, from the cell itself
+    /// to where the path ends (DEM edge, sink or nodata), e.g. the minimum slope downstream.
+    ///
+    /// Where flow splits (D∞, MFD) sums and means are expectations over the branches,
+    /// weighted by their shares. Inputs and nodata handling as for `aggregate_upstream`.
+    pub fn aggregate_downstream(&self, values: &[f32], reducer: Reducer) -> Result<Vec<f32>, String> {
+        self.check_aggregation_input(values)?;
+        let width = self.dem.width;
+        let is_valid = |idx: usize| self.dem.get_elevation(idx % width, idx / width).is_some();
+
+        // The same traversal on the reversed graph: settle cells once all of their receivers are
+        let aggregated = self.aggregate(
+            values,
+            reducer,
+            |idx| {
+                self.donors(idx)
+                    .filter(move |&(upstream_idx, _)| is_valid(idx) && is_valid(upstream_idx))
+                    .map(|(upstream_idx, _)| upstream_idx)
+            },
+            |idx| self.receivers(idx).filter(move |&(downstream_idx, _)| is_valid(downstream_idx)),
+        );
+        println!("Aggregated {:?} along the downstream path of {} cells", reducer, aggregated.len());
+        Ok(aggregated)
+    }
+
+    fn check_aggregation_input(&self, values: &[f32]) -> Result<(), String> {
+        let cell_count = self.dem.width * self.dem.height;
+        if values.len() != cell_count {
+            return Err(format!("Value grid has {} values, expected {} ({}x{})",
+                               values.len(), cell_count, self.dem.width, self.dem.height));
+        }
+        if self.flow_method == FlowMethod::MFD && self.mfd_flow_proportions.is_none() {
+            return Err("MFD proportions not computed. Run compute_flow_accumulation first.".to_string());
+        }
+        Ok(())
+    }
+
+    /// Settle every cell in the order given by `links` (the cells each one passes its
+    /// result on to) while pulling the results of its `inputs` with their shares
+    fn aggregate<L, LI, N, NI>(&self, values: &[f32], reducer: Reducer, links: L, inputs: N) -> Vec<f32>
+    where
+        L: Fn(usize) -> LI + Sync,
+        LI: Iterator<Item = usize>,
+        N: Fn(usize) -> NI + Sync,
+        NI: Iterator<Item = (usize, f32)>,
+    {
+        let width = self.dem.width;
+        let cell_count = values.len();
+        let is_valid = |idx: usize| self.dem.get_elevation(idx % width, idx / width).is_some();
+        let value = |idx: usize| Some(values[idx]).filter(|value| value.is_finite());
+
+        let aggregated: Vec<f32> = match reducer {
+            Reducer::Sum | Reducer::Mean | Reducer::Fraction(_) => {
+                // Weighted sum of the values and of the weights of the cells that have one
+                let (totals, _) = traversal::settle_topological(
+                    cell_count,
+                    is_valid,
+                    &links,
+                    |idx, totals: &[(f64, f64)]| {
+                        let own = match (reducer, value(idx)) {
+                            (_, None) => (0.0, 0.0),
+                            (Reducer::Fraction(class), Some(value)) => (if value == class { 1.0 } else { 0.0 }, 1.0),
+                            (_, Some(value)) => (value as f64, 1.0),
+                        };
+                        inputs(idx).fold(own, |(sum, weight), (input_idx, share)| {
+                            (sum + totals[input_idx].0 * share as f64, weight + totals[input_idx].1 * share as f64)
+                        })
+                    },
+                );
+                totals.iter()
+                    .map(|&(sum, weight)| match reducer {
+                        Reducer::Sum => sum as f32,
+                        _ if weight > 0.0 => (sum / weight) as f32,
+                        _ => f32::NAN,
+                    })
+                    .collect()
+            }
+            Reducer::Min | Reducer::Max => {
+                // f32::min and f32::max return the other operand when one is NaN
+                let combine = |a: f32, b: f32| if reducer == Reducer::Min { a.min(b) } else { a.max(b) };
+                traversal::settle_topological(
+                    cell_count,
+                    is_valid,
+                    &links,
+                    |idx, extremes: &[f32]| {
+                        inputs(idx).fold(value(idx).unwrap_or(f32::NAN), |extreme, (input_idx, _)| {
+                            combine(extreme, extremes[input_idx])
+                        })
+                    },
+                ).0
+            }
+        };
+
+        aggregated.into_iter()
+            .enumerate()
+            .map(|(idx, aggregate)| if is_valid(idx) { aggregate } else { f32::NAN })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{FlowDirection, MfdOptions};
+    use crate::test_support::{ramp, routed, valley};
+
+    /// 6x1 eastward ramp with a nodata cell at x = 3 that every cell drains towards
+    fn holed_row() -> FlowModel {
+        let mut dem = ramp(6, 1, 10.0, 10.0, 0.0, 0.1);
+        dem.data[3] = f32::NAN;
+        let mut model = routed(dem, FlowMethod::D8);
+        model.flow_directions = vec![FlowDirection::East; 6];
+        model.compute_flow_accumulation();
+        model
+    }
+
+    #[test]
+    fn upstream_and_downstream_aggregation() {
+        let model = valley();
+        let ones = vec![1.0; 630];
+        let elevations = model.dem.data.clone();
+        let upstream = |values: &[f32], reducer| model.aggregate_upstream(values, reducer).unwrap();
+        let downstream = |values: &[f32], reducer| model.aggregate_downstream(values, reducer).unwrap();
+        let outlet = 29 * 21 + 10;
+
+        assert_eq!(upstream(&ones, Reducer::Sum), model.flow_accumulation);
+        assert_eq!(upstream(&elevations, Reducer::Max)[outlet], 144.5);
+        assert_eq!(upstream(&elevations, Reducer::Min)[outlet], 100.0);
+        // Land cover class 1 west of the valley axis
+        let west: Vec<f32> = (0..630).map(|idx| if idx % 21 < 10 { 1.0 } else { 0.0 }).collect();
+        assert!((upstream(&west, Reducer::Fraction(1.0))[outlet] - 300.0 / 630.0).abs() < 1e-6);
+
+        // From the top-left corner 10 cells east to the valley, then down it to the outlet
+        assert_eq!(downstream(&ones, Reducer::Sum)[0], 40.0);
+        assert_eq!(downstream(&model.slopes, Reducer::Max)[0], 0.3);
+        assert_eq!(downstream(&model.slopes, Reducer::Min)[10], 0.0);
+        assert!((downstream(&elevations, Reducer::Mean)[10] - 107.25).abs() < 1e-4);
+
+        // Proportional routing: fractional shares of a constant still average to it
+        let dinf = routed(ramp(9, 9, 10.0, 10.0, 30.0_f32.to_radians(), 0.1), FlowMethod::DInf);
+        let sums = dinf.aggregate_upstream(&[1.0; 81], Reducer::Sum).unwrap();
+        assert!(sums.iter().zip(&dinf.flow_accumulation).all(|(a, b)| (a - b).abs() < 1e-3));
+        assert!(dinf.aggregate_downstream(&[2.5; 81], Reducer::Mean).unwrap().iter().all(|mean| (mean - 2.5).abs() < 1e-5));
+    }
+
+    #[test]
+    fn aggregation_stops_at_nodata_holes() {
+        let model = holed_row();
+        let ones = [1.0; 6];
+
+        let downstream = model.aggregate_downstream(&ones, Reducer::Sum).unwrap();
+        assert_eq!(downstream[..3], [3.0, 2.0, 1.0]);
+        assert!(downstream[3].is_nan());
+        assert_eq!(downstream[4..], [2.0, 1.0]);
+
+        // Nothing upstream of the hole reaches the cells below it
+        let upstream = model.aggregate_upstream(&ones, Reducer::Sum).unwrap();
+        assert_eq!((upstream[2], upstream[5]), (3.0, 2.0));
+        let maxima = model.aggregate_upstream(&[1.0, 2.0, 3.0, 9.0, 4.0, 5.0], Reducer::Max).unwrap();
+        assert_eq!((maxima[2], maxima[5]), (3.0, 5.0));
+        assert!(maxima[3].is_nan());
+    }
+
+    #[test]
+    fn non_finite_values_are_left_out() {
+        let model = holed_row();
+        let values = [1.0, f32::NAN, 3.0, 0.0, f32::INFINITY, f32::NAN];
+
+        let upstream = |reducer| model.aggregate_upstream(&values, reducer).unwrap();
+        assert_eq!(upstream(Reducer::Sum)[2], 4.0);
+        assert_eq!(upstream(Reducer::Mean)[2], 2.0);
+        assert_eq!(upstream(Reducer::Min)[2], 1.0);
+        assert_eq!(upstream(Reducer::Fraction(3.0))[2], 0.5);
+
+        // Without any valid value means and extremes are undefined, sums are zero
+        assert!(upstream(Reducer::Mean)[5].is_nan() && upstream(Reducer::Max)[5].is_nan());
+        assert_eq!(upstream(Reducer::Sum)[5], 0.0);
+    }
+
+    #[test]
+    fn invalid_inputs_and_reducer_names_are_rejected() {
+        let model = valley();
+        assert!(model.aggregate_upstream(&[1.0; 10], Reducer::Sum).is_err());
+        assert!(model.aggregate_downstream(&[1.0; 631], Reducer::Sum).is_err());
+
+        // MFD needs the proportions of an accumulation run
+        let mut mfd = FlowModel::new(ramp(4, 4, 10.0, 10.0, 0.0, 0.1));
+        mfd.compute_flow_directions_mfd(MfdOptions::default());
+        assert!(mfd.aggregate_upstream(&[1.0; 16], Reducer::Sum).is_err());
+        mfd.compute_flow_accumulation();
+        assert!(mfd.aggregate_upstream(&[1.0; 16], Reducer::Sum).is_ok());
+
+        assert_eq!(Reducer::parse(" Max "), Some(Reducer::Max));
+        assert_eq!(Reducer::parse("fraction 21"), Some(Reducer::Fraction(21.0)));
+        assert_eq!(Reducer::parse("fraction"), None);
+        assert_eq!(Reducer::parse("sum of all"), None);
+        assert_eq!(Reducer::parse("median"), None);
+    }
+}
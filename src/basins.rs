@@ -0,0 +1,180 @@
+use crate::flow::FlowModel;
+use crate::traversal::PathWalker;
+use serde::{Serialize, Deserialize};
+
+/// Basin id used for cells without elevation data
+pub const NO_BASIN: u32 = 0;
+
+/// Where a basin's water ends up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutletKind {
+    Edge,    // Leaves the DEM across its outer edge
+    Sink,    // Internal depression without outflow
+    Nodata,  // Drains into a nodata area (ocean, masked lake, ...)
+}
+
+/// One drainage basin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Basin {
+    /// 1-based id as stored in `BasinMap::labels`, largest outlet accumulation first
+    pub id: u32,
+    /// Terminal cell all other basin cells drain to
+    pub outlet: (usize, usize),
+    pub outlet_kind: OutletKind,
+    /// Flow accumulation at the outlet cell
+    pub outlet_accumulation: f32,
+    /// Number of cells labelled with this basin
+    pub cell_count: usize,
+    /// Basin area in m²
+    pub area_m2: f64,
+    /// The outlet's upstream area reaches the DEM edge or nodata, so its accumulation
+    /// may be underestimated (see `FlowModel::edge_contamination`)
+    pub contaminated: bool,
+}
+
+/// Basin label for every cell plus the basin table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BasinMap {
+    /// Basin id per cell (`NO_BASIN` for cells without elevation data)
+    pub labels: Vec<u32>,
+    pub basins: Vec<Basin>,
+}
+
+impl FlowModel {
+    /// Assign every valid cell to the outlet it drains to.
+    ///
+    /// Cells follow their downstream cell (the dominant receiver for D∞ and MFD) until
+    /// a cell without one is reached; that cell is the basin outlet.
+    pub fn label_basins(&self) -> BasinMap {
+        println!("Labelling drainage basins...");
+
+        let width = self.dem.width;
+        let height = self.dem.height;
+        let cell_count = width * height;
+
+        // Outlet cell index per cell, resolved with path compression
+        let mut outlet_of = vec![usize::MAX; cell_count];
+        let mut walker = PathWalker::new(cell_count);
+        let mut path = Vec::new();
+        for start in 0..cell_count {
+            if walker.is_resolved(start) || self.dem.get_elevation(start % width, start / width).is_none() {
+                continue;
+            }
+
+            let outlet = match walker.walk(start, |idx| self.downstream_index(idx), &mut path) {
+                Some(joined) => outlet_of[joined],
+                None => *path.last().unwrap(),
+            };
+            for cell in path.drain(..) {
+                outlet_of[cell] = outlet;
+            }
+        }
+
+        // Number the outlets, largest accumulation first
+        let mut outlets: Vec<usize> = (0..cell_count).filter(|&idx| outlet_of[idx] == idx).collect();
+        outlets.sort_by(|&a, &b| {
+            self.flow_accumulation[b].partial_cmp(&self.flow_accumulation[a])
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.cmp(&b))
+        });
+
+        let contaminated = self.edge_contamination();
+        let mut id_of_outlet = vec![NO_BASIN; cell_count];
+        let mut basins: Vec<Basin> = outlets.iter()
+            .enumerate()
+            .map(|(i, &idx)| {
+                id_of_outlet[idx] = i as u32 + 1;
+                Basin {
+                    id: i as u32 + 1,
+                    outlet: (idx % width, idx / width),
+                    outlet_kind: self.outlet_kind(idx % width, idx / width),
+                    outlet_accumulation: self.flow_accumulation[idx],
+                    cell_count: 0,
+                    area_m2: 0.0,
+                    contaminated: contaminated[idx],
+                }
+            })
+            .collect();
+
+        let cell_area = self.dem.cell_area();
+        let labels: Vec<u32> = outlet_of.iter()
+            .map(|&outlet| {
+                if outlet == usize::MAX {
+                    return NO_BASIN;
+                }
+                let id = id_of_outlet[outlet];
+                let basin = &mut basins[(id - 1) as usize];
+                basin.cell_count += 1;
+                basin.area_m2 += cell_area;
+                id
+            })
+            .collect();
+
+        println!("Found {} drainage basins", basins.len());
+        BasinMap { labels, basins }
+    }
+
+    /// Classify a terminal cell: on the DEM edge, next to nodata, or an internal sink
+    fn outlet_kind(&self, x: usize, y: usize) -> OutletKind {
+        let hood = self.neighborhood();
+        if hood.is_edge(x, y) {
+            OutletKind::Edge
+        } else if hood.neighbors(x, y).any(|(_, (nx, ny))| self.dem.get_elevation(nx, ny).is_none()) {
+            OutletKind::Nodata
+        } else {
+            OutletKind::Sink
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dem::DigitalElevationModel;
+    use crate::flow::FlowDirection;
+
+    /// 7x4 grid with valleys along columns 1 and 4 falling to the south, split between
+    /// columns 2 and 3, and a nodata column on the east
+    fn two_valleys() -> FlowModel {
+        let data = (0..7 * 4)
+            .map(|idx| {
+                let (x, y) = ((idx % 7) as f32, (idx / 7) as f32);
+                let valley = if x < 3.0 { 1.0 } else { 4.0 };
+                if x == 6.0 { f32::NAN } else { 10.0 + 2.0 * (x - valley).abs() + (3.0 - y) }
+            })
+            .collect();
+        let mut model = FlowModel::new(DigitalElevationModel::new(7, 4, 10.0, data));
+        model.compute_flow_directions();
+        model.compute_flow_accumulation();
+        model
+    }
+
+    #[test]
+    fn every_valid_cell_gets_the_basin_of_its_outlet() {
+        let map = two_valleys().label_basins();
+        assert_eq!(map.basins.len(), 2);
+        let summary: Vec<_> = map.basins.iter()
+            .map(|basin| (basin.id, basin.outlet, basin.outlet_kind, basin.cell_count, basin.area_m2))
+            .collect();
+        assert_eq!(summary, vec![
+            (1, (1, 3), OutletKind::Edge, 12, 1200.0),
+            (2, (4, 3), OutletKind::Edge, 12, 1200.0),
+        ]);
+        for y in 0..4 {
+            let row: Vec<u32> = (0..7).map(|x| map.labels[y * 7 + x]).collect();
+            assert_eq!(row, vec![1, 1, 1, 2, 2, 2, NO_BASIN]);
+        }
+    }
+
+    #[test]
+    fn cyclic_directions_do_not_hang_labelling() {
+        let mut model = two_valleys();
+        model.flow_directions[7 + 1] = FlowDirection::East;
+        model.flow_directions[7 + 2] = FlowDirection::West;
+        let map = model.label_basins();
+        assert_eq!(map.labels.iter().filter(|&&label| label != NO_BASIN).count(), 24);
+        assert_eq!(map.basins.iter().map(|basin| basin.cell_count).sum::<usize>(), 24);
+        // The two cells end in one basin, cut where the walk closed the cycle
+        assert_eq!(map.labels[7 + 1], map.labels[7 + 2]);
+    }
+}
@@ -0,0 +1,289 @@
+// Stream initiation criteria beyond a plain accumulation threshold.
+//
+// Slope-area and curvature modes mark channel heads cell by cell and extend the
+// channels down the single downstream path of every head. The constant-drop mode
+// picks an accumulation threshold from the DEM itself (Tarboton et al. 1991): below
+// the right threshold first-order streams fall by as much as higher-order ones.
+
+use crate::flow::{FlowModel, StreamThreshold};
+use crate::stream_order::StreamOrders;
+use crate::traversal::PathWalker;
+use serde::{Serialize, Deserialize};
+
+/// Number of candidate thresholds tested by the constant-drop analysis
+const DROP_CANDIDATE_COUNT: usize = 16;
+
+/// Smallest candidate threshold of the constant-drop analysis in upstream cells
+const DROP_MIN_CELLS: f32 = 10.0;
+
+/// Largest |t| at which the mean drops of first-order and higher-order streams count as equal
+const DROP_T_LIMIT: f64 = 2.0;
+
+/// Result of the constant-drop test for one candidate threshold
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DropTest {
+    /// Candidate threshold in upstream cells
+    pub threshold_cells: f32,
+    pub first_order_count: usize,
+    pub higher_order_count: usize,
+    /// Mean elevation drop of first-order and higher-order Strahler streams in m
+    pub first_order_mean_drop: f64,
+    pub higher_order_mean_drop: f64,
+    /// Welch's t statistic of the two means (None with fewer than two streams on either side)
+    pub t: Option<f64>,
+}
+
+/// Constant-drop analysis over log-spaced candidate thresholds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DropAnalysis {
+    /// Candidates from the smallest threshold up
+    pub tests: Vec<DropTest>,
+    /// Smallest threshold with |t| < 2, else the one closest to it (None if no candidate could be tested)
+    pub threshold_cells: Option<f32>,
+}
+
+impl FlowModel {
+    /// Stream cells under any stream initiation criterion, one flag per cell
+    pub fn stream_mask(&self, threshold: StreamThreshold) -> Vec<bool> {
+        let width = self.dem.width;
+        let cell_count = width * self.dem.height;
+        match threshold {
+            StreamThreshold::SlopeArea { constant, exponent } => {
+                let area = self.contributing_area_m2();
+                let heads: Vec<bool> = (0..cell_count)
+                    .map(|idx| area[idx] as f64 * (self.slopes[idx] as f64).powf(exponent as f64) >= constant)
+                    .collect();
+                self.extend_downstream(heads)
+            }
+            StreamThreshold::Curvature { min_curvature, valley_cells } => {
+                let valley: Vec<f32> = self.laplacian_curvature().iter()
+                    .map(|&curvature| if curvature > min_curvature { 1.0 } else { 0.0 })
+                    .collect();
+                let heads = match self.compute_weighted_accumulation(&valley, None) {
+                    Ok(valley_area) => valley_area.iter().map(|&count| count >= valley_cells).collect(),
+                    Err(_) => vec![false; cell_count],
+                };
+                self.extend_downstream(heads)
+            }
+            _ => {
+                let threshold = threshold.accumulation_threshold(self);
+                (0..cell_count)
+                    .map(|idx| {
+                        self.dem.get_elevation(idx % width, idx / width).is_some()
+                            && self.flow_accumulation[idx] >= threshold
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Curvature of every cell in 1/m as the Laplacian of the elevation: positive in
+    /// valleys and hollows, negative on ridges, 0 on the grid edge and next to nodata
+    pub fn laplacian_curvature(&self) -> Vec<f32> {
+        let width = self.dem.width;
+        let height = self.dem.height;
+        let x_res2 = (self.dem.x_resolution * self.dem.x_resolution) as f32;
+        let y_res2 = (self.dem.y_resolution * self.dem.y_resolution) as f32;
+        (0..width * height)
+            .map(|idx| {
+                let (x, y) = (idx % width, idx / width);
+                if x == 0 || y == 0 || x + 1 >= width || y + 1 >= height {
+                    return 0.0;
+                }
+                let z = |x: usize, y: usize| self.dem.get_elevation(x, y);
+                match (z(x, y), z(x - 1, y), z(x + 1, y), z(x, y - 1), z(x, y + 1)) {
+                    (Some(center), Some(west), Some(east), Some(north), Some(south)) => {
+                        (west + east - 2.0 * center) / x_res2 + (north + south - 2.0 * center) / y_res2
+                    }
+                    _ => 0.0,
+                }
+            })
+            .collect()
+    }
+
+    /// Test log-spaced accumulation thresholds for a constant mean drop of Strahler streams.
+    ///
+    /// Every candidate extracts a network, groups its links into Strahler streams (runs of
+    /// links of one order) and compares the mean elevation drop of first-order streams with
+    /// that of all higher-order streams. The smallest threshold at which the difference is
+    /// no longer significant is taken as the channel threshold.
+    ///
+    /// The analysis is computed once per flow accumulation and cached on the model.
+    pub fn drop_analysis(&self) -> &DropAnalysis {
+        self.drop_analysis.get_or_init(|| self.analyse_drops())
+    }
+
+    fn analyse_drops(&self) -> DropAnalysis {
+        let max_flow = self.flow_accumulation.iter().fold(0.0_f32, |max_val, &val| max_val.max(val));
+        let max_cells = max_flow / 10.0;
+        if max_cells <= DROP_MIN_CELLS {
+            return DropAnalysis { tests: Vec::new(), threshold_cells: None };
+        }
+
+        let ratio = (max_cells / DROP_MIN_CELLS).powf(1.0 / (DROP_CANDIDATE_COUNT - 1) as f32);
+        let tests: Vec<DropTest> = (0..DROP_CANDIDATE_COUNT)
+            .map(|i| {
+                let threshold_cells = DROP_MIN_CELLS * ratio.powi(i as i32);
+                let orders = self.compute_stream_orders(StreamThreshold::Cells(threshold_cells));
+                let drops = self.strahler_stream_drops(&orders);
+                let first: Vec<f64> = drops.iter().filter(|&&(order, _)| order == 1).map(|&(_, drop)| drop).collect();
+                let higher: Vec<f64> = drops.iter().filter(|&&(order, _)| order > 1).map(|&(_, drop)| drop).collect();
+                DropTest {
+                    threshold_cells,
+                    first_order_count: first.len(),
+                    higher_order_count: higher.len(),
+                    first_order_mean_drop: mean(&first),
+                    higher_order_mean_drop: mean(&higher),
+                    t: welch_t(&first, &higher),
+                }
+            })
+            .collect();
+
+        let tested = || tests.iter().filter_map(|test| test.t.map(|t| (test.threshold_cells, t.abs())));
+        let threshold_cells = tested()
+            .find(|&(_, t)| t < DROP_T_LIMIT)
+            .or_else(|| tested().min_by(|a, b| a.1.total_cmp(&b.1)))
+            .map(|(threshold_cells, _)| threshold_cells);
+
+        println!("Constant-drop analysis picked a threshold of {:?} cells", threshold_cells);
+        DropAnalysis { tests, threshold_cells }
+    }
+
+    /// Strahler order and elevation drop of every Strahler stream of a network. A stream
+    /// runs from the head of its first link to the junction where it joins a higher order.
+    fn strahler_stream_drops(&self, orders: &StreamOrders) -> Vec<(u32, f64)> {
+        let elevation = |(x, y): (usize, usize)| self.dem.get_elevation(x, y).unwrap_or(0.0) as f64;
+        orders.strahler_streams().into_iter()
+            .map(|(order, first, last)| {
+                let end = &orders.links[last];
+                let outlet = match end.downstream_link {
+                    Some(id) => orders.links[id as usize - 1].cells[0],
+                    None => *end.cells.last().unwrap(),
+                };
+                (order, (elevation(orders.links[first].cells[0]) - elevation(outlet)).max(0.0))
+            })
+            .collect()
+    }
+
+    /// Flag every cell downstream of a flagged cell
+    fn extend_downstream(&self, mut streams: Vec<bool>) -> Vec<bool> {
+        let width = self.dem.width;
+        let mut walker = PathWalker::new(streams.len());
+        let mut path = Vec::new();
+        for head in 0..streams.len() {
+            if !streams[head] || walker.is_resolved(head) || self.dem.get_elevation(head % width, head / width).is_none() {
+                continue;
+            }
+            walker.walk(head, |idx| self.downstream_index(idx), &mut path);
+            for cell in path.drain(..) {
+                streams[cell] = true;
+            }
+        }
+        streams
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() { 0.0 } else { values.iter().sum::<f64>() / values.len() as f64 }
+}
+
+/// Welch's t statistic for the difference of two sample means
+fn welch_t(a: &[f64], b: &[f64]) -> Option<f64> {
+    if a.len() < 2 || b.len() < 2 {
+        return None;
+    }
+    let variance = |values: &[f64]| {
+        let m = mean(values);
+        values.iter().map(|v| (v - m).powi(2)).sum::<f64>() / (values.len() - 1) as f64
+    };
+    let error = (variance(a) / a.len() as f64 + variance(b) / b.len() as f64).sqrt();
+    if error > 0.0 {
+        Some((mean(a) - mean(b)) / error)
+    } else if mean(a) == mean(b) {
+        Some(0.0)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dem::DigitalElevationModel;
+    use crate::flow::FlowMethod;
+    use crate::test_support::{cyclic_pair, routed, valley};
+
+    #[test]
+    fn stream_initiation_criteria_find_the_valley_head() {
+        let mut model = valley();
+        let valley_from = |head: usize| (head..30).map(|y| (10, y)).collect::<Vec<_>>();
+
+        // Valley cells drain 21 cells per row at a slope of 0.05; the sides never reach A·S = 1000 m²
+        let slope_area = StreamThreshold::SlopeArea { constant: 1000.0, exponent: 1.0 };
+        assert_eq!(model.extract_stream_network(slope_area), valley_from(9));
+        assert_eq!(slope_area.accumulation_threshold(&model), 210.0);
+
+        // Only the valley axis is concave; its fifth valley cell is the channel head
+        let curvature = StreamThreshold::Curvature { min_curvature: 0.01, valley_cells: 5.0 };
+        assert_eq!(model.extract_high_quality_streams(curvature), vec![valley_from(5)]);
+
+        // A single stream leaves nothing to compare, so no threshold is picked
+        assert!(model.drop_analysis().threshold_cells.is_none());
+        assert!(model.extract_stream_network(StreamThreshold::ConstantDrop).is_empty());
+
+        // The analysis is kept until the accumulation changes
+        assert!(std::ptr::eq(model.drop_analysis(), model.drop_analysis()));
+        model.compute_flow_accumulation();
+        assert!(model.drop_analysis.get().is_none());
+
+        assert_eq!(StreamThreshold::parse("slope-area 200"), Some(StreamThreshold::SlopeArea { constant: 200.0, exponent: 2.0 }));
+        assert_eq!(StreamThreshold::parse("curvature 5 0.01"), Some(curvature));
+        assert_eq!(StreamThreshold::parse("constant-drop"), Some(StreamThreshold::ConstantDrop));
+        assert_eq!(StreamThreshold::parse("slope-area"), None);
+        assert_eq!(StreamThreshold::parse("curvature many"), None);
+    }
+
+    #[test]
+    fn flat_grids_have_no_drop_threshold() {
+        let model = routed(DigitalElevationModel::new(8, 8, 10.0, vec![50.0; 64]), FlowMethod::D8);
+        let analysis = model.drop_analysis();
+        assert!(analysis.tests.is_empty());
+        assert!(analysis.threshold_cells.is_none());
+        assert!(model.stream_mask(StreamThreshold::Curvature { min_curvature: 0.0, valley_cells: 1.0 }).iter().all(|&flag| !flag));
+    }
+
+    #[test]
+    fn laplacian_curvature_of_a_bowl() {
+        // z = x² + y² in cells has a Laplacian of 4 per cell², 0.04 /m² on 10 m cells
+        let mut data: Vec<f32> = (0..25).map(|idx| ((idx % 5 - 2) * (idx % 5 - 2) + (idx / 5 - 2) * (idx / 5 - 2)) as f32 + 10.0).collect();
+        data[5 + 1] = f32::NAN;
+        let model = FlowModel::new(DigitalElevationModel::new(5, 5, 10.0, data));
+        let curvature = model.laplacian_curvature();
+        assert!((curvature[12] - 0.04).abs() < 1e-6);
+        // Zero on the edge, on nodata and next to it
+        assert!((0..5).all(|x| curvature[x] == 0.0 && curvature[20 + x] == 0.0));
+        assert_eq!((curvature[6], curvature[7], curvature[11]), (0.0, 0.0, 0.0));
+        assert!((curvature[18] - 0.04).abs() < 1e-6);
+    }
+
+    #[test]
+    fn welch_t_needs_two_samples_and_spread() {
+        assert_eq!(welch_t(&[1.0], &[1.0, 2.0]), None);
+        assert_eq!(welch_t(&[3.0, 3.0], &[3.0, 3.0]), Some(0.0));
+        assert_eq!(welch_t(&[3.0, 3.0], &[4.0, 4.0]), None);
+        let t = welch_t(&[1.0, 3.0], &[5.0, 7.0]).unwrap();
+        assert!((t + 4.0 / 2.0_f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn heads_extend_to_the_outlet_and_stop_on_cycles() {
+        let model = valley();
+        let mut heads = vec![false; 21 * 30];
+        heads[20 * 21 + 10] = true;
+        let streams = model.extend_downstream(heads);
+        assert_eq!(streams.iter().filter(|&&flag| flag).count(), 10);
+        assert!(streams[29 * 21 + 10]);
+
+        assert_eq!(cyclic_pair().extend_downstream(vec![true, false]), vec![true, true]);
+    }
+}
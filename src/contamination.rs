@@ -0,0 +1,123 @@
+use crate::flow::FlowModel;
+use crate::traversal;
+
+impl FlowModel {
+    /// Cells whose upstream area reaches the DEM edge or nodata. Part of their real
+    /// catchment may lie outside the data, so their accumulation is underestimated.
+    ///
+    /// Contamination starts at boundary cells (on the grid edge or next to nodata, which
+    /// may receive flow from outside the data) and spreads to everything downstream of
+    /// them under the active flow method.
+    pub fn edge_contamination(&self) -> Vec<bool> {
+        let width = self.dem.width;
+        let (contaminated, _) = traversal::settle_topological(
+            width * self.dem.height,
+            |idx| self.dem.get_elevation(idx % width, idx / width).is_some(),
+            |idx| self.receivers(idx).map(|(downstream_idx, _)| downstream_idx),
+            |idx, contaminated: &[bool]| {
+                self.is_data_boundary(idx)
+                    || self.donors(idx).any(|(upstream_idx, _)| contaminated[upstream_idx])
+            },
+        );
+
+        println!("{} cells are edge-contaminated", contaminated.iter().filter(|&&flag| flag).count());
+        contaminated
+    }
+
+    /// Share of the cells with elevation data that are contaminated (0 for an empty DEM)
+    pub fn contaminated_fraction(&self, contaminated: &[bool]) -> f64 {
+        let width = self.dem.width;
+        let (valid, flagged) = (0..width * self.dem.height)
+            .filter(|&idx| self.dem.get_elevation(idx % width, idx / width).is_some())
+            .fold((0usize, 0usize), |(valid, flagged), idx| (valid + 1, flagged + contaminated[idx] as usize));
+        if valid > 0 { flagged as f64 / valid as f64 } else { 0.0 }
+    }
+
+    /// Stream polylines with their contaminated cells removed. Polylines are split where
+    /// they cross contaminated cells; pieces shorter than two cells are dropped.
+    pub fn exclude_contaminated(&self, polylines: Vec<Vec<(usize, usize)>>, contaminated: &[bool]) -> Vec<Vec<(usize, usize)>> {
+        let width = self.dem.width;
+        polylines.iter()
+            .flat_map(|polyline| polyline.split(|&(x, y)| contaminated[y * width + x]))
+            .filter(|piece| piece.len() >= 2)
+            .map(|piece| piece.to_vec())
+            .collect()
+    }
+
+    /// Whether a cell lies on the grid edge or next to nodata
+    fn is_data_boundary(&self, idx: usize) -> bool {
+        let (x, y) = (idx % self.dem.width, idx / self.dem.width);
+        let hood = self.neighborhood();
+        hood.is_edge(x, y) || hood.neighbors(x, y).any(|(_, (nx, ny))| self.dem.get_elevation(nx, ny).is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dem::DigitalElevationModel;
+    use crate::flow::FlowMethod;
+    use crate::test_support::{ramp, routed};
+
+    /// Cone draining outwards to the grid edge, optionally with a nodata cell left of the peak
+    fn cone(hole: bool) -> FlowModel {
+        let data = (0..81)
+            .map(|idx| {
+                let (x, y) = ((idx % 9) as f32 - 4.0, (idx / 9) as f32 - 4.0);
+                if hole && (x, y) == (-1.0, 0.0) { f32::NAN } else { 100.0 - x.hypot(y) }
+            })
+            .collect();
+        routed(DigitalElevationModel::new(9, 9, 10.0, data), FlowMethod::D8)
+    }
+
+    #[test]
+    fn edge_contamination_spreads_downstream_of_the_data_boundary() {
+        // Only the edge cells themselves may receive flow from outside
+        let model = cone(false);
+        let contaminated = model.edge_contamination();
+        assert!((0..81).all(|idx| contaminated[idx] == model.neighborhood().is_edge(idx % 9, idx / 9)));
+        assert!((model.contaminated_fraction(&contaminated) - 32.0 / 81.0).abs() < 1e-9);
+
+        // Cells around a nodata hole, and everything downstream of them, but not the hole itself
+        let model = cone(true);
+        let contaminated = model.edge_contamination();
+        assert!(!contaminated[4 * 9 + 3]);
+        assert!(contaminated[4 * 9 + 2] && contaminated[4 * 9 + 1]);
+        assert!(!contaminated[4 * 9 + 6] && !contaminated[4 * 9 + 7]);
+        assert!(model.label_basins().basins.iter().all(|basin| basin.contaminated));
+
+        let streams = model.exclude_contaminated(vec![vec![(6, 4), (7, 4), (8, 4)], vec![(2, 4), (1, 4), (0, 4)]], &contaminated);
+        assert_eq!(streams, vec![vec![(6, 4), (7, 4)]]);
+    }
+
+    #[test]
+    fn flow_entering_at_an_edge_contaminates_the_whole_path() {
+        // Every row of an eastward ramp starts at the western edge
+        for method in [FlowMethod::D8, FlowMethod::MFD] {
+            let model = routed(ramp(6, 4, 10.0, 10.0, 0.0, 0.1), method);
+            let contaminated = model.edge_contamination();
+            assert_eq!(model.contaminated_fraction(&contaminated), 1.0);
+        }
+    }
+
+    #[test]
+    fn grids_without_data_have_no_contamination() {
+        let model = routed(DigitalElevationModel::new(3, 3, 10.0, vec![f32::NAN; 9]), FlowMethod::D8);
+        let contaminated = model.edge_contamination();
+        assert_eq!(contaminated, vec![false; 9]);
+        assert_eq!(model.contaminated_fraction(&contaminated), 0.0);
+
+        let empty = FlowModel::new(DigitalElevationModel::new(0, 0, 10.0, Vec::new()));
+        assert!(empty.edge_contamination().is_empty());
+        assert_eq!(empty.contaminated_fraction(&[]), 0.0);
+    }
+
+    #[test]
+    fn contaminated_cells_split_polylines() {
+        let model = routed(ramp(6, 1, 10.0, 10.0, 0.0, 0.1), FlowMethod::D8);
+        let contaminated = [false, false, true, false, false, true];
+        let polyline = (0..6).map(|x| (x, 0)).collect();
+        assert_eq!(model.exclude_contaminated(vec![polyline], &contaminated), vec![vec![(0, 0), (1, 0)], vec![(3, 0), (4, 0)]]);
+        assert!(model.exclude_contaminated(vec![vec![(2, 0), (3, 0), (5, 0)]], &contaminated).is_empty());
+    }
+}
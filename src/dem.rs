@@ -5,6 +5,7 @@ use gdal::Dataset;
 #[allow(unused_imports)]
 use ndarray::{Array2, ShapeError};
 
+use crate::neighborhood::{Connectivity, Neighborhood};
 use thiserror::Error;
 use std::collections::BinaryHeap;
 use std::cmp::Reverse as StdReverse;
@@ -34,7 +35,9 @@ pub enum DemError {
 pub struct DigitalElevationModel {
     pub width: usize,
     pub height: usize,
-    pub resolution: f64,
+    pub resolution: f64,  // Mean cell size, kept for display and resampling decisions
+    pub x_resolution: f64,  // Cell width (east-west) in map units
+    pub y_resolution: f64,  // Cell height (north-south) in map units
     pub data: Vec<f32>,
     pub no_data_value: Option<f32>,
     pub geo_transform: [f64; 6],
@@ -45,13 +48,20 @@ pub struct DigitalElevationModel {
 impl DigitalElevationModel {
     /// Create a new DEM from raw data
     pub fn new(width: usize, height: usize, resolution: f64, data: Vec<f32>) -> Self {
-        let bounds = (0.0, 0.0, width as f64 * resolution, height as f64 * resolution);
-        let geo_transform = [0.0, resolution, 0.0, 0.0, 0.0, resolution];
+        Self::with_cell_size(width, height, resolution, resolution, data)
+    }
+    
+    /// Create a new DEM from raw data with rectangular cells
+    pub fn with_cell_size(width: usize, height: usize, x_resolution: f64, y_resolution: f64, data: Vec<f32>) -> Self {
+        let bounds = (0.0, 0.0, width as f64 * x_resolution, height as f64 * y_resolution);
+        let geo_transform = [0.0, x_resolution, 0.0, 0.0, 0.0, y_resolution];
         
         DigitalElevationModel {
             width,
             height,
-            resolution,
+            resolution: (x_resolution + y_resolution) / 2.0,
+            x_resolution,
+            y_resolution,
             data,
             no_data_value: Some(f32::NAN),
             geo_transform,
@@ -77,7 +87,7 @@ impl DigitalElevationModel {
         // Get the geotransform (contains resolution and coordinate information)
         let geo_transform = dataset.geo_transform()?;
         
-        // Calculate resolution (assuming square pixels)
+        // Keep both cell dimensions; the mean is only used for reporting
         let x_resolution = geo_transform[1].abs();
         let y_resolution = geo_transform[5].abs();
         let resolution = (x_resolution + y_resolution) / 2.0;
@@ -106,6 +116,8 @@ impl DigitalElevationModel {
             width: width as usize,
             height: height as usize,
             resolution,
+            x_resolution,
+            y_resolution,
             data,
             no_data_value,
             geo_transform,
@@ -132,25 +144,24 @@ impl DigitalElevationModel {
         let mut min_y = f64::MAX;
         let mut max_x = f64::MIN;
         let mut max_y = f64::MIN;
-        let mut common_resolution = None;
+        let mut common_resolution: Option<(f64, f64)> = None;
         let mut common_no_data = None;
         
         for path in paths {
             let dem = Self::from_geotiff(path)?;
             
             // Check resolution compatibility
-            if let Some(res) = common_resolution {
+            if let Some((res_x, res_y)) = common_resolution {
                 // Allow a small tolerance for floating point differences
-                let dem_res: f64 = dem.resolution;
-                let common_res: f64 = res;
-                let resolution_diff = (dem_res - common_res).abs();
+                let resolution_diff = (dem.x_resolution - res_x).abs().max((dem.y_resolution - res_y).abs());
                 if resolution_diff > 0.01f64 {
                     return Err(DemError::MergeError(
-                        format!("Resolution mismatch: {} vs {}", dem.resolution, res)
+                        format!("Resolution mismatch: {}x{} vs {}x{}",
+                                dem.x_resolution, dem.y_resolution, res_x, res_y)
                     ));
                 }
             } else {
-                common_resolution = Some(dem.resolution);
+                common_resolution = Some((dem.x_resolution, dem.y_resolution));
             }
             
             // Use the first no_data value we find, or prefer explicit ones
@@ -168,23 +179,24 @@ impl DigitalElevationModel {
             dems.push(dem);
         }
         
-        let resolution = common_resolution.unwrap();
+        let (x_resolution, y_resolution) = common_resolution.unwrap();
+        let resolution = (x_resolution + y_resolution) / 2.0;
         
         // Calculate grid dimensions for the merged DEM
-        let width = ((max_x - min_x) / resolution).ceil() as usize;
-        let height = ((max_y - min_y) / resolution).ceil() as usize;
+        let width = ((max_x - min_x) / x_resolution).ceil() as usize;
+        let height = ((max_y - min_y) / y_resolution).ceil() as usize;
         
-        println!("Creating merged DEM: {}x{} cells at {} meter resolution", 
-                width, height, resolution);
+        println!("Creating merged DEM: {}x{} cells at {}x{} meter resolution", 
+                width, height, x_resolution, y_resolution);
         
         // Create a new geo_transform for the merged DEM
         let geo_transform = [
             min_x,                   // top left x
-            resolution,              // w-e pixel resolution
+            x_resolution,            // w-e pixel resolution
             0.0,                     // 0 if north-up
             max_y,                   // top left y
             0.0,                     // 0 if north-up
-            -resolution,             // n-s pixel resolution (negative)
+            -y_resolution,           // n-s pixel resolution (negative)
         ];
         
         // Initialize the merged data with no_data values
@@ -200,8 +212,8 @@ impl DigitalElevationModel {
                     let (world_x, world_y) = dem.grid_to_geo(src_x, src_y);
                     
                     // Calculate the corresponding cell in the merged grid
-                    let merged_x = ((world_x - min_x) / resolution).floor() as usize;
-                    let merged_y = ((max_y - world_y) / resolution).floor() as usize;
+                    let merged_x = ((world_x - min_x) / x_resolution).floor() as usize;
+                    let merged_y = ((max_y - world_y) / y_resolution).floor() as usize;
                     
                     // Skip if outside the bounds of the merged grid
                     if merged_x >= width || merged_y >= height {
@@ -228,6 +240,8 @@ impl DigitalElevationModel {
             width,
             height,
             resolution,
+            x_resolution,
+            y_resolution,
             data: merged_data,
             no_data_value: Some(no_data_value),
             geo_transform,
@@ -268,7 +282,13 @@ impl DigitalElevationModel {
     
     /// Fill sinks in the DEM using the priority-flood algorithm
     pub fn fill_sinks(&mut self) {
-        println!("Filling sinks in DEM...");
+        self.fill_sinks_with_connectivity(Connectivity::D8);
+    }
+    
+    /// Fill sinks using priority-flood, spreading only through the given neighborhood.
+    /// D4 routing needs a D4 fill, otherwise cells can be left draining only diagonally.
+    pub fn fill_sinks_with_connectivity(&mut self, connectivity: Connectivity) {
+        println!("Filling sinks in DEM ({:?})...", connectivity);
         
         let width = self.width;
         let height = self.height;
@@ -311,7 +331,7 @@ impl DigitalElevationModel {
             }
             
             // Get all valid neighbors of this cell
-            let neighbors = self.get_neighbors(item.x, item.y);
+            let neighbors = self.get_neighbors(item.x, item.y, connectivity);
             
             // Process each unprocessed neighbor
             for (nx, ny) in neighbors {
@@ -344,40 +364,38 @@ impl DigitalElevationModel {
         println!("Sink filling completed");
     }
     
-    /// Get the 8 adjacent neighbors of a cell that have valid elevation data
-    fn get_neighbors(&self, x: usize, y: usize) -> Vec<(usize, usize)> {
-        let width = self.width;
-        let height = self.height;
-        let mut neighbors = Vec::with_capacity(8);
-        
-        // Check all 8 adjacent cells
-        for dy in -1..=1 {
-            for dx in -1..=1 {
-                // Skip the center cell (ourselves)
-                if dx == 0 && dy == 0 {
-                    continue;
-                }
-                
-                // Calculate neighbor coordinates
-                let nx = x as isize + dx;
-                let ny = y as isize + dy;
-                
-                // Skip if outside the DEM boundaries
-                if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
-                    continue;
-                }
-                
-                let nx = nx as usize;
-                let ny = ny as usize;
-                
-                // Only add if the cell has valid elevation data
-                if self.get_elevation(nx, ny).is_some() {
-                    neighbors.push((nx, ny));
-                }
-            }
-        }
-        
-        neighbors
+    /// Get the adjacent neighbors of a cell that have valid elevation data
+    fn get_neighbors(&self, x: usize, y: usize, connectivity: Connectivity) -> Vec<(usize, usize)> {
+        Neighborhood::new(self.width, self.height, connectivity)
+            .neighbors(x, y)
+            .map(|(_, n)| n)
+            .filter(|&(nx, ny)| self.get_elevation(nx, ny).is_some())
+            .collect()
+    }
+    
+    /// Distance between cell centers in a direction (index into `neighborhood::OFFSETS`)
+    pub fn neighbor_distance(&self, dir: usize) -> f64 {
+        crate::neighborhood::distance(dir, self.x_resolution, self.y_resolution)
+    }
+    
+    /// Planimetric area of one cell (x_resolution × y_resolution)
+    pub fn cell_area(&self) -> f64 {
+        self.x_resolution * self.y_resolution
+    }
+    
+    /// Change the cell size, keeping the grid origin
+    pub fn set_cell_size(&mut self, x_resolution: f64, y_resolution: f64) {
+        self.x_resolution = x_resolution;
+        self.y_resolution = y_resolution;
+        self.resolution = (x_resolution + y_resolution) / 2.0;
+        self.geo_transform[1] = x_resolution * self.geo_transform[1].signum();
+        self.geo_transform[5] = y_resolution * self.geo_transform[5].signum();
+        
+        let minx = self.geo_transform[0];
+        let maxx = minx + self.width as f64 * self.geo_transform[1];
+        let y0 = self.geo_transform[3];
+        let y1 = y0 + self.height as f64 * self.geo_transform[5];
+        self.bounds = (minx.min(maxx), y0.min(y1), minx.max(maxx), y0.max(y1));
     }
     
     /// Convert grid coordinates to geographic coordinates
@@ -387,6 +405,13 @@ impl DigitalElevationModel {
         (geo_x, geo_y)
     }
     
+    /// Convert grid coordinates to the geographic coordinates of the cell center
+    pub fn cell_center(&self, x: usize, y: usize) -> (f64, f64) {
+        let geo_x = self.geo_transform[0] + (x as f64 + 0.5) * self.geo_transform[1];
+        let geo_y = self.geo_transform[3] + (y as f64 + 0.5) * self.geo_transform[5];
+        (geo_x, geo_y)
+    }
+    
     /// Convert geographic coordinates to grid coordinates
     pub fn geo_to_grid(&self, geo_x: f64, geo_y: f64) -> (usize, usize) {
         let x = ((geo_x - self.geo_transform[0]) / self.geo_transform[1]) as usize;
@@ -394,6 +419,18 @@ impl DigitalElevationModel {
         (x, y)
     }
     
+    /// Grid cell containing a geographic coordinate, or None outside the DEM
+    pub fn cell_at(&self, geo_x: f64, geo_y: f64) -> Option<(usize, usize)> {
+        let col = ((geo_x - self.geo_transform[0]) / self.geo_transform[1]).floor();
+        let row = ((geo_y - self.geo_transform[3]) / self.geo_transform[5]).floor();
+        
+        if col < 0.0 || row < 0.0 || col >= self.width as f64 || row >= self.height as f64 {
+            None
+        } else {
+            Some((col as usize, row as usize))
+        }
+    }
+    
     /// Check if this DEM is adjacent to another DEM
     pub fn is_adjacent_to(&self, other: &Self) -> bool {
         // Check if the bounds of the two DEMs are adjacent or overlapping
@@ -414,9 +451,14 @@ impl DigitalElevationModel {
 
     /// Process sinks in the DEM using different methods
     pub fn process_sinks(&mut self, method: SinkTreatmentMethod) {
+        self.process_sinks_with_connectivity(method, Connectivity::D8);
+    }
+    
+    /// Process sinks so the result drains under the given neighborhood
+    pub fn process_sinks_with_connectivity(&mut self, method: SinkTreatmentMethod, connectivity: Connectivity) {
         match method {
             SinkTreatmentMethod::CompletelyFill => {
-                self.fill_sinks(); // Use the existing method
+                self.fill_sinks_with_connectivity(connectivity);
             },
             SinkTreatmentMethod::EpsilonFill(epsilon) => {
                 self.fill_sinks_epsilon(epsilon);
@@ -477,9 +519,38 @@ impl Ord for PriorityItem {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
 pub enum SinkTreatmentMethod {
     CompletelyFill,       // Current approach
     EpsilonFill(f32),     // Fill by minimum amount + epsilon
     Breach(usize),        // Create drainage path by carving
     Combined(f32, usize), // Breach then fill remaining depressions
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::FlowModel;
+    
+    #[test]
+    fn rectangular_cells_give_anisotropic_distances_and_areas() {
+        let mut dem = DigitalElevationModel::new(4, 3, 10.0, vec![100.0; 12]);
+        dem.geo_transform = [500.0, 10.0, 0.0, 900.0, 0.0, -10.0];
+        dem.set_cell_size(25.0, 30.0);
+        
+        assert_eq!((dem.neighbor_distance(0), dem.neighbor_distance(6)), (25.0, 30.0));
+        assert_eq!(dem.neighbor_distance(1), 25.0_f64.hypot(30.0));
+        assert_eq!((dem.cell_area(), dem.resolution), (750.0, 27.5));
+        assert_eq!((dem.geo_transform[1], dem.geo_transform[5]), (25.0, -30.0));
+        assert_eq!(dem.bounds, (500.0, 810.0, 600.0, 900.0));
+        
+        // A column draining south: areas count 25 × 30 m per cell, lengths 30 m per step
+        let data = (0..3 * 5).map(|idx| 50.0 - (idx / 3) as f32 + 2.0 * ((idx % 3) as f32 - 1.0).abs()).collect();
+        let mut model = FlowModel::new(DigitalElevationModel::with_cell_size(3, 5, 25.0, 30.0, data));
+        model.compute_flow_directions();
+        model.compute_flow_accumulation();
+        let outlet = 4 * 3 + 1;
+        assert_eq!(model.contributing_area_m2()[outlet], model.flow_accumulation[outlet] * 750.0);
+        assert_eq!(model.downstream_flow_length()[1], 4.0 * 30.0);
+    }
+}
@@ -0,0 +1,357 @@
+// Flow direction codes of other hydrology packages and ESRI ASCII grid I/O.
+//
+// All encodings describe the same eight D8 directions on a grid whose rows run from
+// north to south. Only the numbering differs:
+//
+//          ESRI         TauDEM        GRASS       PCRaster LDD
+//       32  64 128     4  3  2      3  2  1        7  8  9
+//       16   .   1     5  .  1      4  .  8        4  5  6
+//        8   4   2     6  7  8      5  6  7        1  2  3
+//
+// Cells without outflow are 0 for ESRI, TauDEM and GRASS and the pit code 5 for PCRaster.
+
+use crate::dem::DigitalElevationModel;
+use crate::flow::{FlowDirection, FlowMethod, FlowModel};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// Value written for nodata cells in exported grids
+const ASCII_NO_DATA: i32 = -9999;
+
+/// Flow direction numbering scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirectionEncoding {
+    Esri,      // Powers of two clockwise from east (ArcGIS, also used by `FlowDirection`)
+    TauDem,    // 1-8 counter-clockwise from east
+    Grass,     // 1-8 counter-clockwise from northeast (r.watershed); negative where flow leaves the map
+    Pcraster,  // Numeric keypad layout with 5 for pits (LDD, as used by wflow)
+}
+
+/// Codes of the directions E, SE, S, SW, W, NW, N, NE (direction index order)
+const TAUDEM_CODES: [i32; 8] = [1, 8, 7, 6, 5, 4, 3, 2];
+const GRASS_CODES: [i32; 8] = [8, 7, 6, 5, 4, 3, 2, 1];
+const PCRASTER_CODES: [i32; 8] = [6, 3, 2, 1, 4, 7, 8, 9];
+const PCRASTER_PIT: i32 = 5;
+
+impl DirectionEncoding {
+    /// Parse an encoding name ("esri"/"arcgis", "taudem", "grass", "pcraster"/"ldd")
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "esri" | "arcgis" => Some(DirectionEncoding::Esri),
+            "taudem" => Some(DirectionEncoding::TauDem),
+            "grass" => Some(DirectionEncoding::Grass),
+            "pcraster" | "ldd" => Some(DirectionEncoding::Pcraster),
+            _ => None,
+        }
+    }
+
+    /// Code of a direction in this encoding
+    pub fn encode(self, direction: FlowDirection) -> i32 {
+        match (self, direction.index()) {
+            (DirectionEncoding::Esri, _) => direction.code() as i32,
+            (DirectionEncoding::Pcraster, None) => PCRASTER_PIT,
+            (_, None) => 0,
+            (DirectionEncoding::TauDem, Some(index)) => TAUDEM_CODES[index],
+            (DirectionEncoding::Grass, Some(index)) => GRASS_CODES[index],
+            (DirectionEncoding::Pcraster, Some(index)) => PCRASTER_CODES[index],
+        }
+    }
+
+    /// Direction of a code in this encoding, or None if the code is not valid
+    pub fn decode(self, code: i32) -> Option<FlowDirection> {
+        let table = match self {
+            DirectionEncoding::Esri => {
+                return match code {
+                    0 => Some(FlowDirection::NoFlow),
+                    1..=128 if (code as u32).is_power_of_two() => Some(FlowDirection::from_code(code as u8)),
+                    _ => None,
+                };
+            }
+            DirectionEncoding::Pcraster if code == PCRASTER_PIT => return Some(FlowDirection::NoFlow),
+            DirectionEncoding::TauDem => &TAUDEM_CODES,
+            DirectionEncoding::Grass => &GRASS_CODES,
+            DirectionEncoding::Pcraster => &PCRASTER_CODES,
+        };
+
+        // GRASS marks cells draining off the map with negative codes
+        let code = if self == DirectionEncoding::Grass { code.abs() } else { code };
+        if code == 0 && self != DirectionEncoding::Pcraster {
+            return Some(FlowDirection::NoFlow);
+        }
+        table.iter().position(|&c| c == code).map(FlowDirection::from_index)
+    }
+
+    /// Encode a direction grid; cells without elevation data get `no_data`
+    pub fn encode_grid(self, dem: &DigitalElevationModel, directions: &[FlowDirection], no_data: i32) -> Vec<i32> {
+        directions.iter()
+            .enumerate()
+            .map(|(idx, &direction)| {
+                if dem.get_elevation(idx % dem.width, idx / dem.width).is_none() {
+                    no_data
+                } else {
+                    self.encode(direction)
+                }
+            })
+            .collect()
+    }
+
+    /// Decode a grid of codes; nodata cells become `NoFlow`
+    pub fn decode_grid(self, grid: &AsciiGrid) -> Result<Vec<FlowDirection>, String> {
+        grid.values.iter()
+            .enumerate()
+            .map(|(idx, &value)| {
+                if grid.is_no_data(value) {
+                    return Ok(FlowDirection::NoFlow);
+                }
+                self.decode(value.round() as i32).ok_or_else(|| {
+                    format!("Invalid {:?} flow direction code {} at cell ({}, {})",
+                            self, value, idx % grid.width, idx / grid.width)
+                })
+            })
+            .collect()
+    }
+}
+
+/// Raster in ESRI ASCII grid format (.asc), readable by ArcGIS, GDAL, GRASS and PCRaster
+#[derive(Debug, Clone)]
+pub struct AsciiGrid {
+    pub width: usize,
+    pub height: usize,
+    /// World coordinates of the lower-left corner of the grid
+    pub x_lower_left: f64,
+    pub y_lower_left: f64,
+    pub x_resolution: f64,
+    pub y_resolution: f64,
+    pub no_data_value: Option<f64>,
+    /// Values row by row from the northern row down
+    pub values: Vec<f64>,
+}
+
+impl AsciiGrid {
+    /// Grid with the size and georeferencing of a DEM
+    pub fn from_dem(dem: &DigitalElevationModel, values: Vec<f64>, no_data_value: Option<f64>) -> Self {
+        AsciiGrid {
+            width: dem.width,
+            height: dem.height,
+            x_lower_left: dem.bounds.0,
+            y_lower_left: dem.bounds.1,
+            x_resolution: dem.x_resolution,
+            y_resolution: dem.y_resolution,
+            no_data_value,
+            values,
+        }
+    }
+
+    /// Parse the text of an ASCII grid
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut tokens = text.split_whitespace().peekable();
+        let mut header = std::collections::HashMap::new();
+        while let Some(key) = tokens.peek().filter(|token| token.starts_with(|c: char| c.is_ascii_alphabetic())) {
+            let key = key.to_ascii_lowercase();
+            tokens.next();
+            let value: f64 = tokens.next()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| format!("Missing value for ASCII grid header {}", key))?;
+            header.insert(key, value);
+        }
+
+        let field = |name: &str| header.get(name).copied();
+        let required = |name: &str| field(name).ok_or_else(|| format!("ASCII grid header lacks {}", name));
+        let width = required("ncols")? as usize;
+        let height = required("nrows")? as usize;
+        let (x_resolution, y_resolution) = match (field("dx"), field("dy")) {
+            (Some(dx), Some(dy)) => (dx, dy),
+            _ => (required("cellsize")?, required("cellsize")?),
+        };
+        // Centre-registered grids give the centre of the lower-left cell
+        let x_lower_left = field("xllcorner").or_else(|| field("xllcenter").map(|x| x - x_resolution / 2.0)).unwrap_or(0.0);
+        let y_lower_left = field("yllcorner").or_else(|| field("yllcenter").map(|y| y - y_resolution / 2.0)).unwrap_or(0.0);
+
+        let values = tokens
+            .map(|token| token.parse::<f64>().map_err(|_| format!("Invalid ASCII grid value {}", token)))
+            .collect::<Result<Vec<f64>, String>>()?;
+        if values.len() != width * height {
+            return Err(format!("ASCII grid has {} values, expected {} ({}x{})", values.len(), width * height, width, height));
+        }
+
+        Ok(AsciiGrid {
+            width,
+            height,
+            x_lower_left,
+            y_lower_left,
+            x_resolution,
+            y_resolution,
+            no_data_value: field("nodata_value"),
+            values,
+        })
+    }
+
+    /// Grid as ASCII grid text
+    pub fn to_text(&self) -> String {
+        let mut text = String::with_capacity(self.values.len() * 4 + 128);
+        let _ = writeln!(text, "ncols {}", self.width);
+        let _ = writeln!(text, "nrows {}", self.height);
+        let _ = writeln!(text, "xllcorner {}", self.x_lower_left);
+        let _ = writeln!(text, "yllcorner {}", self.y_lower_left);
+        if self.x_resolution == self.y_resolution {
+            let _ = writeln!(text, "cellsize {}", self.x_resolution);
+        } else {
+            let _ = writeln!(text, "dx {}", self.x_resolution);
+            let _ = writeln!(text, "dy {}", self.y_resolution);
+        }
+        if let Some(no_data) = self.no_data_value {
+            let _ = writeln!(text, "NODATA_value {}", no_data);
+        }
+
+        for row in self.values.chunks(self.width.max(1)) {
+            let line: Vec<String> = row.iter().map(|value| value.to_string()).collect();
+            text.push_str(&line.join(" "));
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, self.to_text())?;
+        Ok(())
+    }
+
+    fn is_no_data(&self, value: f64) -> bool {
+        value.is_nan() || self.no_data_value == Some(value)
+    }
+}
+
+impl FlowModel {
+    /// Flow directions as an ASCII grid in the given encoding
+    pub fn export_flow_directions(&self, encoding: DirectionEncoding) -> AsciiGrid {
+        let codes = encoding.encode_grid(&self.dem, &self.flow_directions, ASCII_NO_DATA);
+        AsciiGrid::from_dem(&self.dem, codes.into_iter().map(f64::from).collect(), Some(ASCII_NO_DATA as f64))
+    }
+
+    /// Replace the model's flow directions with a grid produced elsewhere, skipping
+    /// the direction step. Switches the model to D8 routing; slopes are taken along the
+    /// imported directions. Run `compute_flow_accumulation` afterwards.
+    pub fn import_flow_directions(&mut self, grid: &AsciiGrid, encoding: DirectionEncoding) -> Result<(), String> {
+        if (grid.width, grid.height) != (self.dem.width, self.dem.height) {
+            return Err(format!("Flow direction grid is {}x{}, DEM is {}x{}",
+                               grid.width, grid.height, self.dem.width, self.dem.height));
+        }
+
+        self.flow_directions = encoding.decode_grid(grid)?;
+        self.flow_method = FlowMethod::D8;
+        self.dinf_flow = None;
+        self.mfd_flow_proportions = None;
+
+        let width = self.dem.width;
+        for idx in 0..width * self.dem.height {
+            let (x, y) = (idx % width, idx / width);
+            self.slopes[idx] = match (self.dem.get_elevation(x, y), self.get_downstream_cell(x, y)) {
+                (Some(elevation), Some((dx, dy))) => {
+                    let drop = elevation - self.dem.get_elevation(dx, dy).unwrap_or(elevation);
+                    (drop as f64 / self.step_length(idx, dy * width + dx)).max(0.0) as f32
+                }
+                _ => 0.0,
+            };
+        }
+
+        println!("Imported {:?} flow directions for {}x{} cells", encoding, grid.width, grid.height);
+        Ok(())
+    }
+
+    /// Read a flow direction grid from an ASCII grid file (see `import_flow_directions`)
+    pub fn read_flow_directions(&mut self, path: &Path, encoding: DirectionEncoding) -> Result<(), Box<dyn Error>> {
+        let grid = AsciiGrid::read(path)?;
+        Ok(self.import_flow_directions(&grid, encoding)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::FlowMethod;
+    use crate::test_support::{ramp, routed};
+
+    #[test]
+    fn direction_encodings_round_trip_through_ascii_grids() {
+        // Southeast in every scheme, and the no-flow code
+        let encodings = [
+            (DirectionEncoding::Esri, 2, 0),
+            (DirectionEncoding::TauDem, 8, 0),
+            (DirectionEncoding::Grass, 7, 0),
+            (DirectionEncoding::Pcraster, 3, 5),
+        ];
+        for (encoding, southeast, no_flow) in encodings {
+            assert_eq!(encoding.encode(FlowDirection::Southeast), southeast);
+            assert_eq!(encoding.encode(FlowDirection::NoFlow), no_flow);
+            for index in 0..8 {
+                let direction = FlowDirection::from_index(index);
+                assert_eq!(encoding.decode(encoding.encode(direction)), Some(direction));
+            }
+        }
+        assert_eq!(DirectionEncoding::Grass.decode(-7), Some(FlowDirection::Southeast));
+        assert_eq!(DirectionEncoding::Esri.decode(3), None);
+
+        let model = routed(ramp(5, 4, 10.0, 10.0, 45.0_f32.to_radians(), 0.1), FlowMethod::D8);
+
+        // Export as a PCRaster LDD and import it into a fresh model
+        let text = model.export_flow_directions(DirectionEncoding::Pcraster).to_text();
+        let grid = AsciiGrid::parse(&text).unwrap();
+        assert_eq!((grid.width, grid.height), (5, 4));
+
+        let mut imported = FlowModel::new(ramp(5, 4, 10.0, 10.0, 45.0_f32.to_radians(), 0.1));
+        imported.import_flow_directions(&grid, DirectionEncoding::Pcraster).unwrap();
+        imported.compute_flow_accumulation();
+        assert_eq!((&imported.flow_directions, &imported.flow_accumulation), (&model.flow_directions, &model.flow_accumulation));
+        assert!((imported.slopes[0] - model.slopes[0]).abs() < 1e-5);
+    }
+
+    #[test]
+    fn names_and_codes_outside_the_schemes_are_rejected() {
+        assert_eq!(DirectionEncoding::from_name("LDD"), Some(DirectionEncoding::Pcraster));
+        assert_eq!(DirectionEncoding::from_name("ArcGIS"), Some(DirectionEncoding::Esri));
+        assert_eq!(DirectionEncoding::from_name("saga"), None);
+
+        assert_eq!(DirectionEncoding::Esri.decode(256), None);
+        assert_eq!(DirectionEncoding::Esri.decode(-1), None);
+        assert_eq!(DirectionEncoding::TauDem.decode(9), None);
+        assert_eq!(DirectionEncoding::TauDem.decode(-1), None);
+        assert_eq!(DirectionEncoding::Grass.decode(9), None);
+        assert_eq!(DirectionEncoding::Pcraster.decode(0), None);
+    }
+
+    #[test]
+    fn imports_reject_invalid_grids_and_keep_the_model() {
+        let mut model = routed(ramp(2, 1, 10.0, 10.0, 0.0, 0.1), FlowMethod::D8);
+        let directions = model.flow_directions.clone();
+
+        let wrong_size = AsciiGrid::parse("ncols 2\nnrows 2\ncellsize 10\n1 1 1 1\n").unwrap();
+        assert!(model.import_flow_directions(&wrong_size, DirectionEncoding::Esri).is_err());
+
+        let unknown_code = AsciiGrid::parse("ncols 2\nnrows 1\ncellsize 10\n1 3\n").unwrap();
+        let error = model.import_flow_directions(&unknown_code, DirectionEncoding::Esri).unwrap_err();
+        assert!(error.contains("(1, 0)"), "{}", error);
+        assert_eq!(model.flow_directions, directions);
+
+        // Nodata cells import as cells without outflow
+        let with_no_data = AsciiGrid::parse("ncols 2\nnrows 1\ncellsize 10\nNODATA_value -9999\n1 -9999\n").unwrap();
+        model.import_flow_directions(&with_no_data, DirectionEncoding::Esri).unwrap();
+        assert_eq!(model.flow_directions, vec![FlowDirection::East, FlowDirection::NoFlow]);
+    }
+
+    #[test]
+    fn ascii_grid_headers_are_validated() {
+        assert!(AsciiGrid::parse("nrows 1\ncellsize 10\n1\n").is_err());
+        assert!(AsciiGrid::parse("ncols 2\nnrows 1\ncellsize 10\n1\n").is_err());
+        assert!(AsciiGrid::parse("ncols 1\nnrows 1\ncellsize 10\nx\n").is_err());
+        assert!(AsciiGrid::parse("ncols 1\nnrows 1\ncellsize\n").is_err());
+
+        let centred = AsciiGrid::parse("ncols 1\nnrows 1\nxllcenter 5\nyllcenter 15\ndx 10\ndy 20\n7\n").unwrap();
+        assert_eq!((centred.x_lower_left, centred.y_lower_left), (0.0, 5.0));
+        assert_eq!((centred.x_resolution, centred.y_resolution, centred.values.clone()), (10.0, 20.0, vec![7.0]));
+    }
+}
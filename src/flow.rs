@@ -1,6 +1,15 @@
+use crate::channel_initiation::DropAnalysis;
 use crate::dem::DigitalElevationModel;
+use crate::flow_fields::{DinfFlowField, MfdProportions, Receivers};
+use crate::traversal;
+use crate::lakes::{self, Lake, NO_LAKE};
+use crate::polygon::Polygon;
+use crate::neighborhood::{self, Connectivity, Neighborhood};
+use serde::{Serialize, Deserialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::sync::OnceLock;
 use std::f32;
-use std::collections::VecDeque;
 
 /// Enum representing the 8 possible flow directions (D8 method)
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -51,6 +60,23 @@ impl FlowDirection {
     pub fn code(&self) -> u8 {
         *self as u8
     }
+    
+    /// Convert a direction index (0-7, E..NE as in `neighborhood::OFFSETS`) to a FlowDirection
+    pub fn from_index(index: usize) -> Self {
+        if index < 8 {
+            Self::from_code(1 << index)
+        } else {
+            FlowDirection::NoFlow
+        }
+    }
+    
+    /// Direction index (0-7) of this direction, or None for NoFlow
+    pub fn index(&self) -> Option<usize> {
+        match self {
+            FlowDirection::NoFlow => None,
+            _ => Some(self.code().trailing_zeros() as usize),
+        }
+    }
 }
 
 pub struct FlowModel {
@@ -59,9 +85,34 @@ pub struct FlowModel {
     pub flow_accumulation: Vec<f32>,
     pub slopes: Vec<f32>,  // Store the slope for each cell
     // D∞ specific data
-    pub dinf_flow_angles: Option<Vec<f32>>,  // Flow direction angles in radians (0-2π)
-    pub dinf_flow_proportions: Option<Vec<Vec<f32>>>, // Flow proportions to each of 8 neighbors
+    pub dinf_flow: Option<DinfFlowField>,  // Flow angles plus two receivers and their split
+    // MFD specific data
+    pub mfd_options: MfdOptions,
+    pub mfd_flow_proportions: Option<MfdProportions>, // Proportions used by the last MFD accumulation
     pub flow_method: FlowMethod,  // Track which method we're using
+    pub connectivity: Connectivity,  // D4 or D8 neighborhood used for routing
+    // Lake (water body) tagging
+    pub lake_ids: Vec<u32>,  // Lake id per cell (NO_LAKE for land)
+    pub lakes: Vec<Lake>,
+    // Constant-drop analysis of the current accumulation, cleared whenever it changes
+    pub(crate) drop_analysis: OnceLock<DropAnalysis>,
+}
+
+/// Stream polylines split into river reaches and reaches crossing lakes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamSegments {
+    pub rivers: Vec<Vec<(usize, usize)>>,
+    pub lakes: Vec<Vec<(usize, usize)>>,
+}
+
+/// Steepest downslope triangular facet of a cell (Tarboton 1997)
+#[derive(Debug, Clone, Copy)]
+struct DinfFacet {
+    angle: f32,              // Flow angle in radians (0 = east, increasing clockwise)
+    slope: f32,              // Facet slope (tan β)
+    cardinal: usize,         // Direction index of the facet's cardinal neighbor
+    diagonal: usize,         // Direction index of the facet's diagonal neighbor
+    diagonal_fraction: f32,  // Share of flow sent to the diagonal neighbor
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -71,8 +122,153 @@ pub enum FlowMethod {
     MFD,     // Multiple flow direction
 }
 
+impl FlowMethod {
+    /// Parse a method name ("d8", "dinf", "mfd"), defaulting to D8
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "dinf" | "d-inf" | "d∞" => FlowMethod::DInf,
+            "mfd" => FlowMethod::MFD,
+            _ => FlowMethod::D8,
+        }
+    }
+}
+
+/// Criterion for a cell to count as part of the stream network
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum StreamThreshold {
+    FractionOfMax(f32),  // Fraction (0-1) of the largest accumulation in the DEM
+    Cells(f32),          // Number of upstream cells
+    Area(f64),           // Upstream area in m², independent of the DEM resolution
+    /// Channels start where contributing area (m²) times slope (tan β) to the power
+    /// `exponent` reaches `constant` (Montgomery & Dietrich 1988 use an exponent of 2)
+    SlopeArea { constant: f64, exponent: f32 },
+    /// Channels start where at least `valley_cells` upstream cells have a Laplacian
+    /// curvature above `min_curvature` (1/m), i.e. where concave hollows converge
+    Curvature { min_curvature: f32, valley_cells: f32 },
+    /// Accumulation threshold picked by a constant-drop analysis of Strahler streams
+    ConstantDrop,
+}
+
+impl StreamThreshold {
+    /// Parse a threshold such as "1 km²", "50 ha", "250000 m2", "500 cells", "5%" or "0.05".
+    /// A bare number is a fraction of the maximum accumulation. Other criteria are
+    /// "slope-area <constant> [exponent]", "curvature <valley cells> [min curvature]"
+    /// and "constant-drop".
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim().to_lowercase();
+        let mut words = text.split_whitespace();
+        match words.next()? {
+            "slope-area" | "slopearea" => {
+                let constant = words.next()?.parse().ok()?;
+                let exponent = words.next().map_or(Some(2.0), |word| word.parse().ok())?;
+                return Some(StreamThreshold::SlopeArea { constant, exponent });
+            }
+            "curvature" => {
+                let valley_cells = words.next()?.parse().ok()?;
+                let min_curvature = words.next().map_or(Some(0.0), |word| word.parse().ok())?;
+                return Some(StreamThreshold::Curvature { min_curvature, valley_cells });
+            }
+            "constant-drop" | "drop" => return Some(StreamThreshold::ConstantDrop),
+            _ => {}
+        }
+        
+        // The longest prefix that parses as a number, so exponents like "2.5e-1 km2" work
+        let (value, split) = (1..=text.len()).rev()
+            .filter(|&end| text.is_char_boundary(end))
+            .find_map(|end| text[..end].trim().parse::<f64>().ok().map(|value| (value, end)))
+            .filter(|(value, _)| value.is_finite())?;
+        
+        match text[split..].trim() {
+            "" => Some(StreamThreshold::FractionOfMax(value as f32)),
+            "%" => Some(StreamThreshold::FractionOfMax((value / 100.0) as f32)),
+            "cell" | "cells" => Some(StreamThreshold::Cells(value as f32)),
+            "m2" | "m²" | "m^2" => Some(StreamThreshold::Area(value)),
+            "ha" => Some(StreamThreshold::Area(value * 1.0e4)),
+            "km2" | "km²" | "km^2" => Some(StreamThreshold::Area(value * 1.0e6)),
+            _ => None,
+        }
+    }
+    
+    /// The threshold expressed as a flow accumulation value (upstream cells) for a model.
+    /// For slope-area and curvature criteria this is the smallest accumulation on the
+    /// resulting network; a constant-drop analysis that finds no threshold gives infinity.
+    pub fn accumulation_threshold(&self, flow_model: &FlowModel) -> f32 {
+        match *self {
+            StreamThreshold::FractionOfMax(fraction) => {
+                let max_flow = flow_model.flow_accumulation.iter()
+                    .fold(0.0_f32, |max_val, &val| max_val.max(val));
+                max_flow * fraction
+            }
+            StreamThreshold::Cells(cells) => cells,
+            StreamThreshold::Area(area) => (area / flow_model.dem.cell_area()) as f32,
+            StreamThreshold::SlopeArea { .. } | StreamThreshold::Curvature { .. } => {
+                flow_model.stream_mask(*self).iter()
+                    .zip(&flow_model.flow_accumulation)
+                    .filter(|(&is_stream, _)| is_stream)
+                    .fold(f32::INFINITY, |min_val, (_, &val)| min_val.min(val))
+            }
+            StreamThreshold::ConstantDrop => flow_model.drop_analysis().threshold_cells.unwrap_or(f32::INFINITY),
+        }
+    }
+}
+
+/// Exponent applied to downslope gradients when splitting MFD flow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MfdExponent {
+    /// Constant exponent (Freeman 1991 uses 1.1, Quinn et al. 1991 use 1.0)
+    Fixed(f32),
+    /// Qin et al. (2007): the exponent grows linearly with the cell's maximum downslope
+    /// gradient from `min_exponent` up to `max_exponent` at `max_gradient` (tan β)
+    SlopeAdaptive { min_exponent: f32, max_exponent: f32, max_gradient: f32 },
+}
+
+impl MfdExponent {
+    /// Qin et al. (2007) defaults: p from 1.1 to 10, saturating at a 45° slope
+    pub fn qin_2007() -> Self {
+        MfdExponent::SlopeAdaptive { min_exponent: 1.1, max_exponent: 10.0, max_gradient: 1.0 }
+    }
+    
+    /// Exponent to use for a cell whose steepest downslope gradient is `max_gradient`
+    pub fn exponent_for(&self, steepest_gradient: f32) -> f32 {
+        match *self {
+            MfdExponent::Fixed(p) => p,
+            MfdExponent::SlopeAdaptive { min_exponent, max_exponent, max_gradient } => {
+                let t = if max_gradient > 0.0 { (steepest_gradient / max_gradient).min(1.0) } else { 1.0 };
+                min_exponent + (max_exponent - min_exponent) * t
+            }
+        }
+    }
+}
+
+/// Parameters of the multiple flow direction (Freeman/Quinn) algorithm
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MfdOptions {
+    pub exponent: MfdExponent,
+    /// Weight each direction by its contour length (Quinn et al. 1991): half the facing
+    /// cell side for cardinal neighbors and 0.354 of the cell size for diagonal ones
+    pub contour_weighting: bool,
+    /// Contributing area (in cells) above which a cell sends all of its flow to the
+    /// steepest neighbor, so channels converge to single-direction flow
+    pub single_flow_threshold: Option<f32>,
+}
+
+impl Default for MfdOptions {
+    fn default() -> Self {
+        MfdOptions {
+            exponent: MfdExponent::Fixed(1.1),
+            contour_weighting: false,
+            single_flow_threshold: None,
+        }
+    }
+}
+
 impl FlowModel {
     pub fn new(dem: DigitalElevationModel) -> Self {
+        Self::with_connectivity(dem, Connectivity::D8)
+    }
+    
+    /// Create a flow model that routes over the given neighborhood
+    pub fn with_connectivity(dem: DigitalElevationModel, connectivity: Connectivity) -> Self {
         let cell_count = dem.width * dem.height;
         
         FlowModel {
@@ -80,107 +276,113 @@ impl FlowModel {
             flow_directions: vec![FlowDirection::NoFlow; cell_count],
             flow_accumulation: vec![0.0; cell_count],
             slopes: vec![0.0; cell_count],
-            dinf_flow_angles: None,
-            dinf_flow_proportions: None,
+            dinf_flow: None,
+            mfd_options: MfdOptions::default(),
+            mfd_flow_proportions: None,
             flow_method: FlowMethod::D8,
+            connectivity,
+            lake_ids: vec![NO_LAKE; cell_count],
+            lakes: Vec::new(),
+            drop_analysis: OnceLock::new(),
+        }
+    }
+    
+    /// Burn lake polygons into the model: tag lake cells and flatten each lake
+    /// to its minimum shoreline elevation. Call before computing flow directions.
+    pub fn set_lakes(&mut self, polygons: &[Polygon]) {
+        self.lake_ids = self.dem.rasterize_lakes(polygons);
+        self.lakes = self.dem.flatten_lakes(&self.lake_ids, polygons.len());
+    }
+    
+    /// Neighborhood used by the routing algorithms of this model
+    pub fn neighborhood(&self) -> Neighborhood {
+        Neighborhood::new(self.dem.width, self.dem.height, self.connectivity)
+    }
+    
+    /// Check whether a cell is part of a lake
+    pub fn is_lake_cell(&self, x: usize, y: usize) -> bool {
+        x < self.dem.width && y < self.dem.height && self.lake_ids[y * self.dem.width + x] != NO_LAKE
+    }
+    
+    /// Override flow directions inside lakes so water runs along each lake's
+    /// centerline from its inflows to its outlet
+    fn apply_lake_routing(&mut self) {
+        if self.lakes.is_empty() {
+            return;
+        }
+        
+        println!("Routing flow through {} lakes...", self.lakes.len());
+        
+        let lake_cells = lakes::lake_cells(&self.lake_ids, self.lakes.len());
+        for (i, cells) in lake_cells.iter().enumerate() {
+            let (outlets, routes) = lakes::route_lake(&self.dem, &self.lake_ids, &self.lakes[i], cells, self.connectivity);
+            self.lakes[i].outlet = outlets.first().copied();
+            self.lakes[i].detached_outlets = outlets.iter().skip(1).copied().collect();
+            
+            for (idx, dir) in routes {
+                // Water surface: no slope inside the lake
+                self.slopes[idx] = 0.0;
+                self.flow_directions[idx] = dir.map_or(FlowDirection::NoFlow, FlowDirection::from_index);
+                
+                if let Some(ref mut dinf) = self.dinf_flow {
+                    dinf.set_single(idx, dir);
+                }
+            }
         }
     }
     
-    /// Compute D8 flow directions for each cell
+    /// Compute D8 (or D4, depending on connectivity) flow directions for each cell
     pub fn compute_flow_directions(&mut self) {
-        println!("Computing D8 flow directions...");
+        println!("Computing {:?} flow directions...", self.connectivity);
         
         let width = self.dem.width;
         let height = self.dem.height;
-        let resolution = self.dem.resolution;
-        
-        // Define the 8 neighboring directions in (dx, dy) offsets
-        // Order: E, SE, S, SW, W, NW, N, NE
-        let directions = [
-            (1, 0),    // East
-            (1, 1),    // Southeast
-            (0, 1),    // South
-            (-1, 1),   // Southwest
-            (-1, 0),   // West
-            (-1, -1),  // Northwest
-            (0, -1),   // North
-            (1, -1),   // Northeast
-        ];
-        
-        // Direction codes corresponding to the directions array
-        let direction_codes = [
-            FlowDirection::East,
-            FlowDirection::Southeast,
-            FlowDirection::South,
-            FlowDirection::Southwest,
-            FlowDirection::West,
-            FlowDirection::Northwest,
-            FlowDirection::North,
-            FlowDirection::Northeast,
-        ];
-        
-        // Distance to each neighbor (used for slope calculation)
-        // For diagonal neighbors, the distance is √2 * resolution
-        // Convert to f32 to match the elevation data type
-        let distances = [
-            resolution as f32,                          // East
-            (resolution * 1.41421356) as f32,           // Southeast (√2)
-            resolution as f32,                          // South
-            (resolution * 1.41421356) as f32,           // Southwest (√2)
-            resolution as f32,                          // West
-            (resolution * 1.41421356) as f32,           // Northwest (√2)
-            resolution as f32,                          // North
-            (resolution * 1.41421356) as f32,           // Northeast (√2)
-        ];
-        
-        // For each cell in the DEM
-        for y in 0..height {
-            for x in 0..width {
-                // Skip if no elevation data or outside boundaries
-                if let Some(elev) = self.dem.get_elevation(x, y) {
-                    let cell_idx = y * width + x;
-                    
-                    // Find the steepest downslope neighbor
-                    let mut max_slope = 0.0;
-                    let mut max_dir = FlowDirection::NoFlow;
-                    
-                    // Check all 8 neighboring cells
-                    for i in 0..8 {
-                        let (dx, dy) = directions[i];
-                        let nx = x as isize + dx;
-                        let ny = y as isize + dy;
-                        
-                        // Skip if neighbor is outside the DEM
-                        if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
-                            continue;
-                        }
-                        
-                        // Get neighbor's elevation (if available)
-                        if let Some(n_elev) = self.dem.get_elevation(nx as usize, ny as usize) {
-                            // Calculate elevation difference (drop)
-                            let drop = elev - n_elev;
-                            
-                            // Calculate slope (drop / distance)
-                            let slope = drop / distances[i];
-                            
-                            // If this is a steeper downward slope than we've seen, record it
-                            if slope > max_slope {
-                                max_slope = slope;
-                                max_dir = direction_codes[i];
-                            }
-                        }
-                    }
-                    
-                    // Assign the flow direction and slope
-                    self.flow_directions[cell_idx] = max_dir;
-                    self.slopes[cell_idx] = max_slope;
-                }
+        
+        // Cells are independent, so this runs data-parallel with the `parallel` feature
+        let results = traversal::map_cells(width * height, |idx| self.steepest_descent(idx % width, idx / width));
+        for (cell_idx, result) in results.into_iter().enumerate() {
+            // Skip if no elevation data
+            if let Some((max_dir, max_slope)) = result {
+                // Assign the flow direction and slope
+                self.flow_directions[cell_idx] = max_dir;
+                self.slopes[cell_idx] = max_slope;
             }
         }
         
+        self.apply_lake_routing();
+        
         println!("Flow direction computation completed");
     }
     
+    /// Steepest downslope neighbor and slope of a cell, or None without elevation data
+    fn steepest_descent(&self, x: usize, y: usize) -> Option<(FlowDirection, f32)> {
+        let elev = self.dem.get_elevation(x, y)?;
+        
+        // Find the steepest downslope neighbor
+        let mut max_slope = 0.0;
+        let mut max_dir = FlowDirection::NoFlow;
+        
+        // Check all neighboring cells in the neighborhood
+        for (dir, (nx, ny)) in self.neighborhood().neighbors(x, y) {
+            // Get neighbor's elevation (if available)
+            if let Some(n_elev) = self.dem.get_elevation(nx, ny) {
+                // Calculate elevation difference (drop)
+                let drop = elev - n_elev;
+                
+                // Calculate slope (drop / distance between cell centers)
+                let slope = drop / self.dem.neighbor_distance(dir) as f32;
+                
+                // If this is a steeper downward slope than we've seen, record it
+                if slope > max_slope {
+                    max_slope = slope;
+                    max_dir = FlowDirection::from_index(dir);
+                }
+            }
+        }
+        
+        Some((max_dir, max_slope))
+    }
+    
     /// Determine the downstream cell indices for each cell based on flow direction
     /// Works with both D8 and D∞ flow methods
     pub fn get_downstream_cell(&self, x: usize, y: usize) -> Option<(usize, usize)> {
@@ -192,41 +394,10 @@ impl FlowModel {
         
         match self.flow_method {
             FlowMethod::DInf => {
-                // For D∞, find the downstream cell with highest flow proportion
-                if let Some(ref flow_proportions) = self.dinf_flow_proportions {
-                    let proportions = &flow_proportions[idx];
-                    
-                    // Direction offsets (matching the proportions array)
-                    let directions = [
-                        (1, 0),    // 0: East
-                        (1, 1),    // 1: Southeast  
-                        (0, 1),    // 2: South
-                        (-1, 1),   // 3: Southwest
-                        (-1, 0),   // 4: West
-                        (-1, -1),  // 5: Northwest
-                        (0, -1),   // 6: North
-                        (1, -1),   // 7: Northeast
-                    ];
-                    
-                    // Find the direction with the highest flow proportion
-                    let mut max_proportion = 0.0;
-                    let mut best_direction = None;
-                    
-                    for dir in 0..8 {
-                        if proportions[dir] > max_proportion {
-                            max_proportion = proportions[dir];
-                            let (dx, dy) = directions[dir];
-                            let nx = x as isize + dx;
-                            let ny = y as isize + dy;
-                            
-                            // Ensure the downstream cell is within bounds
-                            if nx >= 0 && ny >= 0 && nx < self.dem.width as isize && ny < self.dem.height as isize {
-                                best_direction = Some((nx as usize, ny as usize));
-                            }
-                        }
-                    }
-                    
-                    best_direction
+                // For D∞, follow the receiver with the larger share of flow
+                if let Some(ref dinf) = self.dinf_flow {
+                    dinf.dominant_direction(idx)
+                        .and_then(|dir| self.neighborhood().neighbor(x, y, dir))
                 } else {
                     // Fallback to D8 if D∞ data not available
                     self.get_downstream_cell_d8(x, y)
@@ -270,6 +441,7 @@ impl FlowModel {
     
     /// Compute flow accumulation based on flow directions
     pub fn compute_flow_accumulation(&mut self) {
+        self.drop_analysis = OnceLock::new();
         match self.flow_method {
             FlowMethod::D8 => self.compute_flow_accumulation_d8(),
             FlowMethod::DInf => self.compute_flow_accumulation_dinf(),
@@ -277,192 +449,211 @@ impl FlowModel {
         }
     }
     
-    /// Compute flow accumulation using D8 method (original implementation)
+    /// Compute flow accumulation using D8 method
     fn compute_flow_accumulation_d8(&mut self) {
         println!("Computing D8 flow accumulation...");
         
-        let width = self.dem.width;
-        let height = self.dem.height;
-        let cell_count = width * height;
-        
-        // Initialize flow accumulation: each cell starts with a value of 1 (itself)
-        self.flow_accumulation = vec![1.0; cell_count];
+        // Each cell starts with a value of 1 (itself)
+        let cell_count = self.dem.width * self.dem.height;
+        let (accumulation, processed_count) = self.accumulate_receivers(vec![1.0; cell_count], None);
+        self.flow_accumulation = accumulation;
         
-        // Count incoming connections for each cell (indegree)
-        let mut indegree = vec![0; cell_count];
+        println!("D8 flow accumulation completed. Processed {} of {} cells", processed_count, cell_count);
+    }
+    
+    /// Compute flow accumulation using D∞ method with proportional distribution
+    fn compute_flow_accumulation_dinf(&mut self) {
+        println!("Computing D∞ flow accumulation...");
         
-        // For each cell, increment indegree of its downstream cell
-        for y in 0..height {
-            for x in 0..width {
-                if let Some((down_x, down_y)) = self.get_downstream_cell(x, y) {
-                    let down_idx = down_y * width + down_x;
-                    indegree[down_idx] += 1;
-                }
-            }
+        // Flow field should exist after compute_flow_directions_dinf
+        if self.dinf_flow.is_none() {
+            println!("Error: D∞ flow proportions not computed. Run compute_flow_directions_dinf first.");
+            return;
         }
         
-        // Use a queue to process cells in topological order
-        let mut queue = VecDeque::new();
+        let cell_count = self.dem.width * self.dem.height;
+        let (accumulation, processed_count) = self.accumulate_receivers(vec![1.0; cell_count], None);
+        self.flow_accumulation = accumulation;
         
-        // Start with cells that have no upstream connections (leaf nodes in the flow tree)
-        for y in 0..height {
-            for x in 0..width {
-                let idx = y * width + x;
-                if indegree[idx] == 0 && self.dem.get_elevation(x, y).is_some() {
-                    queue.push_back((x, y));
-                }
-            }
+        println!("D∞ flow accumulation completed. Processed {} of {} cells", processed_count, cell_count);
+    }
+    
+    /// Accumulate a per-cell weight raster (rainfall depth, runoff coefficient,
+    /// pollutant load, snowmelt, ...) along the current flow paths.
+    ///
+    /// `weights` must be aligned with the DEM (row-major, one value per cell).
+    /// `loss` optionally gives the fraction (0-1) of the accumulated value lost in each
+    /// cell before it is passed downstream, e.g. infiltration or first-order decay.
+    /// The value at a cell includes its own weight and is reported before its own loss.
+    /// For MFD the proportions of the last `compute_flow_accumulation` are reused.
+    pub fn compute_weighted_accumulation(&self, weights: &[f32], loss: Option<&[f32]>) -> Result<Vec<f32>, String> {
+        let cell_count = self.dem.width * self.dem.height;
+        if weights.len() != cell_count {
+            return Err(format!("Weight grid has {} values, expected {} ({}x{})",
+                               weights.len(), cell_count, self.dem.width, self.dem.height));
         }
-        
-        // Process cells in topological order
-        let mut processed_count = 0;
-        while let Some((x, y)) = queue.pop_front() {
-            processed_count += 1;
-            
-            // Find downstream cell and pass accumulation to it
-            if let Some((down_x, down_y)) = self.get_downstream_cell(x, y) {
-                let down_idx = down_y * width + down_x;
-                let current_idx = y * width + x;
-                
-                // Add the accumulation from current cell to downstream cell
-                self.flow_accumulation[down_idx] += self.flow_accumulation[current_idx];
-                
-                // Decrement indegree of downstream cell
-                indegree[down_idx] -= 1;
-                
-                // If downstream cell has no more upstream cells to process, add it to queue
-                if indegree[down_idx] == 0 {
-                    queue.push_back((down_x, down_y));
-                }
+        if let Some(loss) = loss {
+            if loss.len() != cell_count {
+                return Err(format!("Loss grid has {} values, expected {} ({}x{})",
+                                   loss.len(), cell_count, self.dem.width, self.dem.height));
             }
         }
+        if self.flow_method == FlowMethod::MFD && self.mfd_flow_proportions.is_none() {
+            return Err("MFD proportions not computed. Run compute_flow_accumulation first.".to_string());
+        }
         
-        println!("D8 flow accumulation completed. Processed {} of {} cells", processed_count, cell_count);
+        println!("Computing weighted {:?} flow accumulation...", self.flow_method);
+        let (accumulation, processed_count) = self.accumulate_receivers(weights.to_vec(), loss);
+        println!("Weighted flow accumulation completed. Processed {} of {} cells", processed_count, cell_count);
+        
+        Ok(accumulation)
     }
     
-    /// Compute flow accumulation using D∞ method with proportional distribution
-    fn compute_flow_accumulation_dinf(&mut self) {
-        println!("Computing D∞ flow accumulation...");
-        
-        let width = self.dem.width;
-        let height = self.dem.height;
-        let cell_count = width * height;
+    /// Upstream contributing area of every cell in m²
+    pub fn contributing_area_m2(&self) -> Vec<f32> {
+        let cell_area = self.dem.cell_area() as f32;
+        self.flow_accumulation.iter().map(|&cells| cells * cell_area).collect()
+    }
+    
+    /// Upstream contributing area of every cell in km²
+    pub fn contributing_area_km2(&self) -> Vec<f32> {
+        let cell_area = (self.dem.cell_area() / 1.0e6) as f32;
+        self.flow_accumulation.iter().map(|&cells| cells * cell_area).collect()
+    }
+    
+    /// Flow direction of a cell as a map angle in radians (0 = east, increasing clockwise),
+    /// or None if the cell does not drain anywhere
+    pub fn flow_angle(&self, idx: usize) -> Option<f32> {
+        if let (FlowMethod::DInf, Some(dinf)) = (self.flow_method, &self.dinf_flow) {
+            return dinf.dominant_direction(idx).map(|_| dinf.angles[idx]);
+        }
         
-        // Get flow proportions (should exist after compute_flow_directions_dinf)
-        let flow_proportions = match &self.dinf_flow_proportions {
-            Some(props) => props,
-            None => {
-                println!("Error: D∞ flow proportions not computed. Run compute_flow_directions_dinf first.");
-                return;
+        let dir = self.flow_directions[idx].index()?;
+        let (dx, dy) = neighborhood::OFFSETS[dir];
+        let angle = (dy as f64 * self.dem.y_resolution).atan2(dx as f64 * self.dem.x_resolution);
+        Some(angle.rem_euclid(2.0 * std::f64::consts::PI) as f32)
+    }
+    
+    /// Specific catchment area (m² per m of contour width) of every cell.
+    ///
+    /// The contour width is the width of the cell measured perpendicular to its flow
+    /// direction, so it is the cell height for east/west flow, the cell width for
+    /// north/south flow and grows towards the diagonals. Cells without outflow use
+    /// the mean cell size.
+    pub fn specific_catchment_area(&self) -> Vec<f32> {
+        let cell_area = self.dem.cell_area() as f32;
+        let (x_res, y_res) = (self.dem.x_resolution as f32, self.dem.y_resolution as f32);
+        
+        self.flow_accumulation.iter()
+            .enumerate()
+            .map(|(idx, &cells)| {
+                let contour_width = self.flow_angle(idx)
+                    .map_or(self.dem.resolution as f32, |angle| {
+                        (x_res * angle.sin()).abs() + (y_res * angle.cos()).abs()
+                    });
+                cells * cell_area / contour_width
+            })
+            .collect()
+    }
+    
+    /// Downstream receivers of a cell under the active flow method, with the share
+    /// of the cell's flow each one receives
+    pub fn receivers(&self, idx: usize) -> Receivers {
+        let hood = Neighborhood::new(self.dem.width, self.dem.height, Connectivity::D8);
+        let mut receivers = Receivers::empty();
+        
+        match (self.flow_method, &self.dinf_flow, &self.mfd_flow_proportions) {
+            (FlowMethod::DInf, Some(dinf), _) => {
+                for (dir, proportion) in dinf.proportions(idx) {
+                    receivers.push_direction(&hood, idx, dir as usize, proportion);
+                }
             }
-        };
-        
-        // Initialize flow accumulation: each cell starts with a value of 1 (itself)
-        self.flow_accumulation = vec![1.0; cell_count];
-        
-        // Direction offsets (matching the proportions array)
-        let directions = [
-            (1, 0),    // 0: East
-            (1, 1),    // 1: Southeast  
-            (0, 1),    // 2: South
-            (-1, 1),   // 3: Southwest
-            (-1, 0),   // 4: West
-            (-1, -1),  // 5: Northwest
-            (0, -1),   // 6: North
-            (1, -1),   // 7: Northeast
-        ];
-        
-        // Count incoming flow proportions for each cell (for ordering)
-        let mut incoming_flow = vec![0.0; cell_count];
-        for y in 0..height {
-            for x in 0..width {
-                let idx = y * width + x;
-                for dir in 0..8 {
-                    let proportion = flow_proportions[idx][dir];
-                    if proportion > 0.0 {
-                        let (dx, dy) = directions[dir];
-                        let nx = x as isize + dx;
-                        let ny = y as isize + dy;
-                        
-                        if nx >= 0 && ny >= 0 && nx < width as isize && ny < height as isize {
-                            let downstream_idx = ny as usize * width + nx as usize;
-                            incoming_flow[downstream_idx] += proportion;
-                        }
-                    }
+            (FlowMethod::MFD, _, Some(proportions)) => {
+                for (dir, &proportion) in proportions[idx].iter().enumerate() {
+                    receivers.push_direction(&hood, idx, dir, proportion);
                 }
             }
-        }
-        
-        // Process cells from highest to lowest elevation to ensure proper ordering
-        let mut cell_elevations: Vec<(usize, usize, f32)> = Vec::new();
-        for y in 0..height {
-            for x in 0..width {
-                if let Some(elev) = self.dem.get_elevation(x, y) {
-                    cell_elevations.push((x, y, elev));
+            _ => {
+                if let Some(dir) = self.flow_directions[idx].index() {
+                    receivers.push_direction(&hood, idx, dir, 1.0);
                 }
             }
         }
         
-        // Sort by elevation (highest first)
-        cell_elevations.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
-        
-        let mut processed_count = 0;
-        
-        // Process each cell in elevation order
-        for (x, y, _elev) in cell_elevations {
-            processed_count += 1;
-            let idx = y * width + x;
-            
-            // Distribute this cell's flow to downstream neighbors according to proportions
-            for dir in 0..8 {
-                let proportion = flow_proportions[idx][dir];
-                if proportion > 0.0 {
-                    let (dx, dy) = directions[dir];
-                    let nx = x as isize + dx;
-                    let ny = y as isize + dy;
-                    
-                    if nx >= 0 && ny >= 0 && nx < width as isize && ny < height as isize {
-                        let downstream_idx = ny as usize * width + nx as usize;
-                        
-                        // Add proportional flow to downstream cell
-                        self.flow_accumulation[downstream_idx] += 
-                            self.flow_accumulation[idx] * proportion;
-                    }
+        receivers
+    }
+    
+    /// Share of a cell's flow sent to its neighbor in direction `dir` under the active flow method
+    pub fn share_towards(&self, idx: usize, dir: usize) -> f32 {
+        match (self.flow_method, &self.dinf_flow, &self.mfd_flow_proportions) {
+            (FlowMethod::DInf, Some(dinf), _) => {
+                if dinf.receiver_a[idx] as usize == dir {
+                    dinf.fraction_a[idx]
+                } else if dinf.receiver_b[idx] as usize == dir {
+                    1.0 - dinf.fraction_a[idx]
+                } else {
+                    0.0
                 }
             }
+            (FlowMethod::MFD, _, Some(proportions)) => proportions[idx][dir],
+            _ => {
+                if self.flow_directions[idx].index() == Some(dir) { 1.0 } else { 0.0 }
+            }
         }
-        
-        println!("D∞ flow accumulation completed. Processed {} of {} cells", processed_count, cell_count);
     }
     
-    /// Extract a stream network using a flow accumulation threshold
-    pub fn extract_stream_network(&self, threshold: f32) -> Vec<(usize, usize)> {
+    /// Upstream neighbors that drain into a cell, with the share of their flow it receives.
+    /// Neighbors are visited in direction order.
+    pub fn donors(&self, idx: usize) -> impl Iterator<Item = (usize, f32)> + '_ {
+        let hood = Neighborhood::new(self.dem.width, self.dem.height, Connectivity::D8);
+        (0..8).filter_map(move |dir| {
+            let upstream_idx = hood.neighbor_index(idx, dir)?;
+            let share = self.share_towards(upstream_idx, neighborhood::opposite(dir));
+            (share > 0.0).then_some((upstream_idx, share))
+        })
+    }
+    
+    /// Accumulate values through the flow graph of the active method in topological order.
+    ///
+    /// A cell is settled once every upstream contribution has arrived, so flats and equal
+    /// elevations cannot release flow early. Each cell pulls its inflow from its
+    /// neighbors in fixed direction order, which keeps the floating-point sums identical
+    /// between the serial and parallel paths. Stored proportions sum to one, so
+    /// everything that enters a cell leaves it again (or leaves the DEM), minus the
+    /// optional per-cell `loss` fraction.
+    /// Returns the accumulation and the number of cells processed.
+    fn accumulate_receivers(&self, initial: Vec<f32>, loss: Option<&[f32]>) -> (Vec<f32>, usize) {
         let width = self.dem.width;
-        let height = self.dem.height;
-        let mut streams = Vec::new();
         
-        for y in 0..height {
-            for x in 0..width {
-                let idx = y * width + x;
-                if self.flow_accumulation[idx] >= threshold {
-                    streams.push((x, y));
+        traversal::settle_topological(
+            initial.len(),
+            |idx| self.dem.get_elevation(idx % width, idx / width).is_some(),
+            |idx| self.receivers(idx).map(|(downstream_idx, _)| downstream_idx),
+            |idx, accumulation: &[f32]| {
+                let mut total = initial[idx];
+                for (upstream_idx, proportion) in self.donors(idx) {
+                    let passed = loss.map_or(1.0, |loss| 1.0 - loss[upstream_idx].clamp(0.0, 1.0));
+                    total += accumulation[upstream_idx] * passed * proportion;
                 }
-            }
-        }
-        
-        streams
+                total
+            },
+        )
+    }
+    
+    /// Extract a stream network using a stream initiation criterion
+    pub fn extract_stream_network(&self, threshold: StreamThreshold) -> Vec<(usize, usize)> {
+        let width = self.dem.width;
+        self.stream_mask(threshold).iter()
+            .enumerate()
+            .filter(|(_, &is_stream)| is_stream)
+            .map(|(idx, _)| (idx % width, idx / width))
+            .collect()
     }
     
     /// Get major streams based on a percentile threshold
     pub fn get_major_streams(&self, percentile: f32) -> Vec<(usize, usize)> {
-        // Find the maximum flow accumulation value
-        let max_flow = self.flow_accumulation.iter()
-            .fold(0.0_f32, |max, &val| max.max(val));
-        
-        // Calculate threshold as a percentage of maximum flow
-        let threshold = max_flow * percentile;
-        
-        self.extract_stream_network(threshold)
+        // Threshold as a percentage of maximum flow
+        self.extract_stream_network(StreamThreshold::FractionOfMax(percentile))
     }
     
     /// Get the slope at a specific point
@@ -475,456 +666,396 @@ impl FlowModel {
         Some(self.slopes[idx])
     }
     
-    /// Get the flow direction at a specific point
-    pub fn get_flow_direction(&self, x: usize, y: usize) -> Option<FlowDirection> {
-        if x >= self.dem.width || y >= self.dem.height {
-            return None;
+    /// Compute D∞ flow directions for each cell using Tarboton's (1997) method.
+    ///
+    /// The 3×3 window is split into eight triangular facets, each spanned by the
+    /// center cell, one cardinal and one diagonal neighbor. A plane is fitted to every
+    /// facet and the steepest downslope facet gives a continuous flow angle and slope.
+    /// Flow is then split between the facet's two neighbors in proportion to how close
+    /// the angle is to each of them.
+    ///
+    /// Every facet has a diagonal neighbor, so D∞ is not available under D4 connectivity.
+    pub fn compute_flow_directions_dinf(&mut self) -> Result<(), String> {
+        if self.connectivity == Connectivity::D4 {
+            return Err("D∞ routing needs diagonal neighbors and cannot be used with D4 connectivity".to_string());
         }
         
-        let idx = y * self.dem.width + x;
-        Some(self.flow_directions[idx])
-    }
-    
-    /// Get the flow accumulation at a specific point
-    pub fn get_flow_accumulation(&self, x: usize, y: usize) -> Option<f32> {
-        if x >= self.dem.width || y >= self.dem.height {
-            return None;
-        }
-        
-        let idx = y * self.dem.width + x;
-        Some(self.flow_accumulation[idx])
-    }
-    
-    /// Compute D∞ flow directions for each cell using Tarboton's method
-    pub fn compute_flow_directions_dinf(&mut self) {
         println!("Computing D∞ flow directions...");
         
         let width = self.dem.width;
         let height = self.dem.height;
-        let resolution = self.dem.resolution;
         let cell_count = width * height;
         
         // Initialize D∞ data structures
-        let mut flow_angles = vec![0.0; cell_count];
-        let mut flow_proportions = vec![vec![0.0; 8]; cell_count];
-        
-        // 8 neighbor directions (matching D8 order)
-        let directions = [
-            (1, 0),    // 0: East
-            (1, 1),    // 1: Southeast  
-            (0, 1),    // 2: South
-            (-1, 1),   // 3: Southwest
-            (-1, 0),   // 4: West
-            (-1, -1),  // 5: Northwest
-            (0, -1),   // 6: North
-            (1, -1),   // 7: Northeast
-        ];
-        
-        // Corresponding angles for each direction (in radians)
-        let direction_angles = [
-            0.0,                    // 0: East
-            std::f32::consts::PI / 4.0,      // 1: Southeast
-            std::f32::consts::PI / 2.0,      // 2: South
-            3.0 * std::f32::consts::PI / 4.0, // 3: Southwest
-            std::f32::consts::PI,            // 4: West
-            5.0 * std::f32::consts::PI / 4.0, // 5: Northwest
-            3.0 * std::f32::consts::PI / 2.0, // 6: North
-            7.0 * std::f32::consts::PI / 4.0, // 7: Northeast
-        ];
-        
-        for y in 0..height {
-            for x in 0..width {
-                if let Some(elev) = self.dem.get_elevation(x, y) {
-                    let idx = y * width + x;
-                    
-                    // Calculate steepest slope and its direction using planar fitting
-                    let mut max_slope = 0.0;
-                    let mut best_angle = 0.0;
-                    
-                    // Check all 8 adjacent cells to find steepest descent
-                    for i in 0..8 {
-                        let (dx, dy) = directions[i];
-                        let nx = x as isize + dx;
-                        let ny = y as isize + dy;
-                        
-                        if nx >= 0 && ny >= 0 && nx < width as isize && ny < height as isize {
-                            if let Some(n_elev) = self.dem.get_elevation(nx as usize, ny as usize) {
-                                let drop = elev - n_elev;
-                                if drop > 0.0 {
-                                    // Calculate distance (accounting for diagonals)
-                                    let distance = if dx.abs() + dy.abs() == 2 {
-                                        resolution * std::f64::consts::SQRT_2 // Diagonal
-                                    } else {
-                                        resolution // Cardinal
-                                    };
-                                    
-                                    let slope = drop / distance as f32;
-                                    if slope > max_slope {
-                                        max_slope = slope;
-                                        best_angle = direction_angles[i];
-                                    }
-                                }
-                            }
-                        }
-                    }
+        let mut dinf = DinfFlowField::new(cell_count);
+        
+        // Facets are fitted per cell, data-parallel with the `parallel` feature
+        let facets = traversal::map_cells(cell_count, |idx| {
+            let (x, y) = (idx % width, idx / width);
+            self.dem.get_elevation(x, y).map(|_| self.steepest_dinf_facet(x, y))
+        });
+        
+        for (idx, facet) in facets.into_iter().enumerate() {
+            match facet {
+                Some(Some(facet)) => {
+                    self.slopes[idx] = facet.slope;
+                    dinf.set_split(idx, facet.angle, facet.cardinal, facet.diagonal, 1.0 - facet.diagonal_fraction);
                     
-                    if max_slope > 0.0 {
-                        // Store the continuous flow angle
-                        flow_angles[idx] = best_angle;
-                        self.slopes[idx] = max_slope;
-                        
-                        // Calculate flow proportions to adjacent cells
-                        // Find which two adjacent directions bracket the flow angle
-                        let mut dir1 = 0;
-                        let mut dir2 = 1;
-                        
-                        for i in 0..8 {
-                            let next_i = (i + 1) % 8;
-                            let angle1 = direction_angles[i];
-                            let mut angle2 = direction_angles[next_i];
-                            
-                            // Handle angle wraparound at 2π
-                            if angle2 < angle1 {
-                                angle2 += 2.0 * std::f32::consts::PI;
-                            }
-                            
-                            let mut test_angle = best_angle;
-                            if test_angle < angle1 {
-                                test_angle += 2.0 * std::f32::consts::PI;
-                            }
-                            
-                            if test_angle >= angle1 && test_angle <= angle2 {
-                                dir1 = i;
-                                dir2 = next_i;
-                                break;
-                            }
-                        }
-                        
-                        // Calculate proportional flow split between the two directions
-                        let angle1 = direction_angles[dir1];
-                        let mut angle2 = direction_angles[dir2];
-                        if angle2 < angle1 {
-                            angle2 += 2.0 * std::f32::consts::PI;
-                        }
-                        
-                        let mut flow_angle = best_angle;
-                        if flow_angle < angle1 {
-                            flow_angle += 2.0 * std::f32::consts::PI;
-                        }
-                        
-                        // Linear interpolation between the two directions
-                        let total_angle_diff = angle2 - angle1;
-                        if total_angle_diff > 0.0 {
-                            let prop2 = (flow_angle - angle1) / total_angle_diff;
-                            let prop1 = 1.0 - prop2;
-                            
-                            // Ensure both directions are valid (within bounds and downslope)
-                            let (dx1, dy1) = directions[dir1];
-                            let (dx2, dy2) = directions[dir2];
-                            
-                            let nx1 = x as isize + dx1;
-                            let ny1 = y as isize + dy1;
-                            let nx2 = x as isize + dx2;
-                            let ny2 = y as isize + dy2;
-                            
-                            if nx1 >= 0 && ny1 >= 0 && nx1 < width as isize && ny1 < height as isize {
-                                if let Some(n_elev1) = self.dem.get_elevation(nx1 as usize, ny1 as usize) {
-                                    if elev > n_elev1 {
-                                        flow_proportions[idx][dir1] = prop1;
-                                    }
-                                }
-                            }
-                            
-                            if nx2 >= 0 && ny2 >= 0 && nx2 < width as isize && ny2 < height as isize {
-                                if let Some(n_elev2) = self.dem.get_elevation(nx2 as usize, ny2 as usize) {
-                                    if elev > n_elev2 {
-                                        flow_proportions[idx][dir2] = prop2;
-                                    }
-                                }
-                            }
-                        } else {
-                            // Angle difference is zero, put all flow in first direction
-                            let (dx1, dy1) = directions[dir1];
-                            let nx1 = x as isize + dx1;
-                            let ny1 = y as isize + dy1;
-                            
-                            if nx1 >= 0 && ny1 >= 0 && nx1 < width as isize && ny1 < height as isize {
-                                if let Some(n_elev1) = self.dem.get_elevation(nx1 as usize, ny1 as usize) {
-                                    if elev > n_elev1 {
-                                        flow_proportions[idx][dir1] = 1.0;
-                                    }
-                                }
-                            }
-                        }
-                    }
+                    // Dominant receiver for consumers that need a single direction
+                    self.flow_directions[idx] = dinf.dominant_direction(idx)
+                        .map_or(FlowDirection::NoFlow, FlowDirection::from_index);
+                }
+                Some(None) => {
+                    self.slopes[idx] = 0.0;
+                    self.flow_directions[idx] = FlowDirection::NoFlow;
                 }
+                None => {} // No elevation data
             }
         }
         
         // Store D∞ data and set method
-        self.dinf_flow_angles = Some(flow_angles);
-        self.dinf_flow_proportions = Some(flow_proportions);
+        println!("D∞ flow field uses {:.1} MB", dinf.memory_bytes() as f64 / 1_048_576.0);
+        self.dinf_flow = Some(dinf);
         self.flow_method = FlowMethod::DInf;
         
+        self.apply_lake_routing();
+        
         println!("D∞ flow direction computation completed");
+        Ok(())
     }
     
-    /// Compute flow accumulation based on flow directions using MFD method
-    fn compute_flow_accumulation_mfd(&mut self) {
-        let width = self.dem.width;
-        let height = self.dem.height;
-        let cell_count = width * height;
+    /// Fit planes to the eight triangular facets around a cell and return the
+    /// steepest downslope one, or None for sinks, flats and cells without valid facets
+    fn steepest_dinf_facet(&self, x: usize, y: usize) -> Option<DinfFacet> {
+        use std::f32::consts::{FRAC_PI_2, PI};
+        
+        let e0 = self.dem.get_elevation(x, y)?;
+        let hood = Neighborhood::new(self.dem.width, self.dem.height, Connectivity::D8);
+        let mut best: Option<DinfFacet> = None;
+        
+        // Facet k lies between direction k and k + 1: one cardinal, one diagonal neighbor
+        for k in 0..8 {
+            let (cardinal, diagonal) = if k % 2 == 0 { (k, k + 1) } else { ((k + 1) % 8, k) };
+            
+            let e1 = match hood.neighbor(x, y, cardinal).and_then(|(nx, ny)| self.dem.get_elevation(nx, ny)) {
+                Some(e) => e,
+                None => continue,
+            };
+            let e2 = match hood.neighbor(x, y, diagonal).and_then(|(nx, ny)| self.dem.get_elevation(nx, ny)) {
+                Some(e) => e,
+                None => continue,
+            };
+            
+            // d1: distance to the cardinal neighbor, d2: side step from it to the diagonal
+            let d1 = self.dem.neighbor_distance(cardinal) as f32;
+            let d2 = self.dem.neighbor_distance((cardinal + 2) % 8) as f32;
+            let facet_width = d2.atan2(d1); // Angle between cardinal and diagonal direction
+            
+            let s1 = (e0 - e1) / d1;
+            let s2 = (e1 - e2) / d2;
+            let mut r = s2.atan2(s1);
+            let mut slope = s1.hypot(s2);
+            
+            // Clamp the steepest direction to the facet's edges
+            if r < 0.0 {
+                r = 0.0;
+                slope = s1;
+            } else if r > facet_width {
+                r = facet_width;
+                slope = (e0 - e2) / d1.hypot(d2);
+            }
+            
+            if slope > 0.0 && best.as_ref().is_none_or(|b| slope > b.slope) {
+                // Angles grow with the direction index (clockwise on a north-up map)
+                let sign = if diagonal == (cardinal + 1) % 8 { 1.0 } else { -1.0 };
+                let angle = (cardinal / 2) as f32 * FRAC_PI_2 + sign * r;
+                
+                best = Some(DinfFacet {
+                    angle: angle.rem_euclid(2.0 * PI),
+                    slope,
+                    cardinal,
+                    diagonal,
+                    diagonal_fraction: r / facet_width,
+                });
+            }
+        }
         
-        // Create a matrix to store flow proportions from each cell to its neighbors
-        // For each cell, store up to 8 flow proportions (one for each neighbor)
-        let mut flow_proportions = vec![vec![0.0; 8]; cell_count];
+        best
+    }
+    
+    /// Prepare multiple flow direction (MFD) routing.
+    ///
+    /// D8 directions are still computed so single-path consumers (stream tracing,
+    /// downstream lookups) keep working; the accumulation then uses MFD.
+    pub fn compute_flow_directions_mfd(&mut self, options: MfdOptions) {
+        self.compute_flow_directions();
+        self.mfd_options = options;
+        self.flow_method = FlowMethod::MFD;
+    }
+    
+    /// Compute flow accumulation using the MFD method (Freeman 1991 / Quinn et al. 1991).
+    ///
+    /// Cells are processed once in topological order of the downslope graph, so the
+    /// result is exact in a single pass. Proportions are computed when a cell is
+    /// dequeued, which lets cells above the single-flow threshold switch to D8.
+    fn compute_flow_accumulation_mfd(&mut self) {
+        println!("Computing MFD flow accumulation ({:?})...", self.mfd_options);
         
-        // Calculate flow proportions for each cell
-        for y in 0..height {
-            for x in 0..width {
-                if let Some(elev) = self.dem.get_elevation(x, y) {
-                    let idx = y * width + x;
-                    
-                    // Get all downslope neighbors and their slopes
-                    let mut downslope_neighbors = Vec::new();
-                    let directions = [
-                        (1, 0), (1, 1), (0, 1), (-1, 1),
-                        (-1, 0), (-1, -1), (0, -1), (1, -1)
-                    ];
-                    
-                    let distances = [
-                        self.dem.resolution as f32,
-                        (2.0 * self.dem.resolution * self.dem.resolution) as f32,
-                        self.dem.resolution as f32,
-                        (2.0 * self.dem.resolution * self.dem.resolution) as f32,
-                        self.dem.resolution as f32,
-                        (2.0 * self.dem.resolution * self.dem.resolution) as f32,
-                        self.dem.resolution as f32,
-                        (2.0 * self.dem.resolution * self.dem.resolution) as f32,
-                    ];
-                    
-                    for i in 0..8 {
-                        let (dx, dy) = directions[i];
-                        let nx = x as isize + dx;
-                        let ny = y as isize + dy;
-                        
-                        // Skip if neighbor is outside the DEM
-                        if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
-                            continue;
-                        }
-                        
-                        // Get neighbor's elevation (if available)
-                        if let Some(n_elev) = self.dem.get_elevation(nx as usize, ny as usize) {
-                            // Calculate elevation difference (drop)
-                            let drop = elev - n_elev;
-                            
-                            // Only consider downslope neighbors
-                            if drop > 0.0 {
-                                // Calculate slope (drop / distance)
-                                let slope = drop / distances[i];
-                                downslope_neighbors.push((i, slope));
-                            }
-                        }
+        let width = self.dem.width;
+        let cell_count = width * self.dem.height;
+        let contour_lengths = self.mfd_contour_lengths();
+        
+        // Downslope gradients to each neighbor (cells are independent, so data-parallel)
+        let gradients: Vec<[f32; 8]> = traversal::map_cells(cell_count, |idx| self.mfd_gradients(idx));
+        
+        // Every downslope neighbor is a link of the graph, even if it receives nothing
+        // after convergence. A cell pulls flow from upstream neighbors in direction order,
+        // then computes its own proportions (each cell starts with 1.0).
+        let (settled, processed_count) = traversal::settle_topological(
+            cell_count,
+            |idx| self.dem.get_elevation(idx % width, idx / width).is_some(),
+            |idx| self.mfd_links(gradients[idx], idx),
+            |idx, settled: &[(f32, [f32; 8])]| {
+                let accumulation = self.mfd_inflow(idx, |upstream_idx| gradients[upstream_idx], |upstream_idx| settled[upstream_idx]);
+                (accumulation, self.mfd_proportions(&gradients[idx], &contour_lengths, accumulation))
+            },
+        );
+        
+        let (accumulation, flow_proportions): (Vec<f32>, MfdProportions) = settled.into_iter().unzip();
+        
+        self.flow_accumulation = accumulation;
+        self.mfd_flow_proportions = Some(flow_proportions);
+        
+        println!("MFD flow accumulation completed. Processed {} of {} cells", processed_count, cell_count);
+    }
+    
+    /// Contour lengths for the Quinn weighting (cardinal: half the facing side,
+    /// diagonal: 0.354 of the geometric mean cell size), or 1 without contour weighting
+    fn mfd_contour_lengths(&self) -> [f32; 8] {
+        std::array::from_fn(|dir| {
+            let distance = |dir: usize| self.dem.neighbor_distance(dir) as f32;
+            if !self.mfd_options.contour_weighting {
+                1.0
+            } else if neighborhood::is_diagonal(dir) {
+                0.354 * self.dem.cell_area().sqrt() as f32
+            } else {
+                // East/west neighbors face a side of length y_resolution and vice versa
+                0.5 * distance((dir + 2) % 8)
+            }
+        })
+    }
+    
+    /// Downslope gradients of a cell to each neighbor (0 where the neighbor is not lower)
+    fn mfd_gradients(&self, idx: usize) -> [f32; 8] {
+        let mut cell_gradients = [0.0f32; 8];
+        let (x, y) = (idx % self.dem.width, idx / self.dem.width);
+        let hood = self.neighborhood();
+        if let Some(elev) = self.dem.get_elevation(x, y) {
+            // Lake surfaces are flat: follow the centerline routing instead
+            if self.lake_ids[idx] != NO_LAKE {
+                if let Some(dir) = self.flow_directions[idx].index() {
+                    if hood.neighbor_index(idx, dir).is_some() {
+                        cell_gradients[dir] = 1.0;
                     }
+                }
+                return cell_gradients;
+            }
+            
+            for (dir, (nx, ny)) in hood.neighbors(x, y) {
+                if let Some(n_elev) = self.dem.get_elevation(nx, ny) {
+                    let drop = elev - n_elev;
                     
-                    // Calculate flow proportion for each downslope neighbor
-                    // Using Quinn et al. (1991) method with exponent
-                    let exponent = 1.1; // Adjustable parameter
-                    let total_slope = downslope_neighbors.iter()
-                        .map(|(_, slope)| slope.powf(exponent))
-                        .sum::<f32>();
-                    
-                    if total_slope > 0.0 {
-                        for (dir_idx, slope) in downslope_neighbors {
-                            flow_proportions[idx][dir_idx] = slope.powf(exponent) / total_slope;
-                        }
+                    // Only consider downslope neighbors
+                    if drop > 0.0 {
+                        cell_gradients[dir] = drop / self.dem.neighbor_distance(dir) as f32;
                     }
                 }
             }
         }
+        cell_gradients
+    }
+    
+    /// Downslope neighbors of a cell, the links of the MFD graph
+    fn mfd_links(&self, cell_gradients: [f32; 8], idx: usize) -> impl Iterator<Item = usize> {
+        let hood = self.neighborhood();
+        (0..8)
+            .filter(move |&dir| cell_gradients[dir] > 0.0)
+            .map(move |dir| hood.neighbor_index(idx, dir).unwrap())
+    }
+    
+    /// MFD accumulation of a cell: its own unit plus the shares of its upslope neighbors,
+    /// pulled in direction order. `settled` gives a neighbor's accumulation and proportions.
+    fn mfd_inflow(&self,
+                  idx: usize,
+                  gradients: impl Fn(usize) -> [f32; 8],
+                  settled: impl Fn(usize) -> (f32, [f32; 8])) -> f32 {
+        let hood = self.neighborhood();
+        let mut accumulation = 1.0;
+        for dir in 0..8 {
+            let back = neighborhood::opposite(dir);
+            if let Some(upstream_idx) = hood.neighbor_index(idx, dir) {
+                if gradients(upstream_idx)[back] > 0.0 {
+                    let (upstream_accumulation, upstream_proportions) = settled(upstream_idx);
+                    accumulation += upstream_accumulation * upstream_proportions[back];
+                }
+            }
+        }
+        accumulation
+    }
+    
+    /// MFD proportions of a cell given its gradients and final accumulation
+    fn mfd_proportions(&self, cell_gradients: &[f32; 8], contour_lengths: &[f32; 8], accumulation: f32) -> [f32; 8] {
+        let options = self.mfd_options;
+        let mut proportions = [0.0f32; 8];
+        
+        let (steepest_dir, steepest) = cell_gradients.iter()
+            .enumerate()
+            .fold((0, 0.0_f32), |best, (dir, &g)| if g > best.1 { (dir, g) } else { best });
+        if steepest <= 0.0 {
+            return proportions; // Sink or outlet: nothing to distribute
+        }
         
-        // Initialize flow accumulation (each cell starts with 1.0)
-        self.flow_accumulation = vec![1.0; cell_count];
-        
-        // Create a copy for the iterative calculation
-        let mut new_flow_accumulation = self.flow_accumulation.clone();
+        let converge = options.single_flow_threshold
+            .is_some_and(|threshold| accumulation > threshold);
         
-        // Iterative flow routing until convergence
-        for _ in 0..20 { // Run for a fixed number of iterations
-            for y in 0..height {
-                for x in 0..width {
-                    let idx = y * width + x;
-                    
-                    // Distribute flow to downslope neighbors
-                    for dir_idx in 0..8 {
-                        let proportion = flow_proportions[idx][dir_idx];
-                        if proportion > 0.0 {
-                            let (dx, dy) = match dir_idx {
-                                0 => (1, 0),    // E
-                                1 => (1, 1),    // SE
-                                2 => (0, 1),    // S
-                                3 => (-1, 1),   // SW
-                                4 => (-1, 0),   // W
-                                5 => (-1, -1),  // NW
-                                6 => (0, -1),   // N
-                                7 => (1, -1),   // NE
-                                _ => unreachable!()
-                            };
-                            
-                            let nx = x as isize + dx;
-                            let ny = y as isize + dy;
-                            
-                            if nx >= 0 && ny >= 0 && nx < width as isize && ny < height as isize {
-                                let n_idx = ny as usize * width + nx as usize;
-                                new_flow_accumulation[n_idx] += self.flow_accumulation[idx] * proportion;
-                            }
+        if converge {
+            proportions[steepest_dir] = 1.0;
+        } else {
+            let exponent = options.exponent.exponent_for(steepest);
+            let weights: [f32; 8] = std::array::from_fn(|dir| {
+                if cell_gradients[dir] > 0.0 {
+                    cell_gradients[dir].powf(exponent) * contour_lengths[dir]
+                } else {
+                    0.0
+                }
+            });
+            let total: f32 = weights.iter().sum();
+            for dir in 0..8 {
+                proportions[dir] = weights[dir] / total;
+            }
+        }
+        proportions
+    }
+    
+    /// Recompute flow directions and slopes of the given cells with the active method,
+    /// after their elevations or those of their neighbors changed. Matches a full
+    /// `compute_flow_directions*` pass on these cells; lake routing is not reapplied.
+    pub fn update_flow_directions(&mut self, cells: &[usize]) {
+        let width = self.dem.width;
+        for &idx in cells {
+            let (x, y) = (idx % width, idx / width);
+            match self.flow_method {
+                FlowMethod::DInf => {
+                    if self.dem.get_elevation(x, y).is_none() {
+                        continue;
+                    }
+                    let facet = self.steepest_dinf_facet(x, y);
+                    let Some(dinf) = self.dinf_flow.as_mut() else { continue };
+                    match facet {
+                        Some(facet) => {
+                            self.slopes[idx] = facet.slope;
+                            dinf.set_split(idx, facet.angle, facet.cardinal, facet.diagonal, 1.0 - facet.diagonal_fraction);
+                            self.flow_directions[idx] = dinf.dominant_direction(idx)
+                                .map_or(FlowDirection::NoFlow, FlowDirection::from_index);
+                        }
+                        None => {
+                            self.slopes[idx] = 0.0;
+                            self.flow_directions[idx] = FlowDirection::NoFlow;
+                            dinf.set_single(idx, None);
                         }
                     }
                 }
+                FlowMethod::D8 | FlowMethod::MFD => {
+                    if let Some((dir, slope)) = self.steepest_descent(x, y) {
+                        self.flow_directions[idx] = dir;
+                        self.slopes[idx] = slope;
+                    }
+                }
             }
-            
-            // Update the flow accumulation
-            self.flow_accumulation = new_flow_accumulation.clone();
-            
-            // Reset for next iteration (keep the initial 1.0 for each cell)
-            new_flow_accumulation = vec![1.0; cell_count];
         }
     }
     
-    /// Extract a high-quality stream network with minimal downsampling
-    /// Returns stream polylines with variable density based on flow accumulation
-    pub fn extract_high_quality_streams(&self, threshold_percentile: f32) -> Vec<Vec<(usize, usize)>> {
+    /// Recompute flow accumulation of the given cells and everything downstream of them,
+    /// after `update_flow_directions` changed their routing. Cells upstream keep their
+    /// values, so the result is identical to a full `compute_flow_accumulation`.
+    /// Returns the recomputed cells.
+    pub fn update_flow_accumulation(&mut self, cells: &[usize]) -> Vec<usize> {
+        self.drop_analysis = OnceLock::new();
         let width = self.dem.width;
-        let height = self.dem.height;
-        
-        // Calculate max flow for threshold
-        let max_flow = self.flow_accumulation.iter()
-            .fold(0.0_f32, |max_val, &val| max_val.max(val));
-        
-        // Calculate threshold based on percentile
-        let threshold = max_flow * threshold_percentile;
-        
-        println!("Starting high-quality stream extraction with threshold {}", threshold);
+        let is_valid = |idx: usize| self.dem.get_elevation(idx % width, idx / width).is_some();
+        let mfd = self.flow_method == FlowMethod::MFD && self.mfd_flow_proportions.is_some();
+        let links = |idx: usize| -> Vec<usize> {
+            if mfd {
+                self.mfd_links(self.mfd_gradients(idx), idx).collect()
+            } else {
+                self.receivers(idx).map(|(downstream_idx, _)| downstream_idx).collect()
+            }
+        };
         
-        // Find all cells above threshold
-        let mut stream_cells = Vec::with_capacity(width * height / 100); // Estimate ~1% of cells
-        for y in 0..height {
-            for x in 0..width {
-                let idx = y * width + x;
-                if self.flow_accumulation[idx] >= threshold {
-                    stream_cells.push((x, y));
+        // Everything downstream of the changed cells, in discovery order, with its links
+        let mut affected: Vec<usize> = cells.iter().copied().filter(|&idx| is_valid(idx)).collect();
+        let mut position: HashMap<usize, usize> = affected.iter().enumerate().map(|(i, &idx)| (idx, i)).collect();
+        let mut downstream = Vec::new();
+        while downstream.len() < affected.len() {
+            let cell_links = links(affected[downstream.len()]);
+            for &downstream_idx in &cell_links {
+                if let Entry::Vacant(entry) = position.entry(downstream_idx) {
+                    entry.insert(affected.len());
+                    affected.push(downstream_idx);
                 }
             }
+            downstream.push(cell_links);
         }
         
-        println!("Found {} cells above threshold {}", stream_cells.len(), threshold);
-        
-        // Track visited cells to avoid duplicates
-        let mut visited = vec![false; width * height];
-        
-        // Generate stream polylines with adaptive sampling
-        let mut polylines = Vec::new();
-        
-        // Sort stream cells by flow accumulation (highest first)
-        // This ensures we start tracing from the most significant streams
-        stream_cells.sort_by(|&(x1, y1), &(x2, y2)| {
-            let flow1 = self.flow_accumulation[y1 * width + x1];
-            let flow2 = self.flow_accumulation[y2 * width + x2];
-            flow2.partial_cmp(&flow1).unwrap_or(std::cmp::Ordering::Equal)
-        });
-        
-        // Process cells in batches 
-        let cells_per_batch = 100;
-        let mut _total_polylines = 0;
-        let mut batch_start = 0;
-        
-        // Continue processing batches until we run out of cells
-        while batch_start < stream_cells.len() {
-            let batch_end = (batch_start + cells_per_batch).min(stream_cells.len());
-            let current_batch = &stream_cells[batch_start..batch_end];
-            
-            // Process this batch
-            for &(x, y) in current_batch {
-                let idx = y * width + x;
-                if visited[idx] {
-                    continue;
-                }
-                
-                // Start a new polyline from this cell
-                let mut polyline = Vec::new();
-                let mut current = (x, y);
-                
-                // Track flow accumulation for adaptive sampling
-                let mut last_flow = self.flow_accumulation[idx];
-                let mut _last_point_added = true;
-                let mut _distance_since_last_point = 0;
-                
-                // Trace downstream
-                loop {
-                    let (cx, cy) = current;
-                    let current_idx = cy * width + cx;
-                    
-
-                    // Mark as visited
-                    visited[current_idx] = true;
-                    
-                    // Get the current flow accumulation
-                    let current_flow = self.flow_accumulation[current_idx];
-                    
-                    // Decide whether to add this point based on:
-                    // 1. Flow accumulation change
-                    // 2. Distance since last point
-                    // 3. Always add first and junction points
-                    let flow_change_ratio = if last_flow > 0.0 { 
-                        (current_flow - last_flow).abs() / last_flow 
-                    } else { 
-                        1.0 
-                    };
-                    
-                    let _significant_flow_change = flow_change_ratio > 0.05; // 5% change
-                    let _enough_distance = _distance_since_last_point >= 2;  // Reduced from 3 to 2 cells
-                    
-                    // Always add points to ensure continuous flow lines
-                    polyline.push(current);
-                    last_flow = current_flow;
-                    _last_point_added = true;
-                    _distance_since_last_point = 0;
-                    
-                    // Find the downstream cell to continue tracing
-                    if let Some(downstream) = self.get_downstream_cell(cx, cy) {
-                        // Continue to downstream cell
-                        current = downstream;
-                        
-                        // Check if we'll go below threshold
-                        let downstream_idx = downstream.1 * width + downstream.0;
-                        if self.flow_accumulation[downstream_idx] < threshold {
-                            // Ensure the very last point *before* dropping below threshold is added
-                            polyline.push(downstream); // Add the point that is just below threshold
-                            break;
-                        }
-                    } else {
-                        // Ensure the final point (the outlet/sink itself) is added
-                        // The current point `(cx, cy)` IS the last point here.
-                        // Ensure it was added on the previous iteration or add it now.
-                        if !_last_point_added { // If the last loop iteration decided not to add it
-                            polyline.push(current);
-                        }
-                        break;
-                    }
-                }
-                
-
-                // Add the polyline if it has enough points
-                if polyline.len() >= 2 {
-                    polylines.push(polyline);
-                    _total_polylines += 1;
+        // Settle the affected cells in topological order; their other donors are final already
+        let mut indegree = vec![0u32; affected.len()];
+        for cell_links in &downstream {
+            for downstream_idx in cell_links {
+                indegree[position[downstream_idx]] += 1;
+            }
+        }
+        let mut queue: VecDeque<usize> = (0..affected.len()).filter(|&i| indegree[i] == 0).collect();
+        let contour_lengths = self.mfd_contour_lengths();
+        let mut settled_count = 0;
+        while let Some(i) = queue.pop_front() {
+            let idx = affected[i];
+            if mfd {
+                let proportions = self.mfd_flow_proportions.as_ref().unwrap();
+                let accumulation = self.mfd_inflow(idx, |upstream_idx| self.mfd_gradients(upstream_idx),
+                                                   |upstream_idx| (self.flow_accumulation[upstream_idx], proportions[upstream_idx]));
+                let cell_proportions = self.mfd_proportions(&self.mfd_gradients(idx), &contour_lengths, accumulation);
+                self.flow_accumulation[idx] = accumulation;
+                self.mfd_flow_proportions.as_mut().unwrap()[idx] = cell_proportions;
+            } else {
+                let mut total = 1.0;
+                for (upstream_idx, proportion) in self.donors(idx) {
+                    total += self.flow_accumulation[upstream_idx] * proportion;
                 }
+                self.flow_accumulation[idx] = total;
             }
+            settled_count += 1;
             
-            // Move to next batch
-            batch_start = batch_end;
+            for downstream_idx in &downstream[i] {
+                let j = position[downstream_idx];
+                indegree[j] -= 1;
+                if indegree[j] == 0 {
+                    queue.push_back(j);
+                }
+            }
         }
         
+        println!("Updated flow accumulation of {} cells", settled_count);
+        affected
+    }
+    
+    /// Extract a high-quality stream network with minimal downsampling.
+    /// Returns one polyline per link of the stream graph, so every stream cell appears
+    /// exactly once and links meet at shared confluence points.
+    pub fn extract_high_quality_streams(&self, threshold: StreamThreshold) -> Vec<Vec<(usize, usize)>> {
+        println!("Starting high-quality stream extraction with threshold {:?}", threshold);
+        
+        let polylines = self.build_stream_network(threshold).polylines();
+        
         #[cfg(target_arch = "wasm32")]
         web_sys::console::log_1(&format!("Generated {} high-quality stream polylines", polylines.len()).into());
         #[cfg(not(target_arch = "wasm32"))]
@@ -932,90 +1063,196 @@ impl FlowModel {
         polylines
     }
     
-    /// Extract a hierarchical stream network with multi-level detail
-    pub fn extract_hierarchical_streams(&self) -> Vec<(Vec<Vec<(usize, usize)>>, f32)> {
-        let _width = self.dem.width;
-        let _height = self.dem.height;
-        
-        // Find the maximum flow accumulation value
-        let max_flow = self.flow_accumulation.iter()
-            .fold(0.0_f32, |max, &val| max.max(val));
-        
-        // Define hierarchical thresholds - use only 2 levels for better performance
-        // Level 1: Major rivers (5% of max flow) - far fewer streams
-        // Level 2: Secondary streams (1% of max flow)
-        let thresholds = [
-            max_flow * 0.05,  // Level 1 - Only the largest main rivers (5%)
-            max_flow * 0.01,  // Level 2 - Significant tributaries (1%)
-        ];
-        
-        let mut hierarchical_streams = Vec::new();
-        
-        // Process each threshold level with a limit on stream count
-        for &threshold in &thresholds {
-            // Generate polylines with the current threshold
-            let mut polylines = self.extract_high_quality_streams(threshold / max_flow);
+    /// Extract high-quality streams with lake crossings output separately from rivers
+    pub fn extract_stream_segments(&self, threshold: StreamThreshold) -> StreamSegments {
+        let polylines = self.extract_high_quality_streams(threshold);
+        self.split_streams_at_lakes(polylines)
+    }
+    
+    /// Split stream polylines wherever they enter or leave a lake.
+    /// The transition cell is shared by both segments so the network stays connected.
+    pub fn split_streams_at_lakes(&self, polylines: Vec<Vec<(usize, usize)>>) -> StreamSegments {
+        let mut segments = StreamSegments { rivers: Vec::new(), lakes: Vec::new() };
+        
+        for polyline in polylines {
+            if polyline.is_empty() {
+                continue;
+            }
             
-            // Limit the number of polylines to avoid excessive memory usage
-            // Sort by average flow accumulation to keep the most important streams
-            polylines.sort_by(|a, b| {
-                // Calculate average flow for each polyline
-                let avg_flow_a = self.calculate_polyline_importance(&a);
-                let avg_flow_b = self.calculate_polyline_importance(&b);
-                
-                // Sort descending (highest flow first)
-                avg_flow_b.partial_cmp(&avg_flow_a).unwrap_or(std::cmp::Ordering::Equal)
-            });
+            let mut current = vec![polyline[0]];
+            let mut in_lake = self.is_lake_cell(polyline[0].0, polyline[0].1);
             
-            // Limit to a reasonable number of streams per level
-            let max_streams = 150; // Significantly reduced from potentially thousands
-            if polylines.len() > max_streams {
-                polylines.truncate(max_streams);
+            for &point in &polyline[1..] {
+                let point_in_lake = self.is_lake_cell(point.0, point.1);
+                current.push(point);
+                
+                if point_in_lake != in_lake {
+                    // Close the current segment and start the next one at the transition
+                    let finished = std::mem::replace(&mut current, vec![point]);
+                    if finished.len() >= 2 {
+                        if in_lake {
+                            segments.lakes.push(finished);
+                        } else {
+                            segments.rivers.push(finished);
+                        }
+                    }
+                    in_lake = point_in_lake;
+                }
             }
             
-            #[cfg(target_arch = "wasm32")]
-            web_sys::console::log_1(&format!("Level with threshold {}: kept {} streams (limited from original)", 
-                threshold, polylines.len()).into());
-            #[cfg(not(target_arch = "wasm32"))]
-            println!("Level with threshold {}: kept {} streams (limited from original)", 
-                threshold, polylines.len());
-            
-            // Store the polylines with their importance level (threshold)
-            hierarchical_streams.push((polylines, threshold));
+            if current.len() >= 2 {
+                if in_lake {
+                    segments.lakes.push(current);
+                } else {
+                    segments.rivers.push(current);
+                }
+            }
         }
         
-        #[cfg(target_arch = "wasm32")]
-        web_sys::console::log_1(&format!("Generated hierarchical streams with {} levels", hierarchical_streams.len()).into());
-        #[cfg(not(target_arch = "wasm32"))]
-        println!("Generated hierarchical streams with {} levels", hierarchical_streams.len());
-        hierarchical_streams
+        segments
     }
+}
 
-    // Helper to calculate importance of a polyline based on flow accumulation
-    fn calculate_polyline_importance(&self, polyline: &Vec<(usize, usize)>) -> f32 {
-        if polyline.is_empty() {
-            return 0.0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ramp;
+    
+    fn angle_difference(a: f32, b: f32) -> f32 {
+        let d = (a - b).rem_euclid(2.0 * std::f32::consts::PI);
+        d.min(2.0 * std::f32::consts::PI - d)
+    }
+    
+    #[test]
+    fn dinf_recovers_planar_ramp_angles() {
+        for degrees in [0.0_f32, 10.0, 37.5, 45.0, 80.0, 123.0, 181.0, 222.2, 270.0, 301.0, 359.0] {
+            let angle = degrees.to_radians();
+            let mut model = FlowModel::new(ramp(7, 7, 10.0, 10.0, angle, 0.2));
+            model.compute_flow_directions_dinf().unwrap();
+            
+            let center = 3 * 7 + 3;
+            let computed = model.dinf_flow.as_ref().unwrap().angles[center];
+            assert!(angle_difference(computed, angle) < 1e-3,
+                    "ramp at {}° gave {}°", degrees, computed.to_degrees());
+            assert!((model.slopes[center] - 0.2).abs() < 1e-3);
         }
-        
-        let width = self.dem.width;
-        let mut total_flow = 0.0;
-        
-        // Sample just a few points along the polyline to calculate importance
-        // This is faster than using every point for sorting
-        let num_samples = 3.min(polyline.len());
-        let step = polyline.len() / num_samples;
-        
-        for i in 0..num_samples {
-            let idx = i * step;
-            if idx < polyline.len() {
-                let (x, y) = polyline[idx];
-                let flow_idx = y * width + x;
-                if flow_idx < self.flow_accumulation.len() {
-                    total_flow += self.flow_accumulation[flow_idx];
-                }
-            }
+    }
+    
+    #[test]
+    fn dinf_is_rejected_under_d4() {
+        let mut model = FlowModel::with_connectivity(ramp(5, 5, 10.0, 10.0, 0.3, 0.1), Connectivity::D4);
+        assert!(model.compute_flow_directions_dinf().is_err());
+        assert!(model.dinf_flow.is_none());
+    }
+    
+    #[test]
+    fn dinf_recovers_angles_on_rectangular_cells() {
+        for degrees in [15.0_f32, 50.0, 140.0, 250.0, 330.0] {
+            let angle = degrees.to_radians();
+            let mut model = FlowModel::new(ramp(7, 7, 25.0, 30.0, angle, 0.1));
+            model.compute_flow_directions_dinf().unwrap();
+            
+            let computed = model.dinf_flow.as_ref().unwrap().angles[3 * 7 + 3];
+            assert!(angle_difference(computed, angle) < 1e-3,
+                    "ramp at {}° gave {}°", degrees, computed.to_degrees());
         }
+    }
+    
+    #[test]
+    fn weighted_accumulation_matches_area_and_applies_loss() {
+        let mut model = FlowModel::new(ramp(6, 4, 10.0, 10.0, 0.0, 0.1));
+        model.compute_flow_directions();
+        model.compute_flow_accumulation();
+        
+        let cell_count = 6 * 4;
+        let uniform = model.compute_weighted_accumulation(&vec![1.0; cell_count], None).unwrap();
+        assert_eq!(uniform, model.flow_accumulation);
         
-        total_flow / num_samples as f32
+        // Half of the flow is lost in every cell along an eastward row
+        let halved = model.compute_weighted_accumulation(&vec![1.0; cell_count], Some(&vec![0.5; cell_count])).unwrap();
+        assert!((halved[5] - (1.0 + 0.5 + 0.25 + 0.125 + 0.0625 + 0.03125)).abs() < 1e-6);
+        
+        assert!(model.compute_weighted_accumulation(&[1.0], None).is_err());
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn stream_thresholds_in_physical_units() {
+        assert_eq!(StreamThreshold::parse("1 km²"), Some(StreamThreshold::Area(1.0e6)));
+        assert_eq!(StreamThreshold::parse("50ha"), Some(StreamThreshold::Area(5.0e5)));
+        assert_eq!(StreamThreshold::parse("500 cells"), Some(StreamThreshold::Cells(500.0)));
+        assert_eq!(StreamThreshold::parse("5%"), Some(StreamThreshold::FractionOfMax(0.05)));
+        assert_eq!(StreamThreshold::parse("0.05"), Some(StreamThreshold::FractionOfMax(0.05)));
+        assert_eq!(StreamThreshold::parse("1e6 m2"), Some(StreamThreshold::Area(1.0e6)));
+        assert_eq!(StreamThreshold::parse("2.5e-1 km2"), Some(StreamThreshold::Area(2.5e5)));
+        assert_eq!(StreamThreshold::parse("1E3cells"), Some(StreamThreshold::Cells(1000.0)));
+        assert_eq!(StreamThreshold::parse("nan"), None);
+        assert_eq!(StreamThreshold::parse("1 acre"), None);
+        
+        // Eastward ramp on 20 m × 10 m cells: each row drains 6 cells of 200 m² into the edge
+        let mut model = FlowModel::new(ramp(6, 4, 20.0, 10.0, 0.0, 0.1));
+        model.compute_flow_directions();
+        model.compute_flow_accumulation();
+        assert_eq!(model.contributing_area_m2()[5], 1200.0);
+        assert_eq!(StreamThreshold::Area(800.0).accumulation_threshold(&model), 4.0);
+        
+        // Flow crosses the 10 m tall side of the cell
+        assert!((model.specific_catchment_area()[4] - 100.0).abs() < 1e-3);
+    }
+    
+    #[test]
+    fn dinf_proportions_follow_angle() {
+        let mut model = FlowModel::new(ramp(5, 5, 1.0, 1.0, 30.0_f32.to_radians(), 0.5));
+        model.compute_flow_directions_dinf().unwrap();
+        
+        let proportions = model.dinf_flow.as_ref().unwrap().proportions(2 * 5 + 2);
+        // 30° lies between east (0°) and southeast (45°)
+        assert_eq!((proportions[0].0, proportions[1].0), (0, 1));
+        assert!((proportions[0].1 - 1.0 / 3.0).abs() < 1e-4);
+        assert!((proportions[1].1 - 2.0 / 3.0).abs() < 1e-4);
+        assert!((proportions[0].1 + proportions[1].1 - 1.0).abs() < 1e-6);
+    }
+    
+    #[test]
+    fn mfd_contour_weighting_favours_cardinal_neighbors() {
+        // Cone whose gradient to all eight neighbors is 1, so only the contour lengths differ
+        let diagonal = 20.0 - 10.0 * std::f32::consts::SQRT_2;
+        let data = vec![diagonal, 10.0, diagonal, 10.0, 20.0, 10.0, diagonal, 10.0, diagonal];
+        let mut model = FlowModel::new(DigitalElevationModel::new(3, 3, 10.0, data));
+        let gradients = model.mfd_gradients(4);
+        assert!(gradients.iter().all(|g| (g - 1.0).abs() < 1e-5));
+        
+        let proportions = model.mfd_proportions(&gradients, &model.mfd_contour_lengths(), 1.0);
+        assert!(proportions.iter().all(|p| (p - 0.125).abs() < 1e-5));
+        
+        model.mfd_options.contour_weighting = true;
+        let proportions = model.mfd_proportions(&gradients, &model.mfd_contour_lengths(), 1.0);
+        let (cardinal, diagonal) = (0.5 / (4.0 * 0.5 + 4.0 * 0.354), 0.354 / (4.0 * 0.5 + 4.0 * 0.354));
+        for (dir, p) in proportions.iter().enumerate() {
+            let expected = if neighborhood::is_diagonal(dir) { diagonal } else { cardinal };
+            assert!((p - expected).abs() < 1e-5, "direction {} got {}", dir, p);
+        }
+    }
+    
+    #[test]
+    fn dinf_accumulation_conserves_mass() {
+        // Tilted, bumpy surface with a nodata hole, so most cells split their flow
+        let mut dem = ramp(16, 12, 10.0, 10.0, 20.0_f32.to_radians(), 0.05);
+        for (idx, elevation) in dem.data.iter_mut().enumerate() {
+            *elevation += ((idx % 16) as f32 * 0.7).sin() + ((idx / 16) as f32 * 1.1).cos();
+        }
+        dem.data[5 * 16 + 8] = f32::NAN;
+        let mut model = FlowModel::new(dem);
+        model.compute_flow_directions_dinf().unwrap();
+        model.compute_flow_accumulation();
+        
+        let dinf = model.dinf_flow.as_ref().unwrap();
+        let split_cells = (0..16 * 12).filter(|&idx| dinf.proportions(idx).iter().all(|&(_, p)| p > 0.05)).count();
+        assert!(split_cells > 50, "only {} cells split their flow", split_cells);
+        
+        let report = model.validate();
+        assert_eq!(report.valid_cell_count, 16 * 12 - 1);
+        assert_eq!(report.total_input, (16 * 12 - 1) as f64);
+        assert!(report.mass_balance_error < 1e-5, "{:?}", report.problems());
+        assert!(report.is_valid());
+    }
+}
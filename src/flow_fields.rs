@@ -0,0 +1,154 @@
+use crate::neighborhood::Neighborhood;
+
+/// Direction value used for "no receiver"
+pub const NO_DIRECTION: u8 = u8::MAX;
+
+/// Compact D∞ flow field in structure-of-arrays layout.
+///
+/// Every cell sends its flow to at most two neighbors (the cardinal and diagonal
+/// neighbor of its steepest facet), so instead of eight proportions per cell we keep
+/// the flow angle, two direction indices and the share sent to the first one:
+/// 10 bytes per cell instead of a heap-allocated `Vec<f32>`.
+#[derive(Debug, Clone)]
+pub struct DinfFlowField {
+    /// Flow angle in radians (0 = east, increasing clockwise on the map)
+    pub angles: Vec<f32>,
+    /// Direction index (0-7) of the first receiver, NO_DIRECTION if the cell has no outflow
+    pub receiver_a: Vec<u8>,
+    /// Direction index (0-7) of the second receiver, NO_DIRECTION if all flow goes to `receiver_a`
+    pub receiver_b: Vec<u8>,
+    /// Share of the cell's flow sent to `receiver_a`; the rest goes to `receiver_b`
+    pub fraction_a: Vec<f32>,
+}
+
+impl DinfFlowField {
+    pub fn new(cell_count: usize) -> Self {
+        DinfFlowField {
+            angles: vec![0.0; cell_count],
+            receiver_a: vec![NO_DIRECTION; cell_count],
+            receiver_b: vec![NO_DIRECTION; cell_count],
+            fraction_a: vec![0.0; cell_count],
+        }
+    }
+
+    /// Set a split between two receivers
+    pub fn set_split(&mut self, idx: usize, angle: f32, dir_a: usize, dir_b: usize, fraction_a: f32) {
+        self.angles[idx] = angle;
+        self.receiver_a[idx] = dir_a as u8;
+        self.receiver_b[idx] = dir_b as u8;
+        self.fraction_a[idx] = fraction_a;
+    }
+
+    /// Send all of a cell's flow to one neighbor (or nowhere)
+    pub fn set_single(&mut self, idx: usize, dir: Option<usize>) {
+        self.angles[idx] = dir.map_or(0.0, |d| d as f32 * std::f32::consts::FRAC_PI_4);
+        self.receiver_a[idx] = dir.map_or(NO_DIRECTION, |d| d as u8);
+        self.receiver_b[idx] = NO_DIRECTION;
+        self.fraction_a[idx] = if dir.is_some() { 1.0 } else { 0.0 };
+    }
+
+    /// Receiving directions and their shares: (direction index, proportion)
+    pub fn proportions(&self, idx: usize) -> [(u8, f32); 2] {
+        [
+            (self.receiver_a[idx], self.fraction_a[idx]),
+            (self.receiver_b[idx], 1.0 - self.fraction_a[idx]),
+        ]
+    }
+
+    /// Direction receiving the larger share of flow
+    pub fn dominant_direction(&self, idx: usize) -> Option<usize> {
+        let (a, b) = (self.receiver_a[idx], self.receiver_b[idx]);
+        if a == NO_DIRECTION {
+            None
+        } else if b != NO_DIRECTION && self.fraction_a[idx] < 0.5 {
+            Some(b as usize)
+        } else {
+            Some(a as usize)
+        }
+    }
+
+    /// Approximate heap size in bytes
+    pub fn memory_bytes(&self) -> usize {
+        self.angles.len() * (4 + 1 + 1 + 4)
+    }
+}
+
+/// MFD proportions: one fixed array of eight shares per cell (E..NE order)
+pub type MfdProportions = Vec<[f32; 8]>;
+
+/// Downstream receivers of one cell as (flat cell index, share of the cell's flow).
+///
+/// Shares of flow that leave the grid are not listed, so they may sum to less than one.
+#[derive(Debug, Clone, Copy)]
+pub struct Receivers {
+    items: [(usize, f32); 8],
+    len: usize,
+    pos: usize,
+}
+
+impl Receivers {
+    pub fn empty() -> Self {
+        Receivers { items: [(0, 0.0); 8], len: 0, pos: 0 }
+    }
+
+    /// Add the neighbor in direction `dir` if it exists and the share is positive
+    pub fn push_direction(&mut self, hood: &Neighborhood, idx: usize, dir: usize, proportion: f32) {
+        if proportion > 0.0 && dir < 8 {
+            if let Some(n_idx) = hood.neighbor_index(idx, dir) {
+                self.items[self.len] = (n_idx, proportion);
+                self.len += 1;
+            }
+        }
+    }
+}
+
+impl Iterator for Receivers {
+    type Item = (usize, f32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos < self.len {
+            self.pos += 1;
+            Some(self.items[self.pos - 1])
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neighborhood::Connectivity;
+
+    #[test]
+    fn dinf_field_round_trips_splits_and_single_receivers() {
+        let mut field = DinfFlowField::new(3);
+        field.set_split(0, 0.3, 0, 1, 0.75);
+        field.set_split(1, 2.0, 2, 3, 0.25);
+        field.set_single(2, Some(6));
+
+        assert_eq!(field.proportions(0), [(0, 0.75), (1, 0.25)]);
+        assert_eq!((field.angles[0], field.dominant_direction(0)), (0.3, Some(0)));
+        assert_eq!(field.dominant_direction(1), Some(3));
+        assert_eq!(field.proportions(2), [(6, 1.0), (NO_DIRECTION, 0.0)]);
+        assert_eq!(field.angles[2], 6.0 * std::f32::consts::FRAC_PI_4);
+        assert_eq!(field.memory_bytes(), 30);
+
+        field.set_single(1, None);
+        assert_eq!(field.proportions(1), [(NO_DIRECTION, 0.0), (NO_DIRECTION, 1.0)]);
+        assert_eq!(field.dominant_direction(1), None);
+    }
+
+    #[test]
+    fn receivers_skip_empty_shares_and_off_grid_neighbors() {
+        let hood = Neighborhood::new(3, 3, Connectivity::D8);
+        let mut receivers = Receivers::empty();
+        receivers.push_direction(&hood, 2, 0, 0.5); // East of the north-east corner leaves the grid
+        receivers.push_direction(&hood, 2, 2, 0.3);
+        receivers.push_direction(&hood, 2, 3, 0.0);
+        receivers.push_direction(&hood, 2, 4, 0.2);
+        receivers.push_direction(&hood, 2, NO_DIRECTION as usize, 1.0);
+        assert_eq!(receivers.collect::<Vec<_>>(), vec![(5, 0.3), (1, 0.2)]);
+        assert_eq!(Receivers::empty().count(), 0);
+    }
+}
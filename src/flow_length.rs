@@ -0,0 +1,228 @@
+use crate::flow::FlowModel;
+use crate::neighborhood::{Connectivity, Neighborhood};
+use crate::traversal::{self, PathWalker};
+use serde::{Serialize, Deserialize};
+
+/// Point of a longitudinal profile
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ProfilePoint {
+    /// Distance along the path from its upstream end in m
+    pub distance_m: f64,
+    pub elevation: f32,
+}
+
+/// Flow path along neighbouring cells, listed downstream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowPath {
+    /// Cells from the upstream end to the downstream end
+    pub cells: Vec<(usize, usize)>,
+    /// Cell centres in world coordinates
+    pub coordinates: Vec<(f64, f64)>,
+    /// Elevation along the path (nodata cells are skipped)
+    pub profile: Vec<ProfilePoint>,
+    /// Total length in m
+    pub length_m: f64,
+}
+
+impl FlowModel {
+    /// Distance in m from every cell along its flow path to the cell where the path ends
+    /// (DEM edge, sink or nodata). Nodata cells get 0.
+    ///
+    /// Paths follow the downstream cell (the dominant receiver for D∞ and MFD), with
+    /// cardinal and diagonal steps weighted by the cell size.
+    pub fn downstream_flow_length(&self) -> Vec<f32> {
+        let width = self.dem.width;
+        let cell_count = width * self.dem.height;
+
+        // Lengths filled with path compression, unwinding each walk from its end
+        let mut lengths = vec![0.0; cell_count];
+        let mut walker = PathWalker::new(cell_count);
+        let mut path = Vec::new();
+        for start in 0..cell_count {
+            if walker.is_resolved(start) || self.dem.get_elevation(start % width, start / width).is_none() {
+                continue;
+            }
+
+            let (mut below, mut length) = match walker.walk(start, |idx| self.downstream_index(idx), &mut path) {
+                Some(joined) => (joined, lengths[joined]),
+                None => (*path.last().unwrap(), 0.0),
+            };
+            while let Some(cell) = path.pop() {
+                if cell != below {
+                    length += self.step_length(cell, below);
+                }
+                lengths[cell] = length;
+                below = cell;
+            }
+        }
+
+        lengths.into_iter().map(|length| length as f32).collect()
+    }
+
+    /// Longest distance in m from the divide along the flow paths into every cell.
+    /// Cells without upstream cells (ridges, nodata) get 0.
+    ///
+    /// Uses the same single downstream paths as `downstream_flow_length`.
+    pub fn upstream_flow_length(&self) -> Vec<f32> {
+        self.upstream_lengths().into_iter().map(|length| length as f32).collect()
+    }
+
+    /// Longest flow path ending at `outlet`, traced upwards along the longest upstream
+    /// branch at every cell and returned from its upstream end to the outlet
+    pub fn longest_flow_path(&self, outlet: (usize, usize)) -> FlowPath {
+        let width = self.dem.width;
+        let cell_count = width * self.dem.height;
+        let upstream_lengths = self.upstream_lengths();
+
+        let longest_upstream = |idx: usize| {
+            self.upstream_indices(idx)
+                .map(|upstream_idx| (upstream_idx, upstream_lengths[upstream_idx] + self.step_length(upstream_idx, idx)))
+                .fold(None, |best: Option<(usize, f64)>, candidate| match best {
+                    Some(best) if best.1 >= candidate.1 => Some(best),
+                    _ => Some(candidate),
+                })
+                .map(|(upstream_idx, _)| upstream_idx)
+        };
+        let mut path = Vec::new();
+        PathWalker::new(cell_count).walk(outlet.1 * width + outlet.0, longest_upstream, &mut path);
+        path.reverse();
+
+        let flow_path = self.flow_path(&path);
+        println!("Longest flow path to ({}, {}): {} cells, {:.1} m", outlet.0, outlet.1, flow_path.cells.len(), flow_path.length_m);
+        flow_path
+    }
+
+    /// Georeferenced path with its elevation profile from a chain of neighbouring cell indices
+    pub fn flow_path(&self, path: &[usize]) -> FlowPath {
+        let width = self.dem.width;
+        let cells: Vec<(usize, usize)> = path.iter().map(|&idx| (idx % width, idx / width)).collect();
+        let coordinates = cells.iter().map(|&(x, y)| self.dem.cell_center(x, y)).collect();
+
+        let mut profile = Vec::with_capacity(cells.len());
+        let mut distance_m = 0.0;
+        for (i, &(x, y)) in cells.iter().enumerate() {
+            if i > 0 {
+                distance_m += self.step_length(path[i - 1], path[i]);
+            }
+            if let Some(elevation) = self.dem.get_elevation(x, y) {
+                profile.push(ProfilePoint { distance_m, elevation });
+            }
+        }
+
+        FlowPath { cells, coordinates, profile, length_m: distance_m }
+    }
+
+    /// Longest flow path of the watershed of a pour point (world coordinates), snapped
+    /// like `delineate_watershed`. None if the point lies outside the DEM.
+    pub fn longest_flow_path_at(&self, pour_point: (f64, f64), snap_radius: f64) -> Option<FlowPath> {
+        let (x, y) = self.dem.cell_at(pour_point.0, pour_point.1)?;
+        Some(self.longest_flow_path(self.snap_to_stream(x, y, snap_radius)))
+    }
+
+    /// Upstream flow lengths in full precision, settled upstream-first
+    fn upstream_lengths(&self) -> Vec<f64> {
+        let width = self.dem.width;
+        let (lengths, _) = traversal::settle_topological(
+            width * self.dem.height,
+            |_| true,
+            |idx| self.downstream_index(idx).into_iter(),
+            |idx, lengths: &[f64]| {
+                self.upstream_indices(idx)
+                    .map(|upstream_idx| lengths[upstream_idx] + self.step_length(upstream_idx, idx))
+                    .fold(0.0, f64::max)
+            },
+        );
+        lengths
+    }
+
+    /// Index of the downstream cell of `idx`
+    pub fn downstream_index(&self, idx: usize) -> Option<usize> {
+        let width = self.dem.width;
+        self.get_downstream_cell(idx % width, idx / width).map(|(x, y)| y * width + x)
+    }
+
+    /// Neighbours whose downstream cell is `idx`
+    pub fn upstream_indices(&self, idx: usize) -> impl Iterator<Item = usize> + '_ {
+        let hood = Neighborhood::new(self.dem.width, self.dem.height, Connectivity::D8);
+        let width = self.dem.width;
+        hood.neighbors(idx % width, idx / width)
+            .map(move |(_, (x, y))| y * width + x)
+            .filter(move |&upstream_idx| self.downstream_index(upstream_idx) == Some(idx))
+    }
+
+    /// Distance in m between the centres of two neighbouring cells
+    pub fn step_length(&self, from: usize, to: usize) -> f64 {
+        let width = self.dem.width;
+        let dx = (to % width) as f64 - (from % width) as f64;
+        let dy = (to / width) as f64 - (from / width) as f64;
+        (dx * self.dem.x_resolution).hypot(dy * self.dem.y_resolution)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dem::DigitalElevationModel;
+    use crate::flow::FlowMethod;
+    use crate::test_support::{cyclic_pair, ramp, routed};
+
+    #[test]
+    fn flow_lengths_and_longest_path_use_diagonal_steps() {
+        // Southeast ramp: every interior cell drains diagonally towards (3, 3)
+        let model = routed(ramp(4, 4, 10.0, 10.0, 45.0_f32.to_radians(), 0.1), FlowMethod::D8);
+        let diagonal = 200.0_f32.sqrt();
+
+        let downstream = model.downstream_flow_length();
+        assert!((downstream[0] - 3.0 * diagonal).abs() < 1e-3);
+        assert_eq!(downstream[15], 0.0);
+
+        let upstream = model.upstream_flow_length();
+        assert_eq!(upstream[0], 0.0);
+        assert!((upstream[15] - 3.0 * diagonal).abs() < 1e-3);
+
+        let path = model.longest_flow_path((3, 3));
+        assert_eq!(path.cells, vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+        assert_eq!(path.profile.len(), 4);
+        assert!((path.length_m - 3.0 * diagonal as f64).abs() < 1e-3);
+        assert!(path.profile[0].elevation > path.profile[3].elevation);
+        assert_eq!(path.coordinates[0], (5.0, 5.0));
+        assert_eq!(path.coordinates[3], (35.0, 35.0));
+    }
+
+    #[test]
+    fn paths_end_at_nodata_and_ridges() {
+        // Eastward row with a nodata cell in the middle
+        let mut dem = ramp(6, 1, 10.0, 10.0, 0.0, 0.1);
+        dem.data[3] = f32::NAN;
+        let model = routed(dem, FlowMethod::D8);
+        let downstream = model.downstream_flow_length();
+        assert_eq!(downstream[3], 0.0);
+        assert_eq!((downstream[4], downstream[5]), (10.0, 0.0));
+        assert!(downstream[..3].iter().all(|length| length.is_finite()));
+
+        // Nothing drains into the western end, so its longest path is the cell itself
+        let path = model.longest_flow_path((0, 0));
+        assert_eq!((path.cells.clone(), path.length_m), (vec![(0, 0)], 0.0));
+        assert_eq!(model.upstream_flow_length()[0], 0.0);
+
+        // Pour points off the grid have no watershed
+        assert!(model.longest_flow_path_at((-5.0, 5.0), 0.0).is_none());
+        assert_eq!(model.longest_flow_path_at((55.0, 5.0), 0.0).unwrap().cells, vec![(4, 0), (5, 0)]);
+    }
+
+    #[test]
+    fn step_lengths_follow_rectangular_cells() {
+        let model = FlowModel::new(DigitalElevationModel::with_cell_size(3, 3, 20.0, 10.0, vec![1.0; 9]));
+        assert_eq!(model.step_length(4, 5), 20.0);
+        assert_eq!(model.step_length(4, 7), 10.0);
+        assert_eq!(model.step_length(4, 8), 20.0_f64.hypot(10.0));
+    }
+
+    #[test]
+    fn flow_lengths_stop_on_cyclic_directions() {
+        // Each cycle is cut where the walk closes it
+        let model = cyclic_pair();
+        assert_eq!(model.downstream_flow_length(), vec![10.0, 0.0]);
+        assert_eq!(model.longest_flow_path((0, 0)).cells, vec![(1, 0), (0, 0)]);
+    }
+}
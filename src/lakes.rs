@@ -0,0 +1,450 @@
+use crate::dem::DigitalElevationModel;
+use crate::neighborhood::{self, Connectivity, Neighborhood};
+use crate::polygon::Polygon;
+use serde::{Serialize, Deserialize};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::cmp::Ordering;
+
+/// Lake id used for cells that are not part of any lake
+pub const NO_LAKE: u32 = 0;
+
+/// A rasterized, flattened lake
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lake {
+    /// 1-based lake id as stored in `FlowModel::lake_ids`
+    pub id: u32,
+    /// Flattened water surface elevation (minimum shoreline value)
+    pub surface_elevation: f32,
+    /// Number of DEM cells covered by the lake
+    pub cell_count: usize,
+    /// Lake cell through which all water leaves the lake (None until routed). For a lake
+    /// split into disconnected parts this is the outlet of the largest part.
+    pub outlet: Option<(usize, usize)>,
+    /// Outlets of the other parts: lake cells that are not connected to the largest part
+    /// under the model's connectivity drain through their own outlet
+    #[serde(default)]
+    pub detached_outlets: Vec<(usize, usize)>,
+}
+
+impl DigitalElevationModel {
+    /// Rasterize lake polygons onto the grid using cell centers.
+    /// Returns one lake id per cell (`NO_LAKE` outside lakes); later polygons win on overlap.
+    pub fn rasterize_lakes(&self, polygons: &[Polygon]) -> Vec<u32> {
+        let width = self.width;
+        let height = self.height;
+        let gt = self.geo_transform;
+        let mut lake_ids = vec![NO_LAKE; width * height];
+
+        for (i, polygon) in polygons.iter().enumerate() {
+            let id = i as u32 + 1;
+            let (minx, miny, maxx, maxy) = polygon.bounds();
+
+            // Convert the bounding box to a grid window (works for either sign of the pixel size)
+            let col_a = (minx - gt[0]) / gt[1];
+            let col_b = (maxx - gt[0]) / gt[1];
+            let row_a = (miny - gt[3]) / gt[5];
+            let row_b = (maxy - gt[3]) / gt[5];
+            let x0 = col_a.min(col_b).floor().max(0.0) as usize;
+            let x1 = (col_a.max(col_b).ceil().max(0.0) as usize).min(width);
+            let y0 = row_a.min(row_b).floor().max(0.0) as usize;
+            let y1 = (row_a.max(row_b).ceil().max(0.0) as usize).min(height);
+
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    if self.get_elevation(x, y).is_none() {
+                        continue;
+                    }
+
+                    let cx = gt[0] + (x as f64 + 0.5) * gt[1];
+                    let cy = gt[3] + (y as f64 + 0.5) * gt[5];
+                    if polygon.contains(cx, cy) {
+                        lake_ids[y * width + x] = id;
+                    }
+                }
+            }
+        }
+
+        lake_ids
+    }
+
+    /// Flatten every lake to the lowest elevation found along its shoreline.
+    /// Shoreline cells are lake cells that touch a non-lake cell or the DEM edge.
+    pub fn flatten_lakes(&mut self, lake_ids: &[u32], lake_count: usize) -> Vec<Lake> {
+        println!("Flattening {} lakes...", lake_count);
+
+        let width = self.width;
+        let height = self.height;
+        let mut shoreline_min = vec![f32::INFINITY; lake_count];
+        let mut cell_counts = vec![0usize; lake_count];
+
+        for y in 0..height {
+            for x in 0..width {
+                let id = lake_ids[y * width + x];
+                if id == NO_LAKE {
+                    continue;
+                }
+
+                let lake = (id - 1) as usize;
+                cell_counts[lake] += 1;
+
+                let is_shore = x == 0 || y == 0 || x == width - 1 || y == height - 1 ||
+                    self.lake_neighbors(x, y).any(|(nx, ny)| lake_ids[ny * width + nx] != id);
+                if is_shore {
+                    if let Some(elev) = self.get_elevation(x, y) {
+                        shoreline_min[lake] = shoreline_min[lake].min(elev);
+                    }
+                }
+            }
+        }
+
+        // Apply the flat surface
+        for (idx, &id) in lake_ids.iter().enumerate() {
+            if id != NO_LAKE && shoreline_min[(id - 1) as usize].is_finite() {
+                self.data[idx] = shoreline_min[(id - 1) as usize];
+            }
+        }
+
+        (0..lake_count)
+            .map(|i| Lake {
+                id: i as u32 + 1,
+                surface_elevation: shoreline_min[i],
+                cell_count: cell_counts[i],
+                outlet: None,
+                detached_outlets: Vec::new(),
+            })
+            .collect()
+    }
+
+    /// All in-grid neighbours of a cell, including ones without elevation data
+    fn lake_neighbors(&self, x: usize, y: usize) -> impl Iterator<Item = (usize, usize)> {
+        Neighborhood::new(self.width, self.height, Connectivity::D8)
+            .neighbors(x, y)
+            .map(|(_, n)| n)
+    }
+}
+
+/// Cell indices of every lake 1..=lake_count, collected in one pass over the grid.
+/// Entry `i` holds the cells of lake `i + 1`.
+pub fn lake_cells(lake_ids: &[u32], lake_count: usize) -> Vec<Vec<usize>> {
+    let mut cells = vec![Vec::new(); lake_count];
+    for (idx, &id) in lake_ids.iter().enumerate() {
+        if id != NO_LAKE && id as usize <= lake_count {
+            cells[id as usize - 1].push(idx);
+        }
+    }
+    cells
+}
+
+/// Outlet of every connected part of a lake (largest part first) and, for every lake cell,
+/// the direction it drains to
+pub type LakeRouting = (Vec<(usize, usize)>, Vec<(usize, Option<usize>)>);
+
+/// Route flow through one flattened lake along its centerline.
+///
+/// Returns the outlet cell of every part of the lake that is connected under `connectivity`
+/// (largest part first) and, for every lake cell, the index (0-7, E..NE order) of the
+/// neighbour it drains to. Each outlet drains to the lowest neighbouring land cell, or gets
+/// `None` when its part leaves the grid or has no outflow at all.
+/// Only directions allowed by `connectivity` are used.
+///
+/// `cells` are the lake's cells (see `lake_cells`); all working storage is sized to them,
+/// so routing many small lakes does not scale with the grid.
+pub fn route_lake(
+    dem: &DigitalElevationModel,
+    lake_ids: &[u32],
+    lake: &Lake,
+    cells: &[usize],
+    connectivity: Connectivity,
+) -> LakeRouting {
+    let width = dem.width;
+    let height = dem.height;
+    let id = lake.id;
+    let hood = Neighborhood::new(width, height, connectivity);
+
+    // Distance from the shore (in cells) for every lake cell; the centerline is its ridge
+    let mut shore_distance: HashMap<usize, u32> = HashMap::with_capacity(cells.len());
+    let mut queue = VecDeque::new();
+    for &idx in cells {
+        let (x, y) = (idx % width, idx / width);
+        let on_edge = hood.is_edge(x, y);
+        if on_edge || dem.lake_neighbors(x, y).any(|(nx, ny)| lake_ids[ny * width + nx] != id) {
+            shore_distance.insert(idx, 0);
+            queue.push_back(idx);
+        }
+    }
+    while let Some(idx) = queue.pop_front() {
+        let (x, y) = (idx % width, idx / width);
+        let distance = shore_distance[&idx] + 1;
+        for (nx, ny) in dem.lake_neighbors(x, y) {
+            let n_idx = ny * width + nx;
+            if lake_ids[n_idx] == id && !shore_distance.contains_key(&n_idx) {
+                shore_distance.insert(n_idx, distance);
+                queue.push_back(n_idx);
+            }
+        }
+    }
+
+    // Split the lake into the parts water can cross under the active connectivity
+    let mut assigned: HashSet<usize> = HashSet::with_capacity(cells.len());
+    let mut parts: Vec<Vec<usize>> = Vec::new();
+    for &start in cells {
+        if !assigned.insert(start) {
+            continue;
+        }
+        let mut part = vec![start];
+        let mut next = 0;
+        while next < part.len() {
+            let (x, y) = (part[next] % width, part[next] / width);
+            next += 1;
+            for (_, (nx, ny)) in hood.neighbors(x, y) {
+                let n_idx = ny * width + nx;
+                if lake_ids[n_idx] == id && assigned.insert(n_idx) {
+                    part.push(n_idx);
+                }
+            }
+        }
+        parts.push(part);
+    }
+    parts.sort_by_key(|part| std::cmp::Reverse(part.len()));
+
+    // Parts are disjoint, so their searches can share the cost and receiver maps
+    let mut cost: HashMap<usize, f32> = HashMap::with_capacity(cells.len());
+    let mut receiver: HashMap<usize, usize> = HashMap::with_capacity(cells.len());
+    let mut outlets = Vec::with_capacity(parts.len());
+    let mut routes = Vec::with_capacity(cells.len());
+    for part in &parts {
+        let (outlet_idx, outlet_dir) = pick_outlet(dem, lake_ids, lake, hood, part, &shore_distance);
+        outlets.push((outlet_idx % width, outlet_idx / width));
+
+        // Dijkstra from the outlet through the part. Steps are cheap far from the shore,
+        // so the resulting drainage tree follows the lake centerline.
+        let mut heap = BinaryHeap::new();
+        cost.insert(outlet_idx, 0.0);
+        heap.push(CostItem { cost: 0.0, idx: outlet_idx });
+
+        while let Some(CostItem { cost: c, idx }) = heap.pop() {
+            if c > cost[&idx] {
+                continue;
+            }
+            let (x, y) = (idx % width, idx / width);
+            for (dir, (nx, ny)) in hood.neighbors(x, y) {
+                let n_idx = ny * width + nx;
+                if lake_ids[n_idx] != id {
+                    continue;
+                }
+
+                let step = dem.neighbor_distance(dir) as f32;
+                let new_cost = c + step / (1.0 + shore_distance[&n_idx] as f32);
+                if cost.get(&n_idx).is_none_or(|&old| new_cost < old) {
+                    cost.insert(n_idx, new_cost);
+                    // The neighbour drains back towards us
+                    receiver.insert(n_idx, neighborhood::opposite(dir));
+                    heap.push(CostItem { cost: new_cost, idx: n_idx });
+                }
+            }
+        }
+
+        routes.extend(part.iter()
+            .map(|&idx| if idx == outlet_idx { (idx, outlet_dir) } else { (idx, receiver.get(&idx).copied()) }));
+    }
+
+    if parts.len() > 1 {
+        println!("Lake {} has {} disconnected parts, each routed to its own outlet", id, parts.len());
+    }
+
+    (outlets, routes)
+}
+
+/// Pick the outlet of one connected part of a lake: the lake cell next to the lowest land
+/// cell at or below the lake surface that does not drain straight back into the lake.
+/// Parts touching the DEM edge spill off-grid. Returns the outlet cell and its direction.
+fn pick_outlet(
+    dem: &DigitalElevationModel,
+    lake_ids: &[u32],
+    lake: &Lake,
+    hood: Neighborhood,
+    part: &[usize],
+    shore_distance: &HashMap<usize, u32>,
+) -> (usize, Option<usize>) {
+    let width = dem.width;
+    let id = lake.id;
+    let mut best_exit: Option<(usize, usize, f32)> = None; // (lake cell, direction, neighbour elevation)
+    let mut edge_outlet = None;
+    for &idx in part {
+        let (x, y) = (idx % width, idx / width);
+        if edge_outlet.is_none() && hood.is_edge(x, y) {
+            edge_outlet = Some(idx);
+        }
+        for (dir, (nx, ny)) in hood.neighbors(x, y) {
+            let n_idx = ny * width + nx;
+            if lake_ids[n_idx] == id {
+                continue;
+            }
+            if let Some(n_elev) = dem.get_elevation(nx, ny) {
+                if n_elev > lake.surface_elevation {
+                    continue;
+                }
+                // Equal-elevation exits are only usable if they have somewhere else to go
+                if n_elev == lake.surface_elevation && !has_lower_exit(dem, lake_ids, id, nx, ny, hood) {
+                    continue;
+                }
+                if best_exit.is_none_or(|(_, _, e)| n_elev < e) {
+                    best_exit = Some((idx, dir, n_elev));
+                }
+            }
+        }
+    }
+
+    // Outlet preference: a strictly lower land cell, then the DEM edge, then an equal land cell,
+    // and finally the deepest point of a closed (endorheic) part.
+    match (best_exit, edge_outlet) {
+        (Some((idx, dir, e)), _) if e < lake.surface_elevation => (idx, Some(dir)),
+        (_, Some(idx)) => (idx, None),
+        (Some((idx, dir, _)), None) => (idx, Some(dir)),
+        (None, None) => {
+            let deepest = *part.iter().max_by_key(|idx| shore_distance[idx]).unwrap();
+            (deepest, None)
+        }
+    }
+}
+
+/// Whether a land cell at the lake surface elevation can spill to a cell that is lower
+/// and not part of the same lake
+fn has_lower_exit(
+    dem: &DigitalElevationModel,
+    lake_ids: &[u32],
+    id: u32,
+    x: usize,
+    y: usize,
+    hood: Neighborhood,
+) -> bool {
+    let elev = match dem.get_elevation(x, y) {
+        Some(e) => e,
+        None => return false,
+    };
+    if hood.is_edge(x, y) {
+        return true;
+    }
+    hood.neighbors(x, y).any(|(_, (nx, ny))| {
+        lake_ids[ny * dem.width + nx] != id &&
+            dem.get_elevation(nx, ny).is_some_and(|n| n < elev)
+    })
+}
+
+/// Min-heap entry for the lake routing Dijkstra
+#[derive(Debug, Clone, Copy)]
+struct CostItem {
+    cost: f32,
+    idx: usize,
+}
+
+impl Eq for CostItem {}
+
+impl PartialEq for CostItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost == other.cost
+    }
+}
+
+impl PartialOrd for CostItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CostItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed so BinaryHeap pops the cheapest item first
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::FlowModel;
+    use crate::neighborhood::OFFSETS;
+
+    /// Follow the lake routes from `idx` to the cell without a route or the first cell off the lake
+    fn follow(routes: &[(usize, Option<usize>)], width: usize, mut idx: usize) -> usize {
+        for _ in 0..routes.len() {
+            let Some(&(_, Some(dir))) = routes.iter().find(|&&(cell, _)| cell == idx) else {
+                return idx;
+            };
+            let (dx, dy) = OFFSETS[dir];
+            idx = ((idx / width) as isize + dy) as usize * width + ((idx % width) as isize + dx) as usize;
+        }
+        panic!("lake routes contain a cycle");
+    }
+
+    #[test]
+    fn every_part_of_a_split_lake_drains_to_its_own_outlet() {
+        // Terrain falling 1 m per column to the east; a land bar at column 5 cuts the lake in two
+        let data = (0..12 * 6).map(|idx| 20.0 - (idx % 12) as f32).collect();
+        let mut model = FlowModel::new(DigitalElevationModel::new(12, 6, 1.0, data));
+        model.set_lakes(&[Polygon {
+            exterior: vec![(2.0, 1.0), (10.0, 1.0), (10.0, 4.0), (2.0, 4.0)],
+            holes: vec![vec![(4.8, 0.5), (6.2, 0.5), (6.2, 4.5), (4.8, 4.5)]],
+        }]);
+        model.compute_flow_directions();
+
+        let lake = &model.lakes[0];
+        assert_eq!((lake.cell_count, lake.surface_elevation), (21, 11.0));
+        assert_eq!(lake.outlet.map(|(x, _)| x), Some(9));
+        assert_eq!(lake.detached_outlets.len(), 1);
+
+        // The eastern part spills over the lower land east of it; the closed western part
+        // collects in its own outlet instead of leaving unrouted sinks behind
+        let (wx, wy) = lake.detached_outlets[0];
+        assert!((2..5).contains(&wx) && (1..4).contains(&wy));
+        for y in 1..4 {
+            for x in (2..5).chain(6..10) {
+                let mut idx = y * 12 + x;
+                while let Some(next) = model.downstream_index(idx).filter(|&next| model.lake_ids[next] != NO_LAKE) {
+                    idx = next;
+                }
+                if x < 5 {
+                    assert_eq!(idx, wy * 12 + wx, "cell ({}, {})", x, y);
+                    assert_eq!(model.downstream_index(idx), None);
+                } else {
+                    assert_eq!(Some(idx % 12), lake.outlet.map(|(x, _)| x), "cell ({}, {})", x, y);
+                    assert_eq!(model.downstream_index(idx).map(|d| d % 12), Some(10));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn diagonal_lake_cells_are_separate_parts_under_d4() {
+        // Terrain rising to the south-east; two 2x2 lake blocks touch only at a corner
+        let data = (0..6 * 6).map(|idx| 10.0 + (idx % 6 + idx / 6) as f32).collect();
+        let mut dem = DigitalElevationModel::new(6, 6, 1.0, data);
+        let mut lake_ids = vec![NO_LAKE; 36];
+        for (x, y) in [(1, 1), (2, 1), (1, 2), (2, 2), (3, 3), (4, 3), (3, 4), (4, 4)] {
+            lake_ids[y * 6 + x] = 1;
+        }
+        let lake = dem.flatten_lakes(&lake_ids, 1).remove(0);
+
+        let cells = lake_cells(&lake_ids, 1).remove(0);
+        let (outlets, routes) = route_lake(&dem, &lake_ids, &lake, &cells, Connectivity::D8);
+        assert_eq!(outlets, vec![(1, 1)]);
+        assert_eq!(routes.len(), 8);
+        assert!(routes.iter().all(|&(idx, _)| follow(&routes, 6, idx) == 0));
+
+        // Under D4 the south-eastern block is closed off and gets its own (sink) outlet
+        let (outlets, routes) = route_lake(&dem, &lake_ids, &lake, &cells, Connectivity::D4);
+        assert_eq!(outlets.len(), 2);
+        assert_eq!(routes.len(), 8);
+        assert!(routes.iter().all(|&(_, dir)| dir.is_none_or(|dir| dir % 2 == 0)));
+        let (sx, sy) = outlets[1];
+        assert!((3..5).contains(&sx) && (3..5).contains(&sy));
+        for &(idx, _) in &routes {
+            let end = follow(&routes, 6, idx);
+            if idx % 6 >= 3 {
+                assert_eq!(end, sy * 6 + sx);
+            } else {
+                assert!(end == 1 || end == 6, "D4 exit of the north-western block");
+            }
+        }
+    }
+}
@@ -1,9 +1,31 @@
 use wasm_bindgen::prelude::*;
 use web_sys::console;
 use std::panic;
+mod aggregation;
+mod basins;
+mod channel_initiation;
+mod contamination;
 mod dem;
+mod direction_encoding;
 mod flow;
+mod flow_fields;
+mod flow_length;
+mod lakes;
+mod morphometry;
+mod neighborhood;
+mod polygon;
+mod stream_network;
+mod stream_order;
+mod subcatchments;
+mod terrain_edit;
+mod trace;
+mod traversal;
+mod validation;
+mod velocity;
 mod visualization;
+mod watershed;
+#[cfg(test)]
+mod test_support;
 pub mod precompute;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global allocator.
@@ -30,6 +52,68 @@ pub fn start() {
     console::log_1(&"Module has static debug_streams function and method test() for diagnostics".into());
 }
 
+// Read a stream threshold from JS: a number is a fraction of the maximum accumulation,
+// a string may carry a unit ("1 km²", "50 ha", "500 cells") or name another stream
+// initiation criterion ("slope-area 200 2", "curvature 20", "constant-drop")
+fn parse_stream_threshold(threshold: &JsValue) -> Result<flow::StreamThreshold, JsValue> {
+    if let Some(fraction) = threshold.as_f64() {
+        Ok(flow::StreamThreshold::FractionOfMax(fraction as f32))
+    } else if let Some(text) = threshold.as_string() {
+        flow::StreamThreshold::parse(&text)
+            .ok_or_else(|| JsValue::from_str(&format!("Invalid stream threshold: {}", text)))
+    } else {
+        Err(JsValue::from_str("Stream threshold must be a number or a string"))
+    }
+}
+
+// Read Manning's n from JS: a number for uniform roughness, an array with one value per cell,
+// or { classes: [class per cell], table: [[class, n], ...], default_n } for a land-cover lookup
+fn parse_roughness(roughness: JsValue) -> Result<velocity::Roughness, JsValue> {
+    if let Some(n) = roughness.as_f64() {
+        return Ok(velocity::Roughness::Uniform(n as f32));
+    }
+    if roughness.is_array() {
+        return Ok(velocity::Roughness::PerCell(serde_wasm_bindgen::from_value(roughness)?));
+    }
+    
+    #[derive(serde::Deserialize)]
+    struct LandCover {
+        classes: Vec<u16>,
+        table: Vec<(u16, f32)>,
+        default_n: f32,
+    }
+    let land_cover: LandCover = serde_wasm_bindgen::from_value(roughness)?;
+    Ok(velocity::Roughness::LandCover {
+        classes: land_cover.classes,
+        table: land_cover.table.into_iter().collect(),
+        default_n: land_cover.default_n,
+    })
+}
+
+// Read an elevation edit operation: "set", "raise" or "lower" by `value`
+fn parse_edit_operation(operation: &str, value: f32) -> Result<terrain_edit::EditOperation, JsValue> {
+    match operation {
+        "set" => Ok(terrain_edit::EditOperation::Set(value)),
+        "raise" => Ok(terrain_edit::EditOperation::Raise(value)),
+        "lower" => Ok(terrain_edit::EditOperation::Raise(-value)),
+        _ => Err(JsValue::from_str(&format!("Unknown edit operation: {}", operation))),
+    }
+}
+
+// Read a raster reducer: "sum", "mean", "min", "max" or "fraction <class>"
+fn parse_reducer(reducer: &str) -> Result<aggregation::Reducer, JsValue> {
+    aggregation::Reducer::parse(reducer)
+        .ok_or_else(|| JsValue::from_str(&format!("Unknown reducer: {}", reducer)))
+}
+
+// Morphometry table as CSV text (format "csv") or a JSON array of rows
+fn format_morphometry(table: &morphometry::MorphometryTable, format: &str) -> String {
+    match format {
+        "csv" => table.to_csv(),
+        _ => table.to_json().to_string(),
+    }
+}
+
 #[wasm_bindgen]
 pub struct WaterModel {
     dem: Option<dem::DigitalElevationModel>,
@@ -37,6 +121,11 @@ pub struct WaterModel {
     width: usize,
     height: usize,
     resolution: f64,
+    lake_polygons: Vec<polygon::Polygon>,
+    connectivity: neighborhood::Connectivity,
+    flow_method: flow::FlowMethod,
+    mfd_options: flow::MfdOptions,
+    terrain_editor: Option<terrain_edit::TerrainEditor>,
 }
 
 #[wasm_bindgen]
@@ -50,9 +139,22 @@ impl WaterModel {
             width: 0,
             height: 0,
             resolution: 0.0,
+            lake_polygons: Vec::new(),
+            connectivity: neighborhood::Connectivity::D8,
+            flow_method: flow::FlowMethod::D8,
+            mfd_options: flow::MfdOptions::default(),
+            terrain_editor: None,
         }
     }
     
+    // Select D4 ("d4") or D8 ("d8") neighborhood connectivity.
+    // Call before process_dem_data so sink treatment uses the same neighborhood.
+    #[wasm_bindgen]
+    pub fn set_connectivity(&mut self, connectivity: &str) {
+        self.connectivity = neighborhood::Connectivity::from_name(connectivity);
+        console::log_1(&format!("Using {:?} connectivity", self.connectivity).into());
+    }
+    
     // Process a DEM from raw data
     #[wasm_bindgen]
     pub fn process_dem_data(&mut self, 
@@ -77,8 +179,11 @@ impl WaterModel {
             _ => dem::SinkTreatmentMethod::CompletelyFill
         };
         
+        // Keep the raw elevations so later edits can be applied incrementally
+        self.terrain_editor = Some(terrain_edit::TerrainEditor::new(dem.clone(), method, self.connectivity));
+        
         console::log_1(&"Processing sinks in DEM...".into());
-        dem.process_sinks(method);
+        dem.process_sinks_with_connectivity(method, self.connectivity);
         
         // Save the dimensions for easy access
         self.width = width;
@@ -91,6 +196,63 @@ impl WaterModel {
         Ok(())
     }
     
+    // Set lake polygons (array of { exterior: [[x, y], ...], holes: [...] } in world coordinates)
+    // used to flatten water bodies on the next compute_flow call
+    #[wasm_bindgen]
+    pub fn set_lake_polygons(&mut self, polygons: JsValue) -> Result<(), JsValue> {
+        let polygons: Vec<polygon::Polygon> = serde_wasm_bindgen::from_value(polygons)?;
+        console::log_1(&format!("Received {} lake polygons", polygons.len()).into());
+        self.lake_polygons = polygons;
+        Ok(())
+    }
+    
+    // Set separate cell width/height for DEMs with rectangular pixels (e.g. 25 m × 30 m).
+    // Applies to the loaded DEM; call after process_dem_data and before compute_flow.
+    #[wasm_bindgen]
+    pub fn set_cell_size(&mut self, x_resolution: f64, y_resolution: f64) -> Result<(), JsValue> {
+        if let Some(dem) = &mut self.dem {
+            dem.set_cell_size(x_resolution, y_resolution);
+            self.resolution = dem.resolution;
+            if let Some(editor) = &mut self.terrain_editor {
+                editor.set_cell_size(x_resolution, y_resolution);
+            }
+            console::log_1(&format!("Cell size set to {} x {}", x_resolution, y_resolution).into());
+            Ok(())
+        } else {
+            Err(JsValue::from_str("No DEM loaded"))
+        }
+    }
+    
+    // Select the flow routing method used by compute_flow: "d8", "dinf" or "mfd"
+    #[wasm_bindgen]
+    pub fn set_flow_method(&mut self, method: &str) {
+        self.flow_method = flow::FlowMethod::from_name(method);
+        console::log_1(&format!("Using {:?} flow routing", self.flow_method).into());
+    }
+    
+    // Configure MFD routing.
+    // exponent_mode: "fixed" (uses `exponent`) or "qin2007" (slope-adaptive exponent).
+    // single_flow_threshold: contributing area in cells above which flow converges to
+    // the steepest direction (0 or negative disables it).
+    #[wasm_bindgen]
+    pub fn set_mfd_options(&mut self,
+                           exponent_mode: &str,
+                           exponent: f32,
+                           contour_weighting: bool,
+                           single_flow_threshold: f32) {
+        let exponent = match exponent_mode {
+            "qin2007" | "adaptive" => flow::MfdExponent::qin_2007(),
+            _ => flow::MfdExponent::Fixed(exponent),
+        };
+        
+        self.mfd_options = flow::MfdOptions {
+            exponent,
+            contour_weighting,
+            single_flow_threshold: if single_flow_threshold > 0.0 { Some(single_flow_threshold) } else { None },
+        };
+        console::log_1(&format!("MFD options: {:?}", self.mfd_options).into());
+    }
+    
     // Compute flow directions and accumulation
     #[wasm_bindgen]
     pub fn compute_flow(&mut self) -> Result<(), JsValue> {
@@ -98,10 +260,25 @@ impl WaterModel {
             console::log_1(&"Computing flow directions...".into());
             
             // Create the flow model
-            let mut flow_model = flow::FlowModel::new(dem);
+            let mut flow_model = flow::FlowModel::with_connectivity(dem, self.connectivity);
+            
+            // Flatten and tag lakes before routing
+            if !self.lake_polygons.is_empty() {
+                console::log_1(&format!("Flattening {} lakes...", self.lake_polygons.len()).into());
+                flow_model.set_lakes(&self.lake_polygons);
+            }
             
-            // Compute flow directions
-            flow_model.compute_flow_directions();
+            // Compute flow directions with the selected method
+            match self.flow_method {
+                flow::FlowMethod::D8 => flow_model.compute_flow_directions(),
+                flow::FlowMethod::DInf => {
+                    if let Err(e) = flow_model.compute_flow_directions_dinf() {
+                        self.dem = Some(flow_model.dem);
+                        return Err(JsValue::from_str(&e));
+                    }
+                }
+                flow::FlowMethod::MFD => flow_model.compute_flow_directions_mfd(self.mfd_options),
+            }
             
             // Compute flow accumulation
             console::log_1(&"Computing flow accumulation...".into());
@@ -120,6 +297,93 @@ impl WaterModel {
         }
     }
     
+    // Use a flow direction grid produced elsewhere instead of computing directions.
+    // `text` is an ESRI ASCII grid (.asc) in the given encoding ("esri", "taudem", "grass"
+    // or "pcraster"); flow accumulation is recomputed with D8 routing. Grids with flow cycles
+    // or unresolved receivers are rejected and the current flow model is kept.
+    #[wasm_bindgen]
+    pub fn import_flow_directions(&mut self, text: &str, encoding: &str) -> Result<(), JsValue> {
+        let encoding = direction_encoding::DirectionEncoding::from_name(encoding)
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown flow direction encoding: {}", encoding)))?;
+        let grid = direction_encoding::AsciiGrid::parse(text).map_err(|e| JsValue::from_str(&e))?;
+        
+        // Build the model aside so a rejected grid leaves the current one untouched
+        let dem = self.dem.clone().ok_or_else(|| JsValue::from_str("No DEM loaded"))?;
+        let mut flow_model = flow::FlowModel::with_connectivity(dem, self.connectivity);
+        flow_model.import_flow_directions(&grid, encoding).map_err(|e| JsValue::from_str(&e))?;
+        flow_model.compute_flow_accumulation();
+        
+        let report = flow_model.validate();
+        if !report.cycle_cells.is_empty() || !report.dangling_receiver_cells.is_empty() {
+            return Err(JsValue::from_str(&format!("Rejected flow directions: {}", report.problems().join("; "))));
+        }
+        
+        self.flow_model = Some(flow_model);
+        self.flow_method = flow::FlowMethod::D8;
+        console::log_1(&format!("Imported {:?} flow directions", encoding).into());
+        Ok(())
+    }
+    
+    // Export the flow directions as ESRI ASCII grid text in the given encoding
+    #[wasm_bindgen]
+    pub fn export_flow_directions(&self, encoding: &str) -> Result<String, JsValue> {
+        let encoding = direction_encoding::DirectionEncoding::from_name(encoding)
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown flow direction encoding: {}", encoding)))?;
+        if let Some(flow_model) = &self.flow_model {
+            Ok(flow_model.export_flow_directions(encoding).to_text())
+        } else {
+            Err(JsValue::from_str("Flow directions not computed"))
+        }
+    }
+    
+    // Change the raw elevation of grid cells x_min..=x_max, y_min..=y_max and update sink
+    // filling, flow directions and accumulation where they change.
+    // operation: "set" (elevation = value), "raise" (elevation += value) or "lower" (elevation -= value).
+    // Returns { edited_cell_count, refilled_cell_count, direction_cell_count,
+    // accumulation_cell_count, bounds: [x_min, y_min, x_max, y_max] or null, full_recompute }
+    #[wasm_bindgen]
+    pub fn edit_elevation_rectangle(&mut self,
+                                    x_min: usize,
+                                    y_min: usize,
+                                    x_max: usize,
+                                    y_max: usize,
+                                    operation: &str,
+                                    value: f32) -> Result<JsValue, JsValue> {
+        let edit = terrain_edit::ElevationEdit {
+            region: terrain_edit::EditRegion::Rectangle { x_min, y_min, x_max, y_max },
+            operation: parse_edit_operation(operation, value)?,
+        };
+        self.apply_elevation_edit(&edit)
+    }
+    
+    // Like edit_elevation_rectangle, for the cells within `radius` cells of (x, y)
+    #[wasm_bindgen]
+    pub fn edit_elevation_brush(&mut self, x: usize, y: usize, radius: f64, operation: &str, value: f32) -> Result<JsValue, JsValue> {
+        let edit = terrain_edit::ElevationEdit {
+            region: terrain_edit::EditRegion::Brush { x, y, radius },
+            operation: parse_edit_operation(operation, value)?,
+        };
+        self.apply_elevation_edit(&edit)
+    }
+    
+    // Check the flow graph for cycles, flow into nodata and dangling receivers, and that
+    // the accumulation is conserved. Returns { valid_cell_count, cycle_cells, blocked_cell_count,
+    // nodata_inflow_cells, dangling_receiver_cells, total_input, total_outflow, mass_balance_error }
+    #[wasm_bindgen]
+    pub fn validate_flow(&self) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let report = flow_model.validate();
+            for problem in report.problems() {
+                console::log_1(&format!("Flow validation: {}", problem).into());
+            }
+            
+            let result = serde_wasm_bindgen::to_value(&report)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
     // Get terrain data for visualization
     #[wasm_bindgen]
     pub fn get_terrain_data(&self) -> Result<JsValue, JsValue> {
@@ -160,6 +424,44 @@ impl WaterModel {
         }
     }
     
+    // Accumulate a weight grid (Float32Array aligned with the DEM, e.g. rainfall depth or
+    // pollutant load) along the computed flow paths. `loss` optionally gives the fraction
+    // of flow lost in each cell before it moves on. Returns a Float32Array.
+    #[wasm_bindgen]
+    pub fn compute_weighted_accumulation(&self, weights: Vec<f32>, loss: Option<Vec<f32>>) -> Result<Vec<f32>, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            flow_model.compute_weighted_accumulation(&weights, loss.as_deref())
+                .map_err(|e| JsValue::from_str(&e))
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Reduce a grid (Float32Array aligned with the DEM) over the upstream area of every cell:
+    // reducer "sum", "mean", "min", "max" or "fraction <class>" (area share of cells equal to
+    // the class code, e.g. urban land cover). Returns a Float32Array, NaN where undefined.
+    #[wasm_bindgen]
+    pub fn aggregate_upstream(&self, values: Vec<f32>, reducer: &str) -> Result<Vec<f32>, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            flow_model.aggregate_upstream(&values, parse_reducer(reducer)?)
+                .map_err(|e| JsValue::from_str(&e))
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Like aggregate_upstream, along the downstream flow path of every cell
+    // (e.g. "min" of the slopes for the minimum slope downstream)
+    #[wasm_bindgen]
+    pub fn aggregate_downstream(&self, values: Vec<f32>, reducer: &str) -> Result<Vec<f32>, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            flow_model.aggregate_downstream(&values, parse_reducer(reducer)?)
+                .map_err(|e| JsValue::from_str(&e))
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
     // Get comprehensive water visualization data
     #[wasm_bindgen]
     pub fn get_water_visualization_data(&self) -> Result<JsValue, JsValue> {
@@ -197,12 +499,14 @@ impl WaterModel {
         }
     }
     
-    // Get stream network data based on a threshold
+    // Get stream network data based on a threshold (fraction of max, or e.g. "1 km²")
     #[wasm_bindgen]
-    pub fn get_stream_network(&self, threshold_percentile: f32, smooth_iterations: usize) -> Result<JsValue, JsValue> {
+    pub fn get_stream_network(&self, threshold: JsValue, smooth_iterations: usize) -> Result<JsValue, JsValue> {
         if let Some(flow_model) = &self.flow_model {
+            let threshold = parse_stream_threshold(&threshold)?;
+            
             // Generate stream network with smoothing
-            let stream_network = visualization::generate_stream_network(flow_model, threshold_percentile, smooth_iterations);
+            let stream_network = visualization::generate_stream_network(flow_model, threshold, smooth_iterations);
             
             // Convert to JS
             let result = serde_wasm_bindgen::to_value(&stream_network)?;
@@ -212,15 +516,17 @@ impl WaterModel {
         }
     }
     
-    // Get detailed stream network as polylines
+    // Get detailed stream network as polylines (threshold: fraction of max, or e.g. "1 km²")
     #[wasm_bindgen]
-    pub fn get_stream_polylines(&self, threshold_percentile: f32) -> Result<JsValue, JsValue> {
+    pub fn get_stream_polylines(&self, threshold: JsValue) -> Result<JsValue, JsValue> {
         if let Some(flow_model) = &self.flow_model {
+            let threshold = parse_stream_threshold(&threshold)?;
+            
             // Try to use the high-quality stream generator 
-            console::log_1(&format!("Generating HIGH QUALITY stream network with threshold {}", threshold_percentile).into());
+            console::log_1(&format!("Generating HIGH QUALITY stream network with threshold {:?}", threshold).into());
             
             // Use our high-quality streams function
-            let polylines = visualization::generate_high_quality_streams(flow_model, threshold_percentile);
+            let polylines = visualization::generate_high_quality_streams(flow_model, threshold);
             console::log_1(&format!("Generated {} high-quality stream polylines", polylines.len()).into());
             
             // Convert to a format suitable for JavaScript
@@ -231,6 +537,335 @@ impl WaterModel {
         }
     }
     
+    // Like get_stream_polylines, without the cells whose upstream area reaches the DEM edge
+    // or nodata (their accumulation is underestimated)
+    #[wasm_bindgen]
+    pub fn get_uncontaminated_stream_polylines(&self, threshold: JsValue) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let polylines = visualization::generate_high_quality_streams(flow_model, parse_stream_threshold(&threshold)?);
+            let polylines = flow_model.exclude_contaminated(polylines, &flow_model.edge_contamination());
+            console::log_1(&format!("Kept {} uncontaminated stream polylines", polylines.len()).into());
+            
+            let result = serde_wasm_bindgen::to_value(&polylines)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Flag cells whose upstream area reaches the DEM edge or nodata.
+    // Returns { contaminated: [bool per cell], contaminated_fraction }
+    #[wasm_bindgen]
+    pub fn get_edge_contamination(&self) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            #[derive(serde::Serialize)]
+            struct EdgeContamination {
+                contaminated: Vec<bool>,
+                contaminated_fraction: f64,
+            }
+            
+            let contaminated = flow_model.edge_contamination();
+            let contaminated_fraction = flow_model.contaminated_fraction(&contaminated);
+            let result = serde_wasm_bindgen::to_value(&EdgeContamination { contaminated, contaminated_fraction })?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Run the constant-drop analysis behind the "constant-drop" stream threshold.
+    // Returns the t statistic per candidate threshold and the picked threshold in cells
+    #[wasm_bindgen]
+    pub fn get_drop_analysis(&self) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let result = serde_wasm_bindgen::to_value(&flow_model.drop_analysis())?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Get stream polylines split into river and lake segments
+    #[wasm_bindgen]
+    pub fn get_stream_segments(&self, threshold: JsValue) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let segments = flow_model.extract_stream_segments(parse_stream_threshold(&threshold)?);
+            console::log_1(&format!("Generated {} river and {} lake segments",
+                segments.rivers.len(), segments.lakes.len()).into());
+            
+            let result = serde_wasm_bindgen::to_value(&segments)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Get upstream contributing area per cell in "m2" or "km2"
+    #[wasm_bindgen]
+    pub fn get_contributing_area(&self, unit: &str) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let area = match unit {
+                "km2" | "km²" => flow_model.contributing_area_km2(),
+                _ => flow_model.contributing_area_m2(),
+            };
+            let result = serde_wasm_bindgen::to_value(&area)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Get specific catchment area per cell (m² per m of contour width)
+    #[wasm_bindgen]
+    pub fn get_specific_catchment_area(&self) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let result = serde_wasm_bindgen::to_value(&flow_model.specific_catchment_area())?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Delineate watersheds upstream of pour points ([[x, y], ...] in world coordinates).
+    // Each point snaps to the highest-accumulation cell within snap_radius map units.
+    // Returns [{ pour_point, outlet, mask, cell_count, area_m2, boundary }, ...]
+    #[wasm_bindgen]
+    pub fn delineate_watersheds(&self, pour_points: JsValue, snap_radius: f64) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let pour_points: Vec<(f64, f64)> = serde_wasm_bindgen::from_value(pour_points)?;
+            let watersheds = flow_model.delineate_watersheds(&pour_points, snap_radius);
+            console::log_1(&format!("Delineated {} of {} watersheds", watersheds.len(), pour_points.len()).into());
+            
+            let result = serde_wasm_bindgen::to_value(&watersheds)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Get flow length per cell in m: "downstream" (to the end of the flow path, default)
+    // or "upstream" (longest distance from the divide)
+    #[wasm_bindgen]
+    pub fn get_flow_length(&self, direction: &str) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let lengths = match direction {
+                "upstream" => flow_model.upstream_flow_length(),
+                _ => flow_model.downstream_flow_length(),
+            };
+            let result = serde_wasm_bindgen::to_value(&lengths)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Longest flow path of the watershed at a pour point (world coordinates, snapped like
+    // delineate_watersheds). Returns { cells, coordinates, profile: [{ distance_m, elevation }], length_m }
+    #[wasm_bindgen]
+    pub fn get_longest_flow_path(&self, x: f64, y: f64, snap_radius: f64) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let path = flow_model.longest_flow_path_at((x, y), snap_radius)
+                .ok_or_else(|| JsValue::from_str("Pour point lies outside the DEM"))?;
+            console::log_1(&format!("Longest flow path: {} cells, {:.1} m", path.cells.len(), path.length_m).into());
+            
+            let result = serde_wasm_bindgen::to_value(&path)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Get Manning flow velocity per cell in m/s (roughness: see parse_roughness)
+    #[wasm_bindgen]
+    pub fn get_velocities(&self, roughness: JsValue) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let velocities = flow_model
+                .compute_velocities(&parse_roughness(roughness)?, &velocity::ManningParameters::default())
+                .map_err(|e| JsValue::from_str(&e))?;
+            let result = serde_wasm_bindgen::to_value(&velocities)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Isochrone bands of interval_s seconds travel time to a pour point (world coordinates,
+    // snapped like delineate_watersheds). Returns { outlet, interval_s, labels, bands: [{ id,
+    // min_time_s, max_time_s, cell_count, area_m2, cumulative_area_m2, boundary }, ...] };
+    // the band areas are the time-area histogram.
+    #[wasm_bindgen]
+    pub fn get_isochrones(&self, x: f64, y: f64, snap_radius: f64, interval_s: f32, roughness: JsValue) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let (cell_x, cell_y) = flow_model.dem.cell_at(x, y)
+                .ok_or_else(|| JsValue::from_str("Pour point lies outside the DEM"))?;
+            let outlet = flow_model.snap_to_stream(cell_x, cell_y, snap_radius);
+            let velocities = flow_model
+                .compute_velocities(&parse_roughness(roughness)?, &velocity::ManningParameters::default())
+                .map_err(|e| JsValue::from_str(&e))?;
+            
+            let isochrones = flow_model.isochrones(outlet, &velocities, interval_s);
+            console::log_1(&format!("Computed {} isochrone bands", isochrones.bands.len()).into());
+            
+            let result = serde_wasm_bindgen::to_value(&isochrones)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Flow path from grid cell (x, y) to its outlet under the current flow method.
+    // Returns { cells, coordinates, profile: [{ distance_m, elevation }], length_m }
+    #[wasm_bindgen]
+    pub fn trace_downstream(&self, x: usize, y: usize) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            if x >= flow_model.dem.width || y >= flow_model.dem.height {
+                return Err(JsValue::from_str(&format!("Cell ({}, {}) lies outside the DEM", x, y)));
+            }
+            
+            let path = flow_model.trace_downstream(x, y);
+            let result = serde_wasm_bindgen::to_value(&path)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Everything draining through grid cell (x, y) under the current flow method.
+    // Returns { cell, bounds: [min_x, min_y, max_x, max_y], mask (shares over bounds, row by row),
+    // cell_count, area_m2, boundary }
+    #[wasm_bindgen]
+    pub fn trace_upstream(&self, x: usize, y: usize) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            if x >= flow_model.dem.width || y >= flow_model.dem.height {
+                return Err(JsValue::from_str(&format!("Cell ({}, {}) lies outside the DEM", x, y)));
+            }
+            
+            let trace = flow_model.trace_upstream(x, y);
+            let result = serde_wasm_bindgen::to_value(&trace)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Label every cell with the drainage basin it belongs to.
+    // Returns { labels: [basin id per cell, 0 = nodata], basins: [{ id, outlet, outlet_kind,
+    // outlet_accumulation, cell_count, area_m2, contaminated }, ...] } with basin 1 the largest.
+    #[wasm_bindgen]
+    pub fn get_basins(&self) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let basin_map = flow_model.label_basins();
+            console::log_1(&format!("Labelled {} drainage basins", basin_map.basins.len()).into());
+            
+            let result = serde_wasm_bindgen::to_value(&basin_map)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Split the stream network (threshold: fraction of max, or e.g. "1 km²") into links
+    // between junctions. Returns { labels: [link id per cell, 0 = none], links: [{ id, cells,
+    // downstream_link, upstream_links, cell_count, area_m2 }, ...] }
+    #[wasm_bindgen]
+    pub fn get_subcatchments(&self, threshold: JsValue) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let subcatchments = flow_model.delineate_subcatchments(parse_stream_threshold(&threshold)?);
+            let result = serde_wasm_bindgen::to_value(&subcatchments)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Sub-catchment polygons for the same threshold as a GeoJSON FeatureCollection string
+    #[wasm_bindgen]
+    pub fn get_subcatchments_geojson(&self, threshold: JsValue) -> Result<String, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let subcatchments = flow_model.delineate_subcatchments(parse_stream_threshold(&threshold)?);
+            Ok(subcatchments.to_geojson(&flow_model.dem).to_string())
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Morphometry of every drainage basin (units "basins", default) or stream link sub-catchment
+    // ("subcatchments"), with drainage statistics on the network above `threshold`.
+    // format "csv" returns CSV text, anything else a JSON array of rows
+    #[wasm_bindgen]
+    pub fn get_morphometry(&self, threshold: JsValue, units: &str, format: &str) -> Result<String, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let threshold = parse_stream_threshold(&threshold)?;
+            let table = match units {
+                "subcatchments" => flow_model.subcatchment_morphometry(threshold),
+                _ => flow_model.basin_morphometry(threshold),
+            };
+            Ok(format_morphometry(&table, format))
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Morphometry of the watersheds upstream of pour points ([[x, y], ...], snapped like
+    // delineate_watersheds), one row per watershed found; format as for get_morphometry
+    #[wasm_bindgen]
+    pub fn get_watershed_morphometry(&self, pour_points: JsValue, snap_radius: f64, threshold: JsValue, format: &str) -> Result<String, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let pour_points: Vec<(f64, f64)> = serde_wasm_bindgen::from_value(pour_points)?;
+            let watersheds = flow_model.delineate_watersheds(&pour_points, snap_radius);
+            let table = flow_model.watershed_morphometry(&watersheds, parse_stream_threshold(&threshold)?);
+            Ok(format_morphometry(&table, format))
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Get the stream graph above `threshold` (fraction of max, or e.g. "1 km²"):
+    // { nodes: [{ id, cell, kind, upstream_edges, downstream_edge }], edges: [{ id, from_node,
+    // to_node, cells, length_m, drop_m, mean_slope, sinuosity, upstream_area_m2,
+    // strahler_order, shreve_magnitude, contaminated }] }
+    #[wasm_bindgen]
+    pub fn get_stream_graph(&self, threshold: JsValue) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let network = flow_model.build_stream_network(parse_stream_threshold(&threshold)?);
+            let result = serde_wasm_bindgen::to_value(&network)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Ids of the stream graph edges "upstream" or "downstream" of an edge
+    #[wasm_bindgen]
+    pub fn get_connected_stream_edges(&self, threshold: JsValue, edge: usize, direction: &str) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let network = flow_model.build_stream_network(parse_stream_threshold(&threshold)?);
+            if edge >= network.edges.len() {
+                return Err(JsValue::from_str(&format!("Stream edge {} does not exist", edge)));
+            }
+            
+            let edge_ids: Vec<usize> = match direction {
+                "upstream" => network.upstream(edge).map(|e| e.id).collect(),
+                _ => network.downstream(edge).map(|e| e.id).collect(),
+            };
+            let result = serde_wasm_bindgen::to_value(&edge_ids)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
+    // Get lake id per cell (0 = not a lake)
+    #[wasm_bindgen]
+    pub fn get_lake_ids(&self) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let result = serde_wasm_bindgen::to_value(&flow_model.lake_ids)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
+    
     // Get slope data for visualization
     #[wasm_bindgen]
     pub fn get_slope_data(&self) -> Result<JsValue, JsValue> {
@@ -252,12 +887,20 @@ impl WaterModel {
     
     // Get high-quality stream network without downsampling
     #[wasm_bindgen]
-    pub fn get_hq_streams(&self, threshold_percentile: f32) -> JsValue {
-        console::log_1(&format!("get_hq_streams called with threshold percentile: {}", threshold_percentile).into());
+    pub fn get_hq_streams(&self, threshold: JsValue) -> JsValue {
+        console::log_1(&format!("get_hq_streams called with threshold: {:?}", threshold).into());
+        
+        let threshold = match parse_stream_threshold(&threshold) {
+            Ok(threshold) => threshold,
+            Err(e) => {
+                console::log_1(&e);
+                return JsValue::NULL;
+            }
+        };
         
         if let Some(flow_model) = &self.flow_model {
-            // Generate high-quality stream polylines using the provided threshold percentile
-            let polylines = visualization::generate_high_quality_streams(flow_model, threshold_percentile);
+            // Generate high-quality stream polylines using the provided threshold
+            let polylines = visualization::generate_high_quality_streams(flow_model, threshold);
             
             // Log success
             console::log_1(&format!("Generated {} high-quality stream polylines", polylines.len()).into());
@@ -273,31 +916,52 @@ impl WaterModel {
         }
     }
     
-    // Get hierarchical streams for zoom-based level of detail
+    // Get hierarchical streams for zoom-based level of detail: the network above 1% of
+    // the maximum accumulation grouped by Strahler order, main rivers first
     #[wasm_bindgen]
     pub fn extract_hierarchical_streams(&self) -> JsValue {
-        console::log_1(&"extract_hierarchical_streams called".into());
+        self.extract_hierarchical_streams_by_order(JsValue::from_f64(0.01), "strahler")
+    }
+    
+    // Get hierarchical streams grouped by stream order ("strahler", "shreve", "horton" or
+    // "hack") on the network above `threshold`. Returns [[polylines, order], ...] with the
+    // main rivers first.
+    #[wasm_bindgen]
+    pub fn extract_hierarchical_streams_by_order(&self, threshold: JsValue, order_kind: &str) -> JsValue {
+        console::log_1(&format!("extract_hierarchical_streams_by_order called with {}", order_kind).into());
+        
+        let threshold = match parse_stream_threshold(&threshold) {
+            Ok(threshold) => threshold,
+            Err(e) => {
+                console::log_1(&e);
+                return JsValue::NULL;
+            }
+        };
         
         if let Some(flow_model) = &self.flow_model {
-            // Get hierarchical streams from the flow model
-            let hierarchical_streams = flow_model.extract_hierarchical_streams();
-            
-            // Log success
-            console::log_1(&format!("Generated hierarchical streams with {} levels", hierarchical_streams.len()).into());
+            let orders = flow_model.compute_stream_orders(threshold);
+            let hierarchy = orders.hierarchy(stream_order::OrderKind::from_name(order_kind));
+            console::log_1(&format!("Generated hierarchical streams with {} order levels", hierarchy.len()).into());
             
-            // Convert to JS directly without using Result
-            match serde_wasm_bindgen::to_value(&hierarchical_streams) {
-                Ok(result) => result,
-                Err(e) => {
-                    console::log_1(&format!("Error converting hierarchical streams: {:?}", e).into());
-                    JsValue::NULL
-                },
-            }
+            serde_wasm_bindgen::to_value(&hierarchy).unwrap_or(JsValue::NULL)
         } else {
             console::log_1(&"Flow model not computed".into());
             JsValue::NULL
         }
     }
+    
+    // Get the stream order of every cell (0 = not a stream) for the given ordering scheme
+    #[wasm_bindgen]
+    pub fn get_stream_orders(&self, threshold: JsValue, order_kind: &str) -> Result<JsValue, JsValue> {
+        if let Some(flow_model) = &self.flow_model {
+            let orders = flow_model.compute_stream_orders(parse_stream_threshold(&threshold)?);
+            let cell_orders = orders.cell_orders(stream_order::OrderKind::from_name(order_kind));
+            let result = serde_wasm_bindgen::to_value(&cell_orders)?;
+            Ok(result)
+        } else {
+            Err(JsValue::from_str("Flow model not computed"))
+        }
+    }
 
     // Simple test function with minimal name to check if it gets exported
     #[wasm_bindgen]
@@ -307,6 +971,34 @@ impl WaterModel {
     }
 }
 
+impl WaterModel {
+    // Apply an elevation edit and update the flow model. Lake flattening and routing are
+    // only computed in full, so models with lakes are rebuilt from the edited DEM.
+    fn apply_elevation_edit(&mut self, edit: &terrain_edit::ElevationEdit) -> Result<JsValue, JsValue> {
+        let editor = self.terrain_editor.as_mut().ok_or_else(|| JsValue::from_str("No DEM loaded"))?;
+        let flow_model = self.flow_model.as_mut().ok_or_else(|| JsValue::from_str("Flow model not computed"))?;
+        
+        let update = if flow_model.lakes.is_empty() {
+            let update = editor.apply(flow_model, edit).map_err(|e| JsValue::from_str(&e))?;
+            self.dem = Some(flow_model.dem.clone());
+            update
+        } else {
+            let edited = editor.edit_raw(edit);
+            let mut dem = flow_model.dem.clone();
+            dem.data = editor.treated_elevations();
+            let update = terrain_edit::EditUpdate::full(edited.len(), dem.width, dem.height);
+            self.dem = Some(dem);
+            self.compute_flow()?;
+            update
+        };
+        
+        console::log_1(&format!("Elevation edit changed {} cells, recomputed accumulation of {}",
+                                update.edited_cell_count, update.accumulation_cell_count).into());
+        let result = serde_wasm_bindgen::to_value(&update)?;
+        Ok(result)
+    }
+}
+
 // Add a static debug function outside of the WaterModel impl
 #[wasm_bindgen]
 pub fn debug_streams() -> JsValue {
@@ -1,9 +1,31 @@
 use std::path::{Path, PathBuf};
 use std::fs;
 
+mod aggregation;
+mod basins;
+mod channel_initiation;
+mod contamination;
 mod dem;
+mod direction_encoding;
 mod flow;
+mod flow_fields;
+mod flow_length;
+mod lakes;
+mod morphometry;
+mod neighborhood;
+mod polygon;
+mod stream_network;
+mod stream_order;
+mod subcatchments;
+mod terrain_edit;
+mod trace;
+mod traversal;
+mod validation;
+mod velocity;
 mod visualization;
+mod watershed;
+#[cfg(test)]
+mod test_support;
 
 use dem::DigitalElevationModel;
 use flow::FlowModel;
@@ -92,22 +114,11 @@ fn main() {
                          i+1, x, y, acc, elev);
             }
             
-            // If there are outlet cells (cells that flow outside the DEM), identify them
-            let mut outlets = Vec::new();
-            for y in 0..flow_model.dem.height {
-                for x in 0..flow_model.dem.width {
-                    if flow_model.get_flow_direction(x, y) == Some(FlowDirection::NoFlow) {
-                        if let Some(acc) = flow_model.get_flow_accumulation(x, y) {
-                            if acc > 1.0 {  // Not just a single cell with no flow
-                                outlets.push((x, y, acc));
-                            }
-                        }
-                    }
-                }
-            }
-            
-            // Sort outlets by accumulation (largest first)
-            outlets.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
+            // Basin outlets come sorted by accumulation (largest first)
+            let outlets: Vec<(usize, usize, f32)> = flow_model.label_basins().basins.iter()
+                .filter(|basin| basin.outlet_accumulation > 1.0)  // Not just a single cell with no flow
+                .map(|basin| (basin.outlet.0, basin.outlet.1, basin.outlet_accumulation))
+                .collect();
             
             // Print the largest outlets (catchment pour points)
             println!("Catchment outlet points (x, y, accumulation):");
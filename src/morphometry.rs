@@ -0,0 +1,353 @@
+use crate::flow::{FlowModel, StreamThreshold};
+use crate::stream_order::StreamOrders;
+use crate::traversal::PathWalker;
+use crate::watershed::{Watershed, BOUNDARY_FRACTION};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// Number of points of the hypsometric curve, at relative heights 0, 0.1, ..., 1
+const HYPSOMETRIC_CURVE_POINTS: usize = 11;
+
+/// Morphometric statistics of one basin, sub-catchment or watershed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BasinMorphometry {
+    /// Label of the unit (basin id, link id, or position in the watershed list plus one)
+    pub id: u32,
+    /// Cell with the highest flow accumulation
+    pub outlet: (usize, usize),
+    pub cell_count: usize,
+    pub area_m2: f64,
+    /// Length of the outline along cell edges in m
+    pub perimeter_m: f64,
+    pub min_elevation: f32,
+    pub max_elevation: f32,
+    pub relief_m: f32,
+    /// Longest flow path inside the unit in m
+    pub basin_length_m: f64,
+    /// Relief over basin length (Schumm 1956)
+    pub relief_ratio: f64,
+    /// 4πA/P², 1 for a circle (Miller 1953)
+    pub circularity: f64,
+    /// Diameter of the circle with the same area over basin length (Schumm 1956)
+    pub elongation: f64,
+    /// (mean - min) / (max - min) elevation (Pike & Wilson 1971)
+    pub hypsometric_integral: f64,
+    /// Share of the area above relative heights 0, 0.1, ..., 1
+    pub hypsometric_curve: Vec<f64>,
+    /// Total channel length in m
+    pub stream_length_m: f64,
+    /// Channel length per area in km/km²
+    pub drainage_density: f64,
+    /// Number of Strahler streams of order 1, 2, ... whose head lies in the unit
+    pub stream_counts: Vec<usize>,
+    /// Strahler streams per km²
+    pub stream_frequency: f64,
+    /// N(u) / N(u + 1) for the orders u = 1, 2, ... (0 where no stream of order u + 1 starts in the unit)
+    pub bifurcation_ratios: Vec<f64>,
+    /// Mean of the non-zero bifurcation ratios
+    pub mean_bifurcation_ratio: f64,
+    /// Mean slope along the flow directions (tan β)
+    pub mean_slope: f64,
+}
+
+/// Morphometry of a set of units, one row each
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MorphometryTable {
+    pub rows: Vec<BasinMorphometry>,
+}
+
+/// Per-unit sums collected in one pass over the grid
+#[derive(Default)]
+struct UnitCells {
+    outlet: Option<usize>,
+    elevations: Vec<f32>,
+    perimeter_m: f64,
+    slope_sum: f64,
+    basin_length_m: f64,
+    stream_length_m: f64,
+    stream_counts: Vec<usize>,
+}
+
+impl FlowModel {
+    /// Morphometry of every drainage basin (see `label_basins`)
+    pub fn basin_morphometry(&self, threshold: StreamThreshold) -> MorphometryTable {
+        self.morphometry(&self.label_basins().labels, threshold)
+    }
+
+    /// Morphometry of the local sub-catchment of every stream link (see `delineate_subcatchments`)
+    pub fn subcatchment_morphometry(&self, threshold: StreamThreshold) -> MorphometryTable {
+        self.morphometry(&self.delineate_subcatchments(threshold).labels, threshold)
+    }
+
+    /// Morphometry of delineated watersheds, each taken as the cells sending at least half
+    /// of their flow to its outlet (the cells inside its boundary polygon)
+    pub fn watershed_morphometry(&self, watersheds: &[Watershed], threshold: StreamThreshold) -> MorphometryTable {
+        let orders = self.compute_stream_orders(threshold);
+        let rows = watersheds.iter()
+            .enumerate()
+            .flat_map(|(i, watershed)| {
+                let labels: Vec<u32> = watershed.mask.iter()
+                    .map(|&fraction| if fraction >= BOUNDARY_FRACTION { i as u32 + 1 } else { 0 })
+                    .collect();
+                self.morphometry_rows(&labels, &orders)
+            })
+            .collect();
+        MorphometryTable { rows }
+    }
+
+    /// Morphometry of every unit of a label grid (one id per cell, 0 for cells outside
+    /// all units). Drainage statistics refer to the stream network above `threshold`.
+    pub fn morphometry(&self, labels: &[u32], threshold: StreamThreshold) -> MorphometryTable {
+        let rows = self.morphometry_rows(labels, &self.compute_stream_orders(threshold));
+        println!("Computed morphometry of {} units", rows.len());
+        MorphometryTable { rows }
+    }
+
+    fn morphometry_rows(&self, labels: &[u32], orders: &StreamOrders) -> Vec<BasinMorphometry> {
+        let width = self.dem.width;
+        let height = self.dem.height;
+        let (x_res, y_res) = (self.dem.x_resolution, self.dem.y_resolution);
+        let label_count = labels.iter().copied().max().unwrap_or(0) as usize;
+        let mut units: Vec<UnitCells> = (0..label_count).map(|_| UnitCells::default()).collect();
+        let label_at = |x: isize, y: isize| {
+            if x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height {
+                labels[y as usize * width + x as usize]
+            } else {
+                0
+            }
+        };
+        let downstream_in_unit = |idx: usize| self.downstream_index(idx).filter(|&d| labels[d] == labels[idx]);
+
+        // Distance along the flow path to where it leaves the unit, with path compression
+        let mut to_exit = vec![0.0; width * height];
+        let mut walker = PathWalker::new(width * height);
+        let mut path = Vec::new();
+        for idx in 0..width * height {
+            let label = labels[idx];
+            let Some(elevation) = self.dem.get_elevation(idx % width, idx / width).filter(|_| label != 0) else {
+                continue;
+            };
+            let unit = &mut units[label as usize - 1];
+
+            if unit.outlet.is_none_or(|outlet| self.flow_accumulation[idx] > self.flow_accumulation[outlet]) {
+                unit.outlet = Some(idx);
+            }
+            unit.elevations.push(elevation);
+            unit.slope_sum += self.slopes[idx] as f64;
+
+            let (x, y) = ((idx % width) as isize, (idx / width) as isize);
+            for (nx, ny, side_length) in [(x, y - 1, x_res), (x + 1, y, y_res), (x, y + 1, x_res), (x - 1, y, y_res)] {
+                if label_at(nx, ny) != label {
+                    unit.perimeter_m += side_length;
+                }
+            }
+
+            if orders.cell_links[idx].is_some() {
+                if let Some(d) = downstream_in_unit(idx).filter(|&d| orders.cell_links[d].is_some()) {
+                    unit.stream_length_m += self.step_length(idx, d);
+                }
+            }
+
+            if !walker.is_resolved(idx) {
+                let mut below = walker.walk(idx, downstream_in_unit, &mut path);
+                while let Some(cell) = path.pop() {
+                    to_exit[cell] = below.map_or(0.0, |d| to_exit[d] + self.step_length(cell, d));
+                    below = Some(cell);
+                }
+            }
+            unit.basin_length_m = unit.basin_length_m.max(to_exit[idx]);
+        }
+
+        for (order, first, _) in orders.strahler_streams() {
+            let (x, y) = orders.links[first].cells[0];
+            let label = labels[y * width + x];
+            if label != 0 {
+                let counts = &mut units[label as usize - 1].stream_counts;
+                if counts.len() < order as usize {
+                    counts.resize(order as usize, 0);
+                }
+                counts[order as usize - 1] += 1;
+            }
+        }
+
+        units.into_iter()
+            .enumerate()
+            .filter_map(|(i, unit)| {
+                let outlet = unit.outlet?;
+                Some(self.unit_morphometry(i as u32 + 1, outlet, unit))
+            })
+            .collect()
+    }
+
+    fn unit_morphometry(&self, id: u32, outlet: usize, mut unit: UnitCells) -> BasinMorphometry {
+        let width = self.dem.width;
+        let cell_count = unit.elevations.len();
+        let area_m2 = cell_count as f64 * self.dem.cell_area();
+        let area_km2 = area_m2 / 1.0e6;
+        let ratio = |numerator: f64, denominator: f64| if denominator > 0.0 { numerator / denominator } else { 0.0 };
+
+        unit.elevations.sort_by(|a, b| a.total_cmp(b));
+        let min_elevation = unit.elevations[0];
+        let max_elevation = unit.elevations[cell_count - 1];
+        let relief = (max_elevation - min_elevation) as f64;
+        let mean_elevation = unit.elevations.iter().map(|&z| z as f64).sum::<f64>() / cell_count as f64;
+
+        // Share of the cells at or above each relative height
+        let hypsometric_curve = (0..HYPSOMETRIC_CURVE_POINTS)
+            .map(|i| {
+                let level = min_elevation as f64 + relief * i as f64 / (HYPSOMETRIC_CURVE_POINTS - 1) as f64;
+                let below = unit.elevations.partition_point(|&z| (z as f64) < level - 1.0e-6);
+                if i > 0 && relief == 0.0 { 0.0 } else { (cell_count - below) as f64 / cell_count as f64 }
+            })
+            .collect();
+
+        let bifurcation_ratios: Vec<f64> = unit.stream_counts.windows(2)
+            .map(|pair| ratio(pair[0] as f64, pair[1] as f64))
+            .collect();
+        let defined: Vec<f64> = bifurcation_ratios.iter().copied().filter(|&rb| rb > 0.0).collect();
+
+        BasinMorphometry {
+            id,
+            outlet: (outlet % width, outlet / width),
+            cell_count,
+            area_m2,
+            perimeter_m: unit.perimeter_m,
+            min_elevation,
+            max_elevation,
+            relief_m: max_elevation - min_elevation,
+            basin_length_m: unit.basin_length_m,
+            relief_ratio: ratio(relief, unit.basin_length_m),
+            circularity: ratio(4.0 * std::f64::consts::PI * area_m2, unit.perimeter_m.powi(2)),
+            elongation: ratio(2.0 * (area_m2 / std::f64::consts::PI).sqrt(), unit.basin_length_m),
+            hypsometric_integral: ratio(mean_elevation - min_elevation as f64, relief),
+            hypsometric_curve,
+            stream_length_m: unit.stream_length_m,
+            drainage_density: ratio(unit.stream_length_m / 1000.0, area_km2),
+            stream_frequency: ratio(unit.stream_counts.iter().sum::<usize>() as f64, area_km2),
+            stream_counts: unit.stream_counts,
+            mean_bifurcation_ratio: ratio(defined.iter().sum(), defined.len() as f64),
+            bifurcation_ratios,
+            mean_slope: unit.slope_sum / cell_count as f64,
+        }
+    }
+}
+
+impl MorphometryTable {
+    /// Table as CSV with a header row; list columns are separated by semicolons
+    pub fn to_csv(&self) -> String {
+        let list = |values: &[f64]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(";");
+        let mut csv = String::from(
+            "id,outlet_x,outlet_y,cell_count,area_m2,perimeter_m,min_elevation,max_elevation,relief_m,\
+             basin_length_m,relief_ratio,circularity,elongation,hypsometric_integral,hypsometric_curve,\
+             stream_length_m,drainage_density,stream_counts,stream_frequency,bifurcation_ratios,\
+             mean_bifurcation_ratio,mean_slope\n");
+        for row in &self.rows {
+            let stream_counts: Vec<f64> = row.stream_counts.iter().map(|&count| count as f64).collect();
+            let _ = writeln!(csv, "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                             row.id, row.outlet.0, row.outlet.1, row.cell_count, row.area_m2, row.perimeter_m,
+                             row.min_elevation, row.max_elevation, row.relief_m, row.basin_length_m,
+                             row.relief_ratio, row.circularity, row.elongation, row.hypsometric_integral,
+                             list(&row.hypsometric_curve), row.stream_length_m, row.drainage_density,
+                             list(&stream_counts), row.stream_frequency, list(&row.bifurcation_ratios),
+                             row.mean_bifurcation_ratio, row.mean_slope);
+        }
+        csv
+    }
+
+    /// Table as a JSON array of rows
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(&self.rows).unwrap_or(Value::Null)
+    }
+
+    pub fn write_csv(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, self.to_csv())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dem::DigitalElevationModel;
+    use crate::flow::{FlowDirection, FlowMethod};
+    use crate::test_support::{ramp, routed, valley};
+
+    #[test]
+    fn morphometry_of_a_valley_basin() {
+        let model = valley();
+        let table = model.basin_morphometry(StreamThreshold::Cells(21.0));
+        assert_eq!(table.rows.len(), 1);
+        let basin = &table.rows[0];
+
+        assert_eq!((basin.outlet, basin.cell_count), ((10, 29), 630));
+        assert!((basin.area_m2 - 63_000.0).abs() < 1e-6);
+        assert!((basin.perimeter_m - 1020.0).abs() < 1e-6);
+        assert!((basin.relief_m - 44.5).abs() < 1e-4);
+        // From a top corner 10 cells across to the valley, then 29 cells down it
+        assert!((basin.basin_length_m - 390.0).abs() < 1e-6);
+        assert!((basin.relief_ratio - 44.5 / 390.0).abs() < 1e-6);
+        assert!((basin.circularity - 4.0 * std::f64::consts::PI * 63_000.0 / 1020.0_f64.powi(2)).abs() < 1e-9);
+        // Mean elevation 100 + 3·110/21 + 0.5·14.5
+        assert!((basin.hypsometric_integral - (3.0 * 110.0 / 21.0 + 7.25) / 44.5).abs() < 1e-4);
+        assert_eq!(basin.hypsometric_curve.len(), 11);
+        assert_eq!((basin.hypsometric_curve[0], basin.hypsometric_curve[10]), (1.0, 2.0 / 630.0));
+
+        // The whole valley axis is one first-order stream
+        assert_eq!(basin.stream_counts, vec![1]);
+        assert!((basin.drainage_density - 0.29 / 0.063).abs() < 1e-9);
+        assert!(basin.bifurcation_ratios.is_empty());
+        assert!((basin.mean_slope - (600.0 * 0.3 + 29.0 * 0.05) / 630.0).abs() < 1e-6);
+
+        let csv = table.to_csv();
+        assert_eq!(csv.lines().count(), 2);
+        assert_eq!(csv.lines().nth(1).unwrap().split(',').count(), csv.lines().next().unwrap().split(',').count());
+        assert_eq!(table.to_json()[0]["stream_counts"], serde_json::json!([1]));
+    }
+
+    #[test]
+    fn cyclic_directions_give_a_finite_basin_length() {
+        // Three cells draining round in a circle
+        let mut model = FlowModel::new(DigitalElevationModel::new(2, 2, 10.0, vec![10.0; 4]));
+        model.flow_directions = vec![FlowDirection::East, FlowDirection::Southwest, FlowDirection::North, FlowDirection::NoFlow];
+        let table = model.morphometry(&[1, 1, 1, 0], StreamThreshold::Cells(f32::INFINITY));
+        assert_eq!(table.rows.len(), 1);
+        // The walk from (0, 0) runs east, then southwest, and stops where it would close the cycle
+        assert!((table.rows[0].basin_length_m - (10.0 + 10.0 * std::f64::consts::SQRT_2)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unlabelled_and_nodata_cells_form_no_units() {
+        let model = valley();
+        let table = model.morphometry(&vec![0; 21 * 30], StreamThreshold::Cells(21.0));
+        assert!(table.rows.is_empty());
+        assert_eq!(table.to_csv().lines().count(), 1);
+        assert_eq!(table.to_json(), serde_json::json!([]));
+
+        let mut dem = ramp(4, 1, 10.0, 10.0, 0.0, 0.1);
+        dem.data[3] = f32::NAN;
+        let model = routed(dem, FlowMethod::D8);
+        assert!(model.morphometry(&[0, 0, 0, 1], StreamThreshold::Cells(f32::INFINITY)).rows.is_empty());
+    }
+
+    #[test]
+    fn units_keep_their_ids_across_gaps_in_the_labels() {
+        let model = routed(ramp(4, 1, 10.0, 10.0, 0.0, 0.1), FlowMethod::D8);
+        let table = model.morphometry(&[1, 1, 0, 3], StreamThreshold::Cells(f32::INFINITY));
+        let ids: Vec<u32> = table.rows.iter().map(|row| row.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+
+        // A single cell has no relief, length or streams
+        let single = &table.rows[1];
+        assert_eq!((single.outlet, single.cell_count, single.perimeter_m), ((3, 0), 1, 40.0));
+        assert_eq!((single.relief_m, single.basin_length_m, single.relief_ratio), (0.0, 0.0, 0.0));
+        assert_eq!(single.hypsometric_integral, 0.0);
+        assert_eq!(single.hypsometric_curve[..2], [1.0, 0.0]);
+        assert!(single.stream_counts.is_empty() && single.mean_bifurcation_ratio == 0.0);
+
+        // The two-cell unit drains east out of itself over one 10 m step
+        assert_eq!((table.rows[0].outlet, table.rows[0].basin_length_m), ((1, 0), 10.0));
+    }
+}
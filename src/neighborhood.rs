@@ -0,0 +1,152 @@
+use serde::{Serialize, Deserialize};
+
+/// Neighbor offsets in flow direction order: E, SE, S, SW, W, NW, N, NE.
+/// The position in this table is the direction index used throughout the crate
+/// (the matching D8 code is `1 << index`).
+pub const OFFSETS: [(isize, isize); 8] = [
+    (1, 0),    // 0: East
+    (1, 1),    // 1: Southeast
+    (0, 1),    // 2: South
+    (-1, 1),   // 3: Southwest
+    (-1, 0),   // 4: West
+    (-1, -1),  // 5: Northwest
+    (0, -1),   // 6: North
+    (1, -1),   // 7: Northeast
+];
+
+const D4_INDICES: [usize; 4] = [0, 2, 4, 6];
+const D8_INDICES: [usize; 8] = [0, 1, 2, 3, 4, 5, 6, 7];
+
+/// Which neighbors a cell can exchange flow with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Connectivity {
+    D4,  // Cardinal neighbors only (E, S, W, N)
+    D8,  // Cardinal and diagonal neighbors
+}
+
+impl Connectivity {
+    /// Parse a connectivity name ("d4"/"4" or "d8"/"8"), defaulting to D8
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "d4" | "4" => Connectivity::D4,
+            _ => Connectivity::D8,
+        }
+    }
+
+    /// Direction indices (into `OFFSETS`) that belong to this connectivity
+    pub fn direction_indices(self) -> &'static [usize] {
+        match self {
+            Connectivity::D4 => &D4_INDICES,
+            Connectivity::D8 => &D8_INDICES,
+        }
+    }
+}
+
+/// Whether a direction index points to a diagonal neighbor
+pub fn is_diagonal(dir: usize) -> bool {
+    dir % 2 == 1
+}
+
+/// Direction index pointing back the other way
+pub fn opposite(dir: usize) -> usize {
+    (dir + 4) % 8
+}
+
+/// Distance to the neighbor in a direction for cells of size `x_resolution` × `y_resolution`
+pub fn distance(dir: usize, x_resolution: f64, y_resolution: f64) -> f64 {
+    if is_diagonal(dir) {
+        x_resolution.hypot(y_resolution)
+    } else if dir.is_multiple_of(4) {
+        x_resolution // East / West
+    } else {
+        y_resolution // South / North
+    }
+}
+
+/// Grid neighborhood shared by all routing algorithms
+#[derive(Debug, Clone, Copy)]
+pub struct Neighborhood {
+    pub width: usize,
+    pub height: usize,
+    pub connectivity: Connectivity,
+}
+
+impl Neighborhood {
+    pub fn new(width: usize, height: usize, connectivity: Connectivity) -> Self {
+        Neighborhood { width, height, connectivity }
+    }
+
+    /// Coordinates of the neighbor in direction `dir`, if it lies inside the grid.
+    /// Does not check connectivity, so callers may look up any of the 8 directions.
+    pub fn neighbor(&self, x: usize, y: usize, dir: usize) -> Option<(usize, usize)> {
+        let (dx, dy) = OFFSETS[dir];
+        let nx = x as isize + dx;
+        let ny = y as isize + dy;
+
+        if nx < 0 || ny < 0 || nx >= self.width as isize || ny >= self.height as isize {
+            None
+        } else {
+            Some((nx as usize, ny as usize))
+        }
+    }
+
+    /// Flat index of the neighbor in direction `dir`, if it lies inside the grid
+    pub fn neighbor_index(&self, idx: usize, dir: usize) -> Option<usize> {
+        self.neighbor(idx % self.width, idx / self.width, dir)
+            .map(|(nx, ny)| ny * self.width + nx)
+    }
+
+    /// All in-grid neighbors for this connectivity as (direction index, (x, y))
+    pub fn neighbors(self, x: usize, y: usize) -> impl Iterator<Item = (usize, (usize, usize))> {
+        self.connectivity.direction_indices()
+            .iter()
+            .filter_map(move |&dir| self.neighbor(x, y, dir).map(|n| (dir, n)))
+    }
+
+    /// Whether a cell lies on the outer edge of the grid
+    pub fn is_edge(&self, x: usize, y: usize) -> bool {
+        x == 0 || y == 0 || x == self.width - 1 || y == self.height - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neighbor_set(connectivity: Connectivity, x: usize, y: usize) -> Vec<(usize, (usize, usize))> {
+        Neighborhood::new(4, 3, connectivity).neighbors(x, y).collect()
+    }
+
+    #[test]
+    fn d4_and_d8_neighbor_sets() {
+        assert_eq!(neighbor_set(Connectivity::D4, 1, 1), vec![(0, (2, 1)), (2, (1, 2)), (4, (0, 1)), (6, (1, 0))]);
+        assert_eq!(neighbor_set(Connectivity::D8, 1, 1), vec![
+            (0, (2, 1)), (1, (2, 2)), (2, (1, 2)), (3, (0, 2)),
+            (4, (0, 1)), (5, (0, 0)), (6, (1, 0)), (7, (2, 0)),
+        ]);
+
+        // Corners keep only the in-grid neighbors
+        assert_eq!(neighbor_set(Connectivity::D4, 0, 0), vec![(0, (1, 0)), (2, (0, 1))]);
+        assert_eq!(neighbor_set(Connectivity::D8, 3, 2), vec![(4, (2, 2)), (5, (2, 1)), (6, (3, 1))]);
+
+        let hood = Neighborhood::new(4, 3, Connectivity::D4);
+        assert_eq!(hood.neighbor(1, 1, 5), Some((0, 0)), "lookups ignore the connectivity");
+        assert_eq!(hood.neighbor_index(11, 0), None);
+        assert_eq!(hood.neighbor_index(5, 3), Some(8));
+        assert!(hood.is_edge(3, 1) && !hood.is_edge(1, 1));
+    }
+
+    #[test]
+    fn direction_geometry() {
+        for dir in 0..8 {
+            assert_eq!(is_diagonal(dir), OFFSETS[dir].0 != 0 && OFFSETS[dir].1 != 0);
+            let (dx, dy) = OFFSETS[opposite(dir)];
+            assert_eq!((dx, dy), (-OFFSETS[dir].0, -OFFSETS[dir].1));
+        }
+        assert_eq!(distance(0, 3.0, 4.0), 3.0);
+        assert_eq!(distance(6, 3.0, 4.0), 4.0);
+        assert_eq!(distance(3, 3.0, 4.0), 5.0);
+        assert_eq!(Connectivity::from_name("D4"), Connectivity::D4);
+        assert_eq!(Connectivity::from_name("queen"), Connectivity::D8);
+    }
+}
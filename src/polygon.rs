@@ -0,0 +1,46 @@
+use serde::{Serialize, Deserialize};
+
+/// A polygon in world coordinates (same CRS as the DEM geo_transform), used for lake
+/// outlines as well as for vectorized watershed and catchment boundaries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Polygon {
+    /// Outer ring as (x, y) vertices, closed or open
+    pub exterior: Vec<(f64, f64)>,
+    /// Optional rings cut out of the polygon
+    #[serde(default)]
+    pub holes: Vec<Vec<(f64, f64)>>,
+}
+
+impl Polygon {
+    /// Even-odd point-in-polygon test that honours holes
+    pub fn contains(&self, px: f64, py: f64) -> bool {
+        ring_contains(&self.exterior, px, py) &&
+            !self.holes.iter().any(|hole| ring_contains(hole, px, py))
+    }
+
+    /// Bounding box of the outer ring (minx, miny, maxx, maxy)
+    pub fn bounds(&self) -> (f64, f64, f64, f64) {
+        self.exterior.iter().fold(
+            (f64::MAX, f64::MAX, f64::MIN, f64::MIN),
+            |(minx, miny, maxx, maxy), &(x, y)| (minx.min(x), miny.min(y), maxx.max(x), maxy.max(y)),
+        )
+    }
+}
+
+fn ring_contains(ring: &[(f64, f64)], px: f64, py: f64) -> bool {
+    if ring.len() < 3 {
+        return false;
+    }
+
+    let mut inside = false;
+    let mut j = ring.len() - 1;
+    for i in 0..ring.len() {
+        let (xi, yi) = ring[i];
+        let (xj, yj) = ring[j];
+        if (yi > py) != (yj > py) && px < (xj - xi) * (py - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
@@ -1,11 +1,22 @@
 use crate::dem::DigitalElevationModel;
-use crate::flow::FlowModel;
+use crate::direction_encoding::{AsciiGrid, DirectionEncoding};
+use crate::flow::{FlowDirection, FlowModel, StreamThreshold};
+use crate::morphometry::MorphometryTable;
 use crate::visualization::{generate_visualization_data, generate_high_quality_streams};
 use serde::{Serialize, Deserialize};
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::collections::HashMap;
 
+/// Contributing areas in m² at which the detailed, medium and major stream networks start.
+/// Absolute areas keep the stream density independent of the precompute resolution.
+const DETAILED_STREAM_AREA_M2: f64 = 1.0e6;
+const MEDIUM_STREAM_AREA_M2: f64 = 5.0e6;
+const MAJOR_STREAM_AREA_M2: f64 = 25.0e6;
+
+/// Smallest share of the DEM a drainage basin must cover to get a morphometry row
+const MORPHOMETRY_MIN_BASIN_FRACTION: f64 = 0.01;
+
 /// Pre-computed catchment data that can be loaded instantly
 #[derive(Serialize, Deserialize)]
 pub struct PrecomputedCatchment {
@@ -31,6 +42,13 @@ pub struct CatchmentMetadata {
     pub bounds: (f64, f64, f64, f64),
     pub elevation_range: (f32, f32),
     pub processing_timestamp: String,
+    /// Share of the cells whose upstream area reaches the DEM edge or nodata
+    #[serde(default)]
+    pub contaminated_fraction: f64,
+    /// Morphometry of the drainage basins covering at least 1% of the DEM, with drainage
+    /// statistics on the detailed stream network
+    #[serde(default)]
+    pub morphometry: MorphometryTable,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -110,16 +128,46 @@ impl PrecomputedCatchment {
         let mut flow_model = FlowModel::new(dem);
         
         // Use D∞ flow directions for more natural stream networks
-        flow_model.compute_flow_directions_dinf();
+        flow_model.compute_flow_directions_dinf()?;
         flow_model.compute_flow_accumulation();
         
+        Self::from_flow_model(flow_model, catchment_id)
+    }
+    
+    /// Create a pre-computed catchment from a DEM file and a flow direction grid produced
+    /// elsewhere (ESRI ASCII grid in the given encoding). The DEM is used at full resolution
+    /// so it lines up with the direction grid, and the direction step is skipped.
+    pub fn from_dem_with_flow_directions(dem_path: &Path,
+                                         directions_path: &Path,
+                                         encoding: DirectionEncoding,
+                                         catchment_id: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        println!("Processing catchment {} from {} with flow directions from {}",
+                 catchment_id, dem_path.display(), directions_path.display());
+        
+        let dem = DigitalElevationModel::from_multiple_geotiffs(&[dem_path])?;
+        let mut flow_model = FlowModel::new(dem);
+        flow_model.read_flow_directions(directions_path, encoding)?;
+        flow_model.compute_flow_accumulation();
+        
+        Self::from_flow_model(flow_model, catchment_id)
+    }
+    
+    /// Derive all pre-computed products from a flow model with accumulation computed.
+    /// Fails if the flow network does not pass validation.
+    fn from_flow_model(flow_model: FlowModel, catchment_id: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let validation = flow_model.validate();
+        if !validation.is_valid() {
+            return Err(format!("Flow network of {} failed validation: {}",
+                               catchment_id, validation.problems().join("; ")).into());
+        }
+        
         // Generate visualization data
         let water_viz_data = generate_visualization_data(&flow_model);
         
         // Extract stream networks at different detail levels
-        let detailed_streams = generate_high_quality_streams(&flow_model, 0.01);
-        let medium_streams = generate_high_quality_streams(&flow_model, 0.05);
-        let major_streams = generate_high_quality_streams(&flow_model, 0.1);
+        let detailed_streams = generate_high_quality_streams(&flow_model, StreamThreshold::Area(DETAILED_STREAM_AREA_M2));
+        let medium_streams = generate_high_quality_streams(&flow_model, StreamThreshold::Area(MEDIUM_STREAM_AREA_M2));
+        let major_streams = generate_high_quality_streams(&flow_model, StreamThreshold::Area(MAJOR_STREAM_AREA_M2));
         
         // Find outlet points
         let outlets = Self::find_outlets(&flow_model);
@@ -129,6 +177,10 @@ impl PrecomputedCatchment {
         
         // Create metadata
         let elevation_range = Self::calculate_elevation_range(&flow_model.dem.data);
+        let contaminated_fraction = flow_model.contaminated_fraction(&flow_model.edge_contamination());
+        let mut morphometry = flow_model.basin_morphometry(StreamThreshold::Area(DETAILED_STREAM_AREA_M2));
+        let total_area: f64 = morphometry.rows.iter().map(|row| row.area_m2).sum();
+        morphometry.rows.retain(|row| row.area_m2 >= total_area * MORPHOMETRY_MIN_BASIN_FRACTION);
         let metadata = CatchmentMetadata {
             width: flow_model.dem.width,
             height: flow_model.dem.height,
@@ -136,6 +188,8 @@ impl PrecomputedCatchment {
             bounds: flow_model.dem.bounds,
             elevation_range,
             processing_timestamp: chrono::Utc::now().to_rfc3339(),
+            contaminated_fraction,
+            morphometry,
         };
         
         // Create flow data
@@ -171,6 +225,27 @@ impl PrecomputedCatchment {
         })
     }
     
+    /// Flow directions as an ASCII grid in the given encoding, georeferenced like the
+    /// pre-computed grids (e.g. a PCRaster LDD for wflow)
+    pub fn flow_direction_grid(&self, encoding: DirectionEncoding) -> AsciiGrid {
+        let (width, height) = (self.metadata.width, self.metadata.height);
+        let (minx, miny, maxx, maxy) = self.metadata.bounds;
+        let codes = self.flow.flow_directions.iter()
+            .map(|&code| encoding.encode(FlowDirection::from_code(code)) as f64)
+            .collect();
+        
+        AsciiGrid {
+            width,
+            height,
+            x_lower_left: minx,
+            y_lower_left: miny,
+            x_resolution: (maxx - minx) / width.max(1) as f64,
+            y_resolution: (maxy - miny) / height.max(1) as f64,
+            no_data_value: None,
+            values: codes,
+        }
+    }
+    
     /// Save the pre-computed data to a file
     pub fn save_to_file(&self, output_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
         // Use JSON instead of bincode for easier JavaScript consumption
@@ -189,25 +264,12 @@ impl PrecomputedCatchment {
     
     /// Find major outlet points in the catchment
     fn find_outlets(flow_model: &FlowModel) -> Vec<(usize, usize, f32)> {
-        let mut outlets = Vec::new();
-        
-        for y in 0..flow_model.dem.height {
-            for x in 0..flow_model.dem.width {
-                if flow_model.get_flow_direction(x, y) == Some(crate::flow::FlowDirection::NoFlow) {
-                    if let Some(acc) = flow_model.get_flow_accumulation(x, y) {
-                        if acc > 1.0 {  // Not just a single cell with no flow
-                            outlets.push((x, y, acc));
-                        }
-                    }
-                }
-            }
-        }
-        
-        // Sort by accumulation (largest first) and take top 10
-        outlets.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
-        outlets.truncate(10);
-        
-        outlets
+        // Basins come sorted by outlet accumulation (largest first); take the top 10
+        flow_model.label_basins().basins.iter()
+            .filter(|basin| basin.outlet_accumulation > 1.0)  // Not just a single cell with no flow
+            .take(10)
+            .map(|basin| (basin.outlet.0, basin.outlet.1, basin.outlet_accumulation))
+            .collect()
     }
     
     /// Create optimized terrain data for 3D rendering
@@ -277,6 +339,8 @@ impl PrecomputedCatchment {
         let new_width = (original.width + factor - 1) / factor;
         let new_height = (original.height + factor - 1) / factor;
         let new_resolution = original.resolution * factor as f64;
+        let new_x_resolution = original.x_resolution * factor as f64;
+        let new_y_resolution = original.y_resolution * factor as f64;
         
         let mut new_data = Vec::with_capacity(new_width * new_height);
         
@@ -299,14 +363,14 @@ impl PrecomputedCatchment {
         let bounds = (
             original.bounds.0,
             original.bounds.1,
-            original.bounds.0 + new_width as f64 * new_resolution,
-            original.bounds.1 + new_height as f64 * new_resolution,
+            original.bounds.0 + new_width as f64 * new_x_resolution,
+            original.bounds.1 + new_height as f64 * new_y_resolution,
         );
         
         // Update geo_transform
         let geo_transform = [
             original.geo_transform[0],
-            new_resolution,
+            original.geo_transform[1] * factor as f64,
             original.geo_transform[2],
             original.geo_transform[3],
             original.geo_transform[4],
@@ -317,6 +381,8 @@ impl PrecomputedCatchment {
             width: new_width,
             height: new_height,
             resolution: new_resolution,
+            x_resolution: new_x_resolution,
+            y_resolution: new_y_resolution,
             data: new_data,
             no_data_value: original.no_data_value,
             geo_transform,
@@ -354,6 +420,14 @@ pub fn process_all_catchments(input_dir: &Path, output_dir: &Path) -> Result<(),
         // Save to file
         let output_path = output_dir.join(format!("{}.json", catchment_id));
         catchment.save_to_file(&output_path)?;
+        
+        // Local drain direction map for PCRaster and wflow models
+        let ldd_path = output_dir.join(format!("{}_ldd.asc", catchment_id));
+        catchment.flow_direction_grid(DirectionEncoding::Pcraster).write(&ldd_path)?;
+        
+        // Basin morphometry table for spreadsheets and GIS
+        let morphometry_path = output_dir.join(format!("{}_morphometry.csv", catchment_id));
+        catchment.metadata.morphometry.write_csv(&morphometry_path)?;
     }
     
     // Create an index file with metadata
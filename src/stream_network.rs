@@ -0,0 +1,257 @@
+use crate::flow::{FlowModel, StreamThreshold};
+use serde::{Serialize, Deserialize};
+
+/// Role of a node in the stream graph
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeKind {
+    Source,      // Channel head, no upstream edges
+    Confluence,  // Two or more edges join
+    Outlet,      // Channel leaves the network (DEM edge, sink or nodata)
+}
+
+/// Point where edges start or end
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamNode {
+    /// Index into `StreamNetwork::nodes`
+    pub id: usize,
+    pub cell: (usize, usize),
+    pub kind: NodeKind,
+    /// Edges ending at this node
+    pub upstream_edges: Vec<usize>,
+    /// Edge starting at this node (None for outlets)
+    pub downstream_edge: Option<usize>,
+}
+
+/// Stream link between two nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamEdge {
+    /// Index into `StreamNetwork::edges`
+    pub id: usize,
+    pub from_node: usize,
+    pub to_node: usize,
+    /// Channel cells from upstream to downstream; every stream cell belongs to exactly one edge
+    pub cells: Vec<(usize, usize)>,
+    /// Length along the channel to the downstream node in m
+    pub length_m: f64,
+    /// Elevation difference between the start and the downstream node in m
+    pub drop_m: f32,
+    /// Drop divided by length
+    pub mean_slope: f32,
+    /// Channel length divided by the straight-line distance between its ends (1 = straight)
+    pub sinuosity: f64,
+    /// Contributing area at the last cell of the edge in m²
+    pub upstream_area_m2: f64,
+    pub strahler_order: u32,
+    pub shreve_magnitude: u32,
+    /// The contributing area at the last cell reaches the DEM edge or nodata
+    pub contaminated: bool,
+}
+
+/// Stream network as a graph of sources, confluences and outlets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamNetwork {
+    pub nodes: Vec<StreamNode>,
+    pub edges: Vec<StreamEdge>,
+}
+
+impl StreamNetwork {
+    /// Edge cells plus the confluence they run into, so consecutive edges share an endpoint
+    pub fn polyline(&self, edge: usize) -> Vec<(usize, usize)> {
+        let edge = &self.edges[edge];
+        let mut polyline = edge.cells.clone();
+        let to_node = &self.nodes[edge.to_node];
+        if to_node.kind == NodeKind::Confluence {
+            polyline.push(to_node.cell);
+        }
+        polyline
+    }
+
+    /// Polylines of all edges with at least two points
+    pub fn polylines(&self) -> Vec<Vec<(usize, usize)>> {
+        (0..self.edges.len())
+            .map(|edge| self.polyline(edge))
+            .filter(|polyline| polyline.len() >= 2)
+            .collect()
+    }
+
+    /// Edges below `edge`, nearest first, down to the outlet
+    pub fn downstream(&self, edge: usize) -> impl Iterator<Item = &StreamEdge> + '_ {
+        std::iter::successors(Some(&self.edges[edge]), move |current| {
+            self.nodes[current.to_node].downstream_edge.map(|next| &self.edges[next])
+        })
+        .skip(1)
+    }
+
+    /// All edges draining into `edge`, depth-first
+    pub fn upstream(&self, edge: usize) -> UpstreamEdges<'_> {
+        UpstreamEdges {
+            network: self,
+            stack: self.nodes[self.edges[edge].from_node].upstream_edges.clone(),
+        }
+    }
+}
+
+/// Depth-first iterator over the edges upstream of an edge
+pub struct UpstreamEdges<'a> {
+    network: &'a StreamNetwork,
+    stack: Vec<usize>,
+}
+
+impl<'a> Iterator for UpstreamEdges<'a> {
+    type Item = &'a StreamEdge;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let edge = &self.network.edges[self.stack.pop()?];
+        self.stack.extend(&self.network.nodes[edge.from_node].upstream_edges);
+        Some(edge)
+    }
+}
+
+impl FlowModel {
+    /// Build the stream graph for the network above `threshold`.
+    /// Edges are the junction-to-junction links of `stream_links`; no sub-catchments are labelled.
+    pub fn build_stream_network(&self, threshold: StreamThreshold) -> StreamNetwork {
+        let orders = self.compute_stream_orders(threshold);
+        let links = &orders.links;
+        let width = self.dem.width;
+        let cell_area = self.dem.cell_area();
+        let contaminated = self.edge_contamination();
+
+        // One head node per link: a source, or the confluence where its upstream links join
+        let mut nodes: Vec<StreamNode> = links.iter()
+            .enumerate()
+            .map(|(i, link)| StreamNode {
+                id: i,
+                cell: link.cells[0],
+                kind: if link.upstream_links.is_empty() { NodeKind::Source } else { NodeKind::Confluence },
+                upstream_edges: link.upstream_links.iter().map(|&id| id as usize - 1).collect(),
+                downstream_edge: Some(i),
+            })
+            .collect();
+
+        let mut edges = Vec::with_capacity(links.len());
+        for (i, link) in links.iter().enumerate() {
+            let to_node = match link.downstream_link {
+                Some(downstream) => downstream as usize - 1,
+                None => {
+                    nodes.push(StreamNode {
+                        id: nodes.len(),
+                        cell: *link.cells.last().unwrap(),
+                        kind: NodeKind::Outlet,
+                        upstream_edges: vec![i],
+                        downstream_edge: None,
+                    });
+                    nodes.len() - 1
+                }
+            };
+
+            // Geometry runs from the head to the downstream node
+            let mut path = link.cells.clone();
+            if link.downstream_link.is_some() {
+                path.push(nodes[to_node].cell);
+            }
+
+            let length_m: f64 = path.windows(2)
+                .map(|pair| {
+                    let dx = (pair[1].0 as f64 - pair[0].0 as f64) * self.dem.x_resolution;
+                    let dy = (pair[1].1 as f64 - pair[0].1 as f64) * self.dem.y_resolution;
+                    dx.hypot(dy)
+                })
+                .sum();
+            let (first, last) = (path[0], *path.last().unwrap());
+            let straight_m = ((last.0 as f64 - first.0 as f64) * self.dem.x_resolution)
+                .hypot((last.1 as f64 - first.1 as f64) * self.dem.y_resolution);
+            let drop_m = match (self.dem.get_elevation(first.0, first.1), self.dem.get_elevation(last.0, last.1)) {
+                (Some(top), Some(bottom)) => top - bottom,
+                _ => 0.0,
+            };
+
+            let &(end_x, end_y) = link.cells.last().unwrap();
+            edges.push(StreamEdge {
+                id: i,
+                from_node: i,
+                to_node,
+                cells: link.cells.clone(),
+                length_m,
+                drop_m,
+                mean_slope: if length_m > 0.0 { drop_m / length_m as f32 } else { 0.0 },
+                sinuosity: if straight_m > 0.0 { length_m / straight_m } else { 1.0 },
+                upstream_area_m2: self.flow_accumulation[end_y * width + end_x] as f64 * cell_area,
+                strahler_order: orders.link_orders[i].strahler,
+                shreve_magnitude: orders.link_orders[i].shreve,
+                contaminated: contaminated[end_y * width + end_x],
+            });
+        }
+
+        println!("Built stream network with {} nodes and {} edges", nodes.len(), edges.len());
+        StreamNetwork { nodes, edges }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::stream_tree;
+
+    #[test]
+    fn nodes_and_edges_of_a_hand_built_tree() {
+        let network = stream_tree().build_stream_network(StreamThreshold::Cells(1.0));
+        let nodes: Vec<((usize, usize), NodeKind)> = network.nodes.iter().map(|node| (node.cell, node.kind)).collect();
+        assert_eq!(nodes, vec![
+            ((1, 0), NodeKind::Source),
+            ((5, 0), NodeKind::Source),
+            ((3, 1), NodeKind::Source),
+            ((2, 3), NodeKind::Confluence),
+            ((5, 4), NodeKind::Confluence),
+            ((6, 4), NodeKind::Outlet),
+        ]);
+        assert_eq!(network.nodes[5].downstream_edge, None);
+
+        let to_nodes: Vec<usize> = network.edges.iter().map(|edge| edge.to_node).collect();
+        assert_eq!(to_nodes, vec![3, 4, 3, 4, 5]);
+        let strahler: Vec<u32> = network.edges.iter().map(|edge| edge.strahler_order).collect();
+        assert_eq!(strahler, vec![1, 1, 1, 2, 2]);
+        let shreve: Vec<u32> = network.edges.iter().map(|edge| edge.shreve_magnitude).collect();
+        assert_eq!(shreve, vec![1, 1, 1, 2, 3]);
+
+        // A runs two cells south and one diagonal step into the confluence
+        let a = &network.edges[0];
+        assert!((a.length_m - (20.0 + 10.0 * 2.0_f64.sqrt())).abs() < 1e-9);
+        assert_eq!(a.drop_m, 7.0);
+        assert!((a.sinuosity - a.length_m / 10.0_f64.hypot(30.0)).abs() < 1e-9);
+
+        // The outlet edge ends on its own last cell
+        let outlet = &network.edges[4];
+        assert_eq!(outlet.cells, vec![(5, 4), (6, 4)]);
+        assert_eq!(outlet.length_m, 10.0);
+        assert_eq!(outlet.upstream_area_m2, 1500.0);
+    }
+
+    #[test]
+    fn traversal_follows_the_graph() {
+        let network = stream_tree().build_stream_network(StreamThreshold::Cells(1.0));
+        let downstream: Vec<usize> = network.downstream(2).map(|edge| edge.id).collect();
+        assert_eq!(downstream, vec![3, 4]);
+        assert_eq!(network.downstream(4).count(), 0);
+
+        let mut upstream: Vec<usize> = network.upstream(4).map(|edge| edge.id).collect();
+        upstream.sort();
+        assert_eq!(upstream, vec![0, 1, 2, 3]);
+        assert_eq!(network.upstream(0).count(), 0);
+    }
+
+    #[test]
+    fn polylines_share_confluences_and_cover_every_cell_once() {
+        let model = stream_tree();
+        let network = model.build_stream_network(StreamThreshold::Cells(1.0));
+        let polylines = network.polylines();
+        assert_eq!(polylines, model.extract_high_quality_streams(StreamThreshold::Cells(1.0)));
+        assert_eq!(polylines[2], vec![(3, 1), (3, 2), (2, 3)]);
+
+        let mut cells: Vec<(usize, usize)> = network.edges.iter().flat_map(|edge| edge.cells.clone()).collect();
+        cells.sort();
+        cells.dedup();
+        assert_eq!(cells.len(), 15);
+        assert_eq!(network.edges.iter().map(|edge| edge.cells.len()).sum::<usize>(), 15);
+    }
+}
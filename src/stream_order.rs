@@ -0,0 +1,225 @@
+use crate::flow::{FlowModel, StreamThreshold};
+use crate::subcatchments::{link_polyline, StreamLink};
+use serde::{Serialize, Deserialize};
+
+/// Stream cells of one link or stream from upstream to downstream
+pub type Polyline = Vec<(usize, usize)>;
+
+/// Stream ordering scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderKind {
+    Strahler,  // +1 where two streams of equal order meet
+    Shreve,    // Sum of upstream magnitudes (number of sources)
+    Horton,    // Strahler order carried up the main stem to its source
+    Hack,      // 1 for the main river, +1 for each tributary level
+}
+
+impl OrderKind {
+    /// Parse an ordering name ("strahler", "shreve", "horton", "hack"), defaulting to Strahler
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "shreve" => OrderKind::Shreve,
+            "horton" => OrderKind::Horton,
+            "hack" => OrderKind::Hack,
+            _ => OrderKind::Strahler,
+        }
+    }
+}
+
+/// All stream orders of one link
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LinkOrders {
+    pub strahler: u32,
+    pub shreve: u32,
+    pub horton: u32,
+    pub hack: u32,
+}
+
+impl LinkOrders {
+    pub fn get(&self, kind: OrderKind) -> u32 {
+        match kind {
+            OrderKind::Strahler => self.strahler,
+            OrderKind::Shreve => self.shreve,
+            OrderKind::Horton => self.horton,
+            OrderKind::Hack => self.hack,
+        }
+    }
+}
+
+/// Stream orders per link (polyline) and per stream cell
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamOrders {
+    /// Stream links the orders refer to (without sub-catchment areas, see `stream_links`)
+    pub links: Vec<StreamLink>,
+    /// Orders of `links[i]`
+    pub link_orders: Vec<LinkOrders>,
+    /// Index into `links` for every stream cell, None elsewhere
+    pub cell_links: Vec<Option<usize>>,
+}
+
+impl StreamOrders {
+    /// Order of every cell (0 for cells that are not part of the stream network)
+    pub fn cell_orders(&self, kind: OrderKind) -> Vec<u32> {
+        self.cell_links.iter()
+            .map(|link| link.map_or(0, |i| self.link_orders[i].get(kind)))
+            .collect()
+    }
+
+    /// Polyline of every link, extended to the junction cell of its downstream link so
+    /// the drawn network stays connected, paired with the link's order
+    pub fn polylines(&self, kind: OrderKind) -> Vec<(Polyline, u32)> {
+        self.link_orders.iter()
+            .enumerate()
+            .map(|(i, orders)| (link_polyline(&self.links, i), orders.get(kind)))
+            .collect()
+    }
+
+    /// Strahler streams: runs of links of one Strahler order, from the link where the order
+    /// starts down to the last link before a higher order. Returns (order, first link, last
+    /// link) with indices into `links`.
+    pub fn strahler_streams(&self) -> Vec<(u32, usize, usize)> {
+        let order_of = |id: u32| self.link_orders[id as usize - 1].strahler;
+        (0..self.links.len())
+            .filter(|&i| self.links[i].upstream_links.iter().all(|&id| order_of(id) != self.link_orders[i].strahler))
+            .map(|first| {
+                let order = self.link_orders[first].strahler;
+                let mut last = first;
+                while let Some(id) = self.links[last].downstream_link.filter(|&id| order_of(id) == order) {
+                    last = id as usize - 1;
+                }
+                (order, first, last)
+            })
+            .collect()
+    }
+
+    /// Polylines grouped by order, highest order (main rivers) first
+    pub fn hierarchy(&self, kind: OrderKind) -> Vec<(Vec<Polyline>, u32)> {
+        let mut levels: Vec<(Vec<Polyline>, u32)> = Vec::new();
+        for (polyline, order) in self.polylines(kind) {
+            match levels.iter_mut().find(|(_, level_order)| *level_order == order) {
+                Some((polylines, _)) => polylines.push(polyline),
+                None => levels.push((vec![polyline], order)),
+            }
+        }
+        levels.sort_by_key(|&(_, order)| std::cmp::Reverse(order));
+        levels
+    }
+}
+
+impl FlowModel {
+    /// Compute Strahler, Shreve, Horton and Hack orders on the single-direction stream network.
+    ///
+    /// At every junction the upstream link with the largest flow accumulation is taken
+    /// as the main stem (used by Horton and Hack ordering).
+    pub fn compute_stream_orders(&self, threshold: StreamThreshold) -> StreamOrders {
+        let width = self.dem.width;
+        let links = self.stream_links(threshold).links;
+        let link_count = links.len();
+
+        // Links upstream-first: every link comes after all of its upstream links
+        let mut upstream_order = Vec::with_capacity(link_count);
+        let mut stack: Vec<(usize, bool)> = links.iter()
+            .filter(|link| link.downstream_link.is_none())
+            .map(|link| (link.id as usize - 1, false))
+            .collect();
+        while let Some((i, expanded)) = stack.pop() {
+            if expanded {
+                upstream_order.push(i);
+            } else {
+                stack.push((i, true));
+                stack.extend(links[i].upstream_links.iter().map(|&id| (id as usize - 1, false)));
+            }
+        }
+
+        let mut orders = vec![LinkOrders { strahler: 0, shreve: 0, horton: 0, hack: 0 }; link_count];
+
+        // Strahler and Shreve, upstream-first
+        for &i in &upstream_order {
+            let upstream = &links[i].upstream_links;
+            if upstream.is_empty() {
+                orders[i].strahler = 1;
+                orders[i].shreve = 1;
+                continue;
+            }
+
+            let max_order = upstream.iter().map(|&id| orders[id as usize - 1].strahler).max().unwrap_or(1);
+            let max_count = upstream.iter().filter(|&&id| orders[id as usize - 1].strahler == max_order).count();
+            orders[i].strahler = if max_count >= 2 { max_order + 1 } else { max_order };
+            orders[i].shreve = upstream.iter().map(|&id| orders[id as usize - 1].shreve).sum();
+        }
+
+        // Horton and Hack, downstream-first along main stems
+        let end_accumulation = |link: &StreamLink| {
+            let &(x, y) = link.cells.last().unwrap();
+            self.flow_accumulation[y * width + x]
+        };
+        for &i in upstream_order.iter().rev() {
+            if links[i].downstream_link.is_none() {
+                orders[i].horton = orders[i].strahler;
+                orders[i].hack = 1;
+            }
+
+            let main_stem = links[i].upstream_links.iter()
+                .map(|&id| id as usize - 1)
+                .max_by(|&a, &b| end_accumulation(&links[a]).total_cmp(&end_accumulation(&links[b])));
+            for &id in &links[i].upstream_links {
+                let j = id as usize - 1;
+                if Some(j) == main_stem {
+                    orders[j].horton = orders[i].horton;
+                    orders[j].hack = orders[i].hack;
+                } else {
+                    orders[j].horton = orders[j].strahler;
+                    orders[j].hack = orders[i].hack + 1;
+                }
+            }
+        }
+
+        let mut cell_links = vec![None; width * self.dem.height];
+        for (i, link) in links.iter().enumerate() {
+            for &(x, y) in &link.cells {
+                cell_links[y * width + x] = Some(i);
+            }
+        }
+
+        println!("Computed stream orders for {} links (max Strahler order {})",
+                 link_count, orders.iter().map(|o| o.strahler).max().unwrap_or(0));
+
+        StreamOrders { links, link_orders: orders, cell_links }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::stream_tree;
+
+    #[test]
+    fn orders_of_a_hand_built_tree() {
+        let orders = stream_tree().compute_stream_orders(StreamThreshold::Cells(1.0));
+        let heads: Vec<(usize, usize)> = orders.links.iter().map(|link| link.cells[0]).collect();
+        assert_eq!(heads, vec![(1, 0), (5, 0), (3, 1), (2, 3), (5, 4)]);
+
+        let by_kind = |kind: OrderKind| orders.link_orders.iter().map(|o| o.get(kind)).collect::<Vec<_>>();
+        assert_eq!(by_kind(OrderKind::Strahler), vec![1, 1, 1, 2, 2]);
+        assert_eq!(by_kind(OrderKind::Shreve), vec![1, 1, 1, 2, 3]);
+        // A drains more than B and the A-B stem more than C, so they are the main stems
+        assert_eq!(by_kind(OrderKind::Horton), vec![2, 1, 1, 2, 2]);
+        assert_eq!(by_kind(OrderKind::Hack), vec![1, 2, 2, 1, 1]);
+
+        assert_eq!(orders.strahler_streams(), vec![(1, 0, 0), (1, 1, 1), (1, 2, 2), (2, 3, 4)]);
+        assert_eq!(orders.cell_orders(OrderKind::Shreve)[4 * 7 + 6], 3);
+        assert_eq!(orders.cell_orders(OrderKind::Shreve)[0], 0);
+    }
+
+    #[test]
+    fn hierarchy_groups_polylines_by_order() {
+        let orders = stream_tree().compute_stream_orders(StreamThreshold::Cells(1.0));
+        let hierarchy = orders.hierarchy(OrderKind::Strahler);
+        let levels: Vec<(usize, u32)> = hierarchy.iter().map(|(polylines, order)| (polylines.len(), *order)).collect();
+        assert_eq!(levels, vec![(2, 2), (3, 1)]);
+
+        // Polylines run on to the first cell of their downstream link
+        assert_eq!(hierarchy[1].0[2], vec![(3, 1), (3, 2), (2, 3)]);
+        assert_eq!(hierarchy[0].0[1], vec![(5, 4), (6, 4)]);
+    }
+}
@@ -0,0 +1,291 @@
+use crate::dem::DigitalElevationModel;
+use crate::flow::{FlowModel, StreamThreshold};
+use crate::polygon::Polygon;
+use crate::traversal::PathWalker;
+use crate::watershed;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+/// Link id used for cells that never reach a stream (and for nodata cells)
+pub const NO_LINK: u32 = 0;
+
+/// Stretch of channel between two junctions (or a source and a junction/outlet)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamLink {
+    /// 1-based id as stored in `Subcatchments::labels`
+    pub id: u32,
+    /// Channel cells from upstream to downstream
+    pub cells: Vec<(usize, usize)>,
+    /// Link this one flows into, None where the channel leaves the network
+    pub downstream_link: Option<u32>,
+    /// Links joining at the head of this one (empty for first-order links)
+    pub upstream_links: Vec<u32>,
+    /// Number of cells in the link's local sub-catchment
+    pub cell_count: usize,
+    /// Local sub-catchment area in m²
+    pub area_m2: f64,
+}
+
+/// Stream links plus, for every cell, the link it first drains into
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subcatchments {
+    /// Link id per cell (`NO_LINK` for cells that do not reach a stream)
+    pub labels: Vec<u32>,
+    pub links: Vec<StreamLink>,
+}
+
+impl FlowModel {
+    /// Split the stream network into links at every junction and delineate the local
+    /// sub-catchment draining directly into each link.
+    ///
+    /// Streams are the cells returned by `extract_stream_network`; links and labels
+    /// follow the single downstream path of each cell (the dominant receiver for D∞/MFD).
+    pub fn delineate_subcatchments(&self, threshold: StreamThreshold) -> Subcatchments {
+        let width = self.dem.width;
+        let cell_count = width * self.dem.height;
+        let Subcatchments { mut labels, mut links } = self.stream_links(threshold);
+        let downstream = |idx: usize| {
+            self.get_downstream_cell(idx % width, idx / width).map(|(dx, dy)| dy * width + dx)
+        };
+
+        // Label the remaining cells with the link they first drain into (with path compression)
+        let mut walker = PathWalker::new(cell_count);
+        for idx in (0..cell_count).filter(|&idx| labels[idx] != NO_LINK) {
+            walker.resolve(idx);
+        }
+        let mut path = Vec::new();
+        for start in 0..cell_count {
+            if walker.is_resolved(start) || self.dem.get_elevation(start % width, start / width).is_none() {
+                continue;
+            }
+
+            let id = walker.walk(start, downstream, &mut path).map_or(NO_LINK, |joined| labels[joined]);
+            for cell in path.drain(..) {
+                labels[cell] = id;
+            }
+        }
+
+        let cell_area = self.dem.cell_area();
+        for &id in labels.iter().filter(|&&id| id != NO_LINK) {
+            let link = &mut links[(id - 1) as usize];
+            link.cell_count += 1;
+            link.area_m2 += cell_area;
+        }
+
+        println!("Delineated {} stream links and their sub-catchments", links.len());
+        Subcatchments { labels, links }
+    }
+
+    /// Split the stream network into links at every junction without delineating their
+    /// sub-catchments: only channel cells are labelled and the link areas are left at 0
+    pub fn stream_links(&self, threshold: StreamThreshold) -> Subcatchments {
+        let width = self.dem.width;
+        let cell_count = width * self.dem.height;
+
+        let mut is_stream = vec![false; cell_count];
+        for (x, y) in self.extract_stream_network(threshold) {
+            is_stream[y * width + x] = true;
+        }
+        let downstream = |idx: usize| {
+            self.get_downstream_cell(idx % width, idx / width).map(|(dx, dy)| dy * width + dx)
+        };
+
+        // Count stream cells flowing into each stream cell; heads have none, junctions two or more
+        let mut stream_inflow = vec![0u8; cell_count];
+        for idx in (0..cell_count).filter(|&idx| is_stream[idx]) {
+            if let Some(down_idx) = downstream(idx).filter(|&d| is_stream[d]) {
+                stream_inflow[down_idx] = stream_inflow[down_idx].saturating_add(1);
+            }
+        }
+
+        // Trace every link from its head down to the next junction
+        let mut labels = vec![NO_LINK; cell_count];
+        let mut links = Vec::new();
+        let mut link_ends = Vec::new();
+        for start in (0..cell_count).filter(|&idx| is_stream[idx] && stream_inflow[idx] != 1) {
+            let id = links.len() as u32 + 1;
+            let mut cells = Vec::new();
+            let mut idx = start;
+            loop {
+                labels[idx] = id;
+                cells.push((idx % width, idx / width));
+                match downstream(idx) {
+                    Some(next) if is_stream[next] && stream_inflow[next] == 1 => idx = next,
+                    _ => break,
+                }
+            }
+
+            link_ends.push(idx);
+            links.push(StreamLink {
+                id,
+                cells,
+                downstream_link: None,
+                upstream_links: Vec::new(),
+                cell_count: 0,
+                area_m2: 0.0,
+            });
+        }
+
+        // Link topology
+        for i in 0..links.len() {
+            if let Some(next) = downstream(link_ends[i]).filter(|&d| is_stream[d]) {
+                let downstream_id = labels[next];
+                links[i].downstream_link = Some(downstream_id);
+                let id = links[i].id;
+                links[(downstream_id - 1) as usize].upstream_links.push(id);
+            }
+        }
+
+        Subcatchments { labels, links }
+    }
+}
+
+impl Subcatchments {
+    /// Sub-catchment outlines as a GeoJSON FeatureCollection in the DEM's coordinates.
+    /// Each feature is a MultiPolygon with the link's id, topology and area as properties.
+    pub fn to_geojson(&self, dem: &DigitalElevationModel) -> Value {
+        let polygons = watershed::vectorize_labels(dem, &self.labels, self.links.len());
+
+        let features: Vec<Value> = self.links.iter()
+            .zip(polygons)
+            .map(|(link, link_polygons)| json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "MultiPolygon",
+                    "coordinates": link_polygons.iter().map(polygon_coordinates).collect::<Vec<_>>(),
+                },
+                "properties": {
+                    "id": link.id,
+                    "downstream_link": link.downstream_link,
+                    "upstream_links": link.upstream_links,
+                    "cell_count": link.cell_count,
+                    "area_m2": link.area_m2,
+                },
+            }))
+            .collect();
+
+        json!({ "type": "FeatureCollection", "features": features })
+    }
+}
+
+/// Cells of link `i` plus the first cell of its downstream link, so drawn links meet at
+/// shared confluence points
+pub fn link_polyline(links: &[StreamLink], i: usize) -> Vec<(usize, usize)> {
+    let mut polyline = links[i].cells.clone();
+    if let Some(downstream) = links[i].downstream_link {
+        polyline.push(links[(downstream - 1) as usize].cells[0]);
+    }
+    polyline
+}
+
+/// GeoJSON polygon coordinates: closed rings, exterior first. RFC 7946 wants exteriors
+/// counterclockwise and holes clockwise in world coordinates, which depends on the sign of
+/// the pixel height, so every ring is oriented by its signed area.
+fn polygon_coordinates(polygon: &Polygon) -> Vec<Vec<[f64; 2]>> {
+    std::iter::once((&polygon.exterior, true))
+        .chain(polygon.holes.iter().map(|hole| (hole, false)))
+        .map(|(ring, counterclockwise)| {
+            let mut coordinates: Vec<[f64; 2]> = ring.iter().map(|&(x, y)| [x, y]).collect();
+            if (signed_area(&coordinates) > 0.0) != counterclockwise {
+                coordinates.reverse();
+            }
+            if let Some(&first) = coordinates.first() {
+                coordinates.push(first);
+            }
+            coordinates
+        })
+        .collect()
+}
+
+/// Shoelace area of an open ring, positive when counterclockwise with y pointing up
+fn signed_area(ring: &[[f64; 2]]) -> f64 {
+    let n = ring.len();
+    (0..n)
+        .map(|i| {
+            let (a, b) = (ring[i], ring[(i + 1) % n]);
+            a[0] * b[1] - b[0] * a[1]
+        })
+        .sum::<f64>() / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::FlowDirection;
+
+    /// 5x4 flat grid with hand-set directions: tributaries down columns 1 and 3 join at (2, 3)
+    fn confluence() -> FlowModel {
+        use FlowDirection::*;
+        let mut model = FlowModel::new(DigitalElevationModel::new(5, 4, 10.0, vec![10.0; 20]));
+        model.flow_directions = vec![
+            East, South, West, South, West,
+            East, South, West, South, West,
+            East, Southeast, West, Southwest, West,
+            East, East, NoFlow, West, West,
+        ];
+        model.compute_flow_accumulation();
+        model
+    }
+
+    #[test]
+    fn links_split_at_the_junction() {
+        let subcatchments = confluence().delineate_subcatchments(StreamThreshold::Cells(3.0));
+        let links: Vec<_> = subcatchments.links.iter()
+            .map(|link| (link.cells.clone(), link.downstream_link, link.upstream_links.clone(), link.cell_count))
+            .collect();
+        assert_eq!(links, vec![
+            (vec![(1, 0), (1, 1), (1, 2)], Some(3), vec![], 9),
+            (vec![(3, 1), (3, 2)], Some(3), vec![], 6),
+            (vec![(2, 3)], None, vec![1, 2], 5),
+        ]);
+        assert_eq!(subcatchments.labels, vec![
+            1, 1, 1, 2, 2,
+            1, 1, 1, 2, 2,
+            1, 1, 1, 2, 2,
+            3, 3, 3, 3, 3,
+        ]);
+        assert_eq!(subcatchments.links[2].area_m2, 500.0);
+    }
+
+    #[test]
+    fn cyclic_directions_do_not_hang_labelling() {
+        let mut model = confluence();
+        model.flow_directions[0] = FlowDirection::South;
+        model.flow_directions[5] = FlowDirection::North;
+        let subcatchments = model.delineate_subcatchments(StreamThreshold::Cells(3.0));
+        assert_eq!((subcatchments.labels[0], subcatchments.labels[5]), (NO_LINK, NO_LINK));
+        assert_eq!(subcatchments.labels[10], 1);
+    }
+
+    #[test]
+    fn geojson_rings_follow_the_right_hand_rule() {
+        // Link 1 surrounds link 2, so its polygon has a hole
+        let labels: Vec<u32> = (0..25).map(|idx| if idx == 12 { 2 } else { 1 }).collect();
+        let link = |id: u32| StreamLink {
+            id,
+            cells: Vec::new(),
+            downstream_link: None,
+            upstream_links: Vec::new(),
+            cell_count: 0,
+            area_m2: 0.0,
+        };
+        let subcatchments = Subcatchments { labels, links: vec![link(1), link(2)] };
+
+        let mut north_up = DigitalElevationModel::new(5, 5, 10.0, vec![10.0; 25]);
+        north_up.geo_transform = [1000.0, 10.0, 0.0, 2000.0, 0.0, -10.0];
+        for dem in [DigitalElevationModel::new(5, 5, 10.0, vec![10.0; 25]), north_up] {
+            let geojson = subcatchments.to_geojson(&dem);
+            let rings = |feature: usize| -> Vec<Vec<[f64; 2]>> {
+                serde_json::from_value(geojson["features"][feature]["geometry"]["coordinates"][0].clone()).unwrap()
+            };
+            let area = |ring: &[[f64; 2]]| signed_area(&ring[..ring.len() - 1]);
+
+            let outer = rings(0);
+            assert_eq!(outer.len(), 2);
+            assert_eq!(outer[0].first(), outer[0].last());
+            assert_eq!(area(&outer[0]), 2500.0, "exterior must be counterclockwise");
+            assert_eq!(area(&outer[1]), -100.0, "hole must be clockwise");
+            assert_eq!(area(&rings(1)[0]), 100.0);
+        }
+    }
+}
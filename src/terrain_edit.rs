@@ -0,0 +1,521 @@
+// Elevation edits with incremental flow recomputation.
+//
+// An edit changes the raw (untreated) elevations of a rectangle or brush of cells. Instead
+// of treating sinks, routing and accumulating the whole grid again, only what can change
+// is recomputed:
+//
+//  - sink filling: lowered cells spread their lower fill level outwards, raised cells
+//    refill the area that drained through them below their new elevation, seeded from
+//    the unchanged fill levels around it
+//  - flow directions of the cells whose filled elevation changed, plus a one-cell halo
+//  - flow accumulation of those cells and everything downstream of them
+//
+// Priority-flood filling gives every cell the lowest level at which water can leave it
+// towards the DEM edge. That level is unique, so the incremental result is identical to a
+// full recompute.
+
+use crate::dem::{DigitalElevationModel, SinkTreatmentMethod};
+use crate::flow::FlowModel;
+use crate::neighborhood::{Connectivity, Neighborhood};
+use serde::{Serialize, Deserialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+
+/// Cells an elevation edit applies to, in grid coordinates
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum EditRegion {
+    /// Cells x_min..=x_max, y_min..=y_max
+    Rectangle { x_min: usize, y_min: usize, x_max: usize, y_max: usize },
+    /// Cells whose centres lie within `radius` cells of (x, y)
+    Brush { x: usize, y: usize, radius: f64 },
+}
+
+/// Change applied to every cell of an edit region
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum EditOperation {
+    /// Set the elevation
+    Set(f32),
+    /// Add to the elevation (negative amounts lower it)
+    Raise(f32),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ElevationEdit {
+    pub region: EditRegion,
+    pub operation: EditOperation,
+}
+
+/// What an incremental update recomputed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EditUpdate {
+    /// Cells whose raw elevation changed
+    pub edited_cell_count: usize,
+    /// Cells whose sink-treated elevation was recomputed
+    pub refilled_cell_count: usize,
+    /// Cells whose flow direction was recomputed (refilled cells plus halo)
+    pub direction_cell_count: usize,
+    /// Cells whose flow accumulation was recomputed
+    pub accumulation_cell_count: usize,
+    /// Bounding box (x_min, y_min, x_max, y_max) of all recomputed cells, None if nothing changed
+    pub bounds: Option<(usize, usize, usize, usize)>,
+    /// True if the model was rebuilt from scratch; the counts then cover the whole grid
+    pub full_recompute: bool,
+}
+
+impl EditUpdate {
+    /// Update that recomputed every cell of a width × height grid
+    pub fn full(edited_cell_count: usize, width: usize, height: usize) -> Self {
+        let cell_count = width * height;
+        EditUpdate {
+            edited_cell_count,
+            refilled_cell_count: cell_count,
+            direction_cell_count: cell_count,
+            accumulation_cell_count: cell_count,
+            bounds: (cell_count > 0).then(|| (0, 0, width - 1, height - 1)),
+            full_recompute: true,
+        }
+    }
+}
+
+impl EditRegion {
+    /// Indices of the region's cells inside a width × height grid
+    pub fn cells(&self, width: usize, height: usize) -> Vec<usize> {
+        let (x_range, y_range) = match *self {
+            EditRegion::Rectangle { x_min, y_min, x_max, y_max } => {
+                (x_min..(x_max + 1).min(width), y_min..(y_max + 1).min(height))
+            }
+            EditRegion::Brush { x, y, radius } => {
+                let reach = radius.max(0.0) as usize;
+                (x.saturating_sub(reach)..(x + reach + 1).min(width), y.saturating_sub(reach)..(y + reach + 1).min(height))
+            }
+        };
+
+        y_range
+            .flat_map(|cy| x_range.clone().map(move |cx| (cx, cy)))
+            .filter(|&(cx, cy)| match *self {
+                EditRegion::Rectangle { .. } => true,
+                EditRegion::Brush { x, y, radius } => {
+                    (cx as f64 - x as f64).hypot(cy as f64 - y as f64) <= radius
+                }
+            })
+            .map(|(cx, cy)| cy * width + cx)
+            .collect()
+    }
+}
+
+impl EditOperation {
+    /// New elevation of a cell
+    pub fn apply(self, elevation: f32) -> f32 {
+        match self {
+            EditOperation::Set(value) => value,
+            EditOperation::Raise(amount) => elevation + amount,
+        }
+    }
+}
+
+/// Fill level of a queued cell, ordered by level
+struct Level(f32, usize);
+
+impl PartialEq for Level {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Level {}
+
+impl PartialOrd for Level {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Level {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+/// Raw elevations behind a flow model, kept so elevation edits can be applied incrementally
+pub struct TerrainEditor {
+    /// Elevations before sink treatment
+    raw: DigitalElevationModel,
+    sink_method: SinkTreatmentMethod,
+    connectivity: Connectivity,
+    /// Cells connected to the DEM edge through valid cells; sink filling leaves all others as they are
+    drains: Vec<bool>,
+}
+
+impl TerrainEditor {
+    /// Editor for a flow model built from `raw` treated with `sink_method` under `connectivity`
+    pub fn new(raw: DigitalElevationModel, sink_method: SinkTreatmentMethod, connectivity: Connectivity) -> Self {
+        let width = raw.width;
+        let hood = Neighborhood::new(width, raw.height, connectivity);
+        let is_valid = |idx: usize| raw.get_elevation(idx % width, idx / width).is_some();
+
+        let mut drains = vec![false; width * raw.height];
+        let mut queue: VecDeque<usize> = (0..drains.len())
+            .filter(|&idx| hood.is_edge(idx % width, idx / width) && is_valid(idx))
+            .collect();
+        for &idx in &queue {
+            drains[idx] = true;
+        }
+        while let Some(idx) = queue.pop_front() {
+            for (_, (nx, ny)) in hood.neighbors(idx % width, idx / width) {
+                let n_idx = ny * width + nx;
+                if !drains[n_idx] && is_valid(n_idx) {
+                    drains[n_idx] = true;
+                    queue.push_back(n_idx);
+                }
+            }
+        }
+
+        TerrainEditor { raw, sink_method, connectivity, drains }
+    }
+
+    /// Change the cell size of the raw elevations to match the edited flow model
+    pub fn set_cell_size(&mut self, x_resolution: f64, y_resolution: f64) {
+        self.raw.set_cell_size(x_resolution, y_resolution);
+    }
+
+    /// Apply an edit to the raw elevations only and return the changed cells with their
+    /// previous elevation. Nodata cells are left alone and results are clamped at 0,
+    /// since negative elevations count as nodata.
+    pub fn edit_raw(&mut self, edit: &ElevationEdit) -> Vec<(usize, f32)> {
+        let width = self.raw.width;
+        let mut edited = Vec::new();
+        for idx in edit.region.cells(width, self.raw.height) {
+            if let Some(elevation) = self.raw.get_elevation(idx % width, idx / width) {
+                let new_elevation = edit.operation.apply(elevation).max(0.0);
+                if new_elevation != elevation {
+                    self.raw.data[idx] = new_elevation;
+                    edited.push((idx, elevation));
+                }
+            }
+        }
+        edited
+    }
+
+    /// Raw elevations with the sink treatment applied to the whole grid
+    pub fn treated_elevations(&self) -> Vec<f32> {
+        let mut dem = self.raw.clone();
+        dem.process_sinks_with_connectivity(self.sink_method, self.connectivity);
+        dem.data
+    }
+
+    /// Apply an edit and bring `flow_model` up to date, recomputing only the sink treatment,
+    /// flow directions and accumulation the edit can change. The flow model must have been
+    /// built from this editor's elevations and must not contain lakes (their flattening
+    /// and routing are only computed in full).
+    pub fn apply(&mut self, flow_model: &mut FlowModel, edit: &ElevationEdit) -> Result<EditUpdate, String> {
+        let (width, height) = (self.raw.width, self.raw.height);
+        if (flow_model.dem.width, flow_model.dem.height) != (width, height) {
+            return Err(format!("Flow model is {}x{}, edited DEM is {}x{}",
+                               flow_model.dem.width, flow_model.dem.height, width, height));
+        }
+        if flow_model.connectivity != self.connectivity {
+            return Err(format!("Flow model routes with {:?}, sinks were treated with {:?}",
+                               flow_model.connectivity, self.connectivity));
+        }
+        if !flow_model.lakes.is_empty() {
+            return Err("Flow models with lakes must be recomputed in full after an edit".to_string());
+        }
+
+        let edited = self.edit_raw(edit);
+        let refilled = match self.sink_method {
+            SinkTreatmentMethod::CompletelyFill => self.refill(&mut flow_model.dem.data, &edited),
+            _ => {
+                // Only priority-flood filling is updated locally
+                let treated = self.treated_elevations();
+                let changed = (0..treated.len())
+                    .filter(|&idx| treated[idx].to_bits() != flow_model.dem.data[idx].to_bits())
+                    .collect();
+                flow_model.dem.data = treated;
+                changed
+            }
+        };
+
+        // Directions depend on the 3×3 window; accumulation changes at the redirected cells,
+        // at the neighbours they drained into before and downstream of both
+        let directions = dilate(&refilled, width, height);
+        flow_model.update_flow_directions(&directions);
+        let accumulated = flow_model.update_flow_accumulation(&dilate(&directions, width, height));
+
+        let bounds = directions.iter().chain(&accumulated).fold(None, |bounds, &idx| {
+            let (x, y) = (idx % width, idx / width);
+            Some(match bounds {
+                Some((x_min, y_min, x_max, y_max)) => (x.min(x_min), y.min(y_min), x.max(x_max), y.max(y_max)),
+                None => (x, y, x, y),
+            })
+        });
+        let update = EditUpdate {
+            edited_cell_count: edited.len(),
+            refilled_cell_count: refilled.len(),
+            direction_cell_count: directions.len(),
+            accumulation_cell_count: accumulated.len(),
+            bounds,
+            full_recompute: false,
+        };
+        println!("Applied elevation edit: {:?}", update);
+        Ok(update)
+    }
+
+    /// Update priority-flood fill levels in `filled` after the raw elevations of `edited`
+    /// (cell, previous elevation) changed. Returns the cells whose level changed.
+    fn refill(&self, filled: &mut [f32], edited: &[(usize, f32)]) -> Vec<usize> {
+        let width = self.raw.width;
+        let hood = Neighborhood::new(width, self.raw.height, self.connectivity);
+        let raw = |idx: usize| self.raw.data[idx];
+        let is_edge = |idx: usize| hood.is_edge(idx % width, idx / width);
+        let neighbors = |idx: usize| {
+            hood.neighbors(idx % width, idx / width)
+                .map(move |(_, (nx, ny))| ny * width + nx)
+                .filter(|&n_idx| self.raw.get_elevation(n_idx % width, n_idx / width).is_some())
+        };
+
+        // Level of every touched cell before the edit
+        let mut original: HashMap<usize, f32> = HashMap::new();
+        let mut set_level = |filled: &mut [f32], idx: usize, level: f32| {
+            original.entry(idx).or_insert(filled[idx]);
+            filled[idx] = level;
+        };
+
+        // Cells cut off from the edge are never filled
+        for &(idx, _) in edited.iter().filter(|&&(idx, _)| !self.drains[idx]) {
+            set_level(filled, idx, raw(idx));
+        }
+
+        // Lower first: lowered cells at their new and raised cells still at their old
+        // elevation. Levels only drop, spreading outwards from the lowered cells.
+        let previous: HashMap<usize, f32> = edited.iter().copied().collect();
+        let lowered = |idx: usize| previous.get(&idx).map_or(raw(idx), |&old| old.min(raw(idx)));
+        let mut queue = BinaryHeap::new();
+        for &(idx, _) in edited.iter().filter(|&&(idx, old)| self.drains[idx] && raw(idx) < old) {
+            let level = if is_edge(idx) {
+                raw(idx)
+            } else {
+                neighbors(idx).map(|n_idx| filled[n_idx]).fold(f32::INFINITY, f32::min).max(raw(idx))
+            };
+            if level < filled[idx] {
+                set_level(filled, idx, level);
+                queue.push(Reverse(Level(level, idx)));
+            }
+        }
+        while let Some(Reverse(Level(level, idx))) = queue.pop() {
+            if level > filled[idx] {
+                continue; // Superseded by a lower level
+            }
+            for n_idx in neighbors(idx).filter(|&n_idx| !is_edge(n_idx)) {
+                let n_level = lowered(n_idx).max(level);
+                if n_level < filled[n_idx] {
+                    set_level(filled, n_idx, n_level);
+                    queue.push(Reverse(Level(n_level, n_idx)));
+                }
+            }
+        }
+
+        // Then raise. A cell can only rise if it drained through a raised cell, i.e. it is
+        // reached from one without descending the fill surface, and only if it lies below
+        // the highest raised cell.
+        let raised: Vec<usize> = edited.iter()
+            .filter(|&&(idx, old)| self.drains[idx] && raw(idx) > old)
+            .map(|&(idx, _)| idx)
+            .collect();
+        let ceiling = raised.iter().map(|&idx| raw(idx)).fold(f32::NEG_INFINITY, f32::max);
+        let mut region: HashSet<usize> = raised.iter().copied().collect();
+        let mut stack = raised;
+        while let Some(idx) = stack.pop() {
+            for n_idx in neighbors(idx) {
+                if !is_edge(n_idx) && filled[n_idx] >= filled[idx] && filled[n_idx] < ceiling && region.insert(n_idx) {
+                    stack.push(n_idx);
+                }
+            }
+        }
+
+        // Refill the region by priority flood from the DEM edge and the levels around it
+        let mut closed = HashSet::new();
+        for &idx in &region {
+            if is_edge(idx) {
+                set_level(filled, idx, raw(idx));
+                closed.insert(idx);
+                queue.push(Reverse(Level(raw(idx), idx)));
+            }
+            for n_idx in neighbors(idx) {
+                if !region.contains(&n_idx) && closed.insert(n_idx) {
+                    queue.push(Reverse(Level(filled[n_idx], n_idx)));
+                }
+            }
+        }
+        while let Some(Reverse(Level(level, idx))) = queue.pop() {
+            for n_idx in neighbors(idx) {
+                if region.contains(&n_idx) && closed.insert(n_idx) {
+                    let n_level = raw(n_idx).max(level);
+                    set_level(filled, n_idx, n_level);
+                    queue.push(Reverse(Level(n_level, n_idx)));
+                }
+            }
+        }
+
+        let mut changed: Vec<usize> = original.into_iter()
+            .filter(|&(idx, level)| filled[idx].to_bits() != level.to_bits())
+            .map(|(idx, _)| idx)
+            .collect();
+        changed.sort_unstable();
+        changed
+    }
+}
+
+/// Cells plus their eight neighbours, sorted and without duplicates
+fn dilate(cells: &[usize], width: usize, height: usize) -> Vec<usize> {
+    let hood = Neighborhood::new(width, height, Connectivity::D8);
+    let mut dilated: Vec<usize> = cells.iter()
+        .flat_map(|&idx| std::iter::once(idx).chain((0..8).filter_map(move |dir| hood.neighbor_index(idx, dir))))
+        .collect();
+    dilated.sort_unstable();
+    dilated.dedup();
+    dilated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::FlowMethod;
+    use crate::lakes::Lake;
+    use crate::test_support::{ramp, routed};
+
+    /// Flow model of `raw` after filling its sinks, with the matching editor
+    fn edit_setup(raw: &DigitalElevationModel, method: FlowMethod) -> (FlowModel, TerrainEditor) {
+        let mut filled = raw.clone();
+        filled.fill_sinks();
+        let editor = TerrainEditor::new(raw.clone(), SinkTreatmentMethod::CompletelyFill, Connectivity::D8);
+        (routed(filled, method), editor)
+    }
+
+    fn assert_matches_full_recompute(model: &FlowModel, editor: &TerrainEditor, method: FlowMethod) {
+        let mut filled = model.dem.clone();
+        filled.data = editor.treated_elevations();
+        let full = routed(filled, method);
+        assert_eq!(model.dem.data, full.dem.data);
+        assert_eq!(model.flow_directions, full.flow_directions);
+        assert_eq!(model.flow_accumulation, full.flow_accumulation);
+    }
+
+    #[test]
+    fn elevation_edits_match_a_full_recompute() {
+        // Ramp with a wavy surface, so filling leaves several depressions
+        let mut raw = ramp(24, 18, 10.0, 10.0, 20.0_f32.to_radians(), 0.05);
+        for (idx, elevation) in raw.data.iter_mut().enumerate() {
+            *elevation += ((idx % 24) as f32 * 1.3).sin() * 2.0 + ((idx / 24) as f32 * 0.9).cos() * 2.0;
+        }
+        for method in [FlowMethod::D8, FlowMethod::DInf, FlowMethod::MFD] {
+            let (mut model, mut editor) = edit_setup(&raw, method);
+
+            let edits = [
+                ElevationEdit { region: EditRegion::Rectangle { x_min: 8, y_min: 3, x_max: 10, y_max: 14 }, operation: EditOperation::Raise(6.0) },
+                ElevationEdit { region: EditRegion::Brush { x: 15, y: 9, radius: 2.5 }, operation: EditOperation::Raise(-8.0) },
+                ElevationEdit { region: EditRegion::Brush { x: 9, y: 8, radius: 1.0 }, operation: EditOperation::Set(0.0) },
+            ];
+            for edit in &edits {
+                let update = editor.apply(&mut model, edit).unwrap();
+                assert!(update.edited_cell_count > 0 && !update.full_recompute);
+                assert_matches_full_recompute(&model, &editor, method);
+            }
+        }
+    }
+
+    #[test]
+    fn raising_and_lowering_a_rim_closes_and_drains_a_depression() {
+        // Eastward plane with a pit at (3, 3) behind a rim that is open to the east at (4, 3)
+        let mut raw = DigitalElevationModel::new(7, 7, 10.0, (0..49).map(|idx| 20.0 - (idx % 7) as f32).collect());
+        for y in 2..=4 {
+            for x in 2..=4 {
+                raw.data[y * 7 + x] = 30.0;
+            }
+        }
+        raw.data[3 * 7 + 3] = 5.0;
+        raw.data[3 * 7 + 4] = 16.0;
+        let (center, gap) = (3 * 7 + 3, 3 * 7 + 4);
+        let gap_edit = |operation| ElevationEdit {
+            region: EditRegion::Rectangle { x_min: 4, y_min: 3, x_max: 4, y_max: 3 },
+            operation,
+        };
+
+        for method in [FlowMethod::D8, FlowMethod::DInf, FlowMethod::MFD] {
+            let (mut model, mut editor) = edit_setup(&raw, method);
+            assert_eq!(model.dem.data[center], 16.0);
+
+            // Closing the gap raises the pit to the rim
+            let update = editor.apply(&mut model, &gap_edit(EditOperation::Raise(20.0))).unwrap();
+            assert!(update.refilled_cell_count >= 2);
+            assert_eq!(model.dem.data[center], 30.0);
+            assert_matches_full_recompute(&model, &editor, method);
+
+            // Lowering the rim cell again lets the closed depression drain through it
+            editor.apply(&mut model, &gap_edit(EditOperation::Set(16.0))).unwrap();
+            assert_eq!((model.dem.data[center], model.dem.data[gap]), (16.0, 16.0));
+            assert_matches_full_recompute(&model, &editor, method);
+        }
+    }
+
+    #[test]
+    fn regions_are_clipped_to_the_grid() {
+        let corner = EditRegion::Brush { x: 0, y: 0, radius: 1.0 };
+        assert_eq!(corner.cells(5, 4), vec![0, 1, 5]);
+        assert_eq!(EditRegion::Brush { x: 2, y: 2, radius: 0.0 }.cells(5, 4), vec![12]);
+        assert!(EditRegion::Brush { x: 2, y: 2, radius: -1.0 }.cells(5, 4).is_empty());
+
+        let overhanging = EditRegion::Rectangle { x_min: 3, y_min: 2, x_max: 9, y_max: 9 };
+        assert_eq!(overhanging.cells(5, 4), vec![13, 14, 18, 19]);
+        assert!(EditRegion::Rectangle { x_min: 6, y_min: 0, x_max: 9, y_max: 3 }.cells(5, 4).is_empty());
+    }
+
+    #[test]
+    fn raw_edits_clamp_at_zero_and_skip_nodata() {
+        let raw = DigitalElevationModel::new(3, 1, 10.0, vec![5.0, f32::NAN, 2.0]);
+        let mut editor = TerrainEditor::new(raw, SinkTreatmentMethod::CompletelyFill, Connectivity::D8);
+        let edit = ElevationEdit {
+            region: EditRegion::Rectangle { x_min: 0, y_min: 0, x_max: 2, y_max: 0 },
+            operation: EditOperation::Raise(-3.0),
+        };
+        assert_eq!(editor.edit_raw(&edit), vec![(0, 5.0), (2, 2.0)]);
+        let treated = editor.treated_elevations();
+        assert_eq!((treated[0], treated[2]), (2.0, 0.0));
+        assert!(treated[1].is_nan());
+
+        // Nothing left to lower
+        let edit = ElevationEdit { region: EditRegion::Brush { x: 2, y: 0, radius: 0.0 }, operation: EditOperation::Raise(-1.0) };
+        assert!(editor.edit_raw(&edit).is_empty());
+    }
+
+    #[test]
+    fn edits_that_change_nothing_recompute_nothing() {
+        let raw = ramp(6, 4, 10.0, 10.0, 0.0, 0.1);
+        let (mut model, mut editor) = edit_setup(&raw, FlowMethod::D8);
+        let accumulation = model.flow_accumulation.clone();
+        let edit = ElevationEdit { region: EditRegion::Brush { x: 2, y: 2, radius: 0.0 }, operation: EditOperation::Set(raw.data[14]) };
+        let update = editor.apply(&mut model, &edit).unwrap();
+        assert_eq!((update.edited_cell_count, update.accumulation_cell_count, update.bounds), (0, 0, None));
+        assert_eq!(model.flow_accumulation, accumulation);
+    }
+
+    #[test]
+    fn apply_rejects_models_it_cannot_update() {
+        let raw = ramp(6, 4, 10.0, 10.0, 0.0, 0.1);
+        let edit = ElevationEdit { region: EditRegion::Brush { x: 2, y: 2, radius: 1.0 }, operation: EditOperation::Raise(1.0) };
+        let mut editor = TerrainEditor::new(raw.clone(), SinkTreatmentMethod::CompletelyFill, Connectivity::D8);
+
+        let mut smaller = routed(ramp(5, 4, 10.0, 10.0, 0.0, 0.1), FlowMethod::D8);
+        assert!(editor.apply(&mut smaller, &edit).is_err());
+
+        let mut d4 = FlowModel::with_connectivity(raw.clone(), Connectivity::D4);
+        d4.compute_flow_directions();
+        assert!(editor.apply(&mut d4, &edit).is_err());
+
+        let mut with_lake = routed(raw.clone(), FlowMethod::D8);
+        with_lake.lakes.push(Lake { id: 1, surface_elevation: 0.0, cell_count: 1, outlet: None, detached_outlets: Vec::new() });
+        assert!(editor.apply(&mut with_lake, &edit).is_err());
+
+        // Rejected edits leave the raw elevations untouched
+        assert_eq!(editor.treated_elevations(), raw.data);
+    }
+}
@@ -0,0 +1,68 @@
+// Fixtures shared by the unit tests of several modules.
+
+use crate::dem::DigitalElevationModel;
+use crate::flow::{FlowDirection, FlowMethod, FlowModel, MfdOptions};
+
+/// Planar ramp falling towards `angle` (radians, 0 = east, clockwise on the map)
+pub fn ramp(width: usize, height: usize, x_res: f64, y_res: f64, angle: f32, gradient: f32) -> DigitalElevationModel {
+    let mut data = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let along = angle.cos() * (x as f64 * x_res) as f32 + angle.sin() * (y as f64 * y_res) as f32;
+            data.push(500.0 - gradient * along);
+        }
+    }
+    DigitalElevationModel::with_cell_size(width, height, x_res, y_res, data)
+}
+
+/// Flow model of `dem` with directions and accumulation computed by `method`
+pub fn routed(dem: DigitalElevationModel, method: FlowMethod) -> FlowModel {
+    let mut model = FlowModel::new(dem);
+    match method {
+        FlowMethod::D8 => model.compute_flow_directions(),
+        FlowMethod::DInf => model.compute_flow_directions_dinf().unwrap(),
+        FlowMethod::MFD => model.compute_flow_directions_mfd(MfdOptions::default()),
+    }
+    model.compute_flow_accumulation();
+    model
+}
+
+/// 21x30 V-shaped valley of 10 m cells along column 10, falling 0.5 m per row to the south
+/// and rising 3 m per column on both sides
+pub fn valley() -> FlowModel {
+    let data = (0..21 * 30)
+        .map(|idx| 100.0 + 3.0 * ((idx % 21) as f32 - 10.0).abs() + 0.5 * (29 - idx / 21) as f32)
+        .collect();
+    routed(DigitalElevationModel::new(21, 30, 10.0, data), FlowMethod::D8)
+}
+
+/// 2x1 grid of 10 m cells whose two cells drain into each other
+pub fn cyclic_pair() -> FlowModel {
+    let mut model = FlowModel::new(DigitalElevationModel::new(2, 1, 10.0, vec![10.0, 10.0]));
+    model.flow_directions = vec![FlowDirection::East, FlowDirection::West];
+    model
+}
+
+/// Hand-built network on a 7x5 grid where only the channel cells hold data:
+/// A (column 1) and B (column 3) join at (2, 3), C (column 5) joins below at (5, 4);
+/// elevations fall along every channel
+pub fn stream_tree() -> FlowModel {
+    use FlowDirection::*;
+    let channel = [
+        ((1, 0), South, 19.0), ((1, 1), South, 17.0), ((1, 2), Southeast, 15.0),
+        ((3, 1), South, 15.0), ((3, 2), Southwest, 13.0),
+        ((2, 3), South, 12.0), ((2, 4), East, 10.0), ((3, 4), East, 9.0), ((4, 4), East, 8.0),
+        ((5, 0), South, 15.0), ((5, 1), South, 13.0), ((5, 2), South, 11.0), ((5, 3), South, 9.0),
+        ((5, 4), East, 7.0), ((6, 4), NoFlow, 6.0),
+    ];
+    let mut data = vec![f32::NAN; 35];
+    let mut directions = vec![NoFlow; 35];
+    for ((x, y), direction, z) in channel {
+        data[y * 7 + x] = z;
+        directions[y * 7 + x] = direction;
+    }
+    let mut model = FlowModel::new(DigitalElevationModel::new(7, 5, 10.0, data));
+    model.flow_directions = directions;
+    model.compute_flow_accumulation();
+    model
+}
@@ -0,0 +1,124 @@
+use crate::flow::FlowModel;
+use crate::flow_length::FlowPath;
+use crate::polygon::Polygon;
+use crate::watershed::{self, BOUNDARY_FRACTION};
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+
+/// Everything draining through one cell
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamTrace {
+    /// Traced cell
+    pub cell: (usize, usize),
+    /// Bounding box of the contributing cells as (min_x, min_y, max_x, max_y), inclusive
+    pub bounds: (usize, usize, usize, usize),
+    /// Share (0-1) of each cell's flow passing through the traced cell, row by row over `bounds`
+    pub mask: Vec<f32>,
+    /// Number of cells that contribute any flow
+    pub cell_count: usize,
+    /// Contributing area in m², with every cell weighted by its share
+    pub area_m2: f64,
+    /// Outline in world coordinates of the cells sending at least half of their flow through the cell
+    pub boundary: Vec<Polygon>,
+}
+
+impl FlowModel {
+    /// Path from a cell to where its water leaves the grid or ends in a sink, with
+    /// cumulative distance and elevation. For D∞ and MFD the path follows the dominant
+    /// receiver. Only the cells on the path are visited.
+    pub fn trace_downstream(&self, x: usize, y: usize) -> FlowPath {
+        let width = self.dem.width;
+
+        // Stop where a malformed direction grid would lead back onto the path
+        let mut path = vec![y * width + x];
+        let mut on_path = HashSet::from([path[0]]);
+        while let Some(next) = self.downstream_index(*path.last().unwrap()).filter(|&next| on_path.insert(next)) {
+            path.push(next);
+        }
+
+        self.flow_path(&path)
+    }
+
+    /// Contributing area of a cell under the current flow method, cropped to its bounding box.
+    /// Only the upstream cells are visited and no grid-sized buffers are allocated.
+    pub fn trace_upstream(&self, x: usize, y: usize) -> UpstreamTrace {
+        let width = self.dem.width;
+        let (fractions, members) = self.upstream_area(y * width + x);
+
+        let mut bounds = (x, y, x, y);
+        for &idx in &members {
+            let (mx, my) = (idx % width, idx / width);
+            bounds = (bounds.0.min(mx), bounds.1.min(my), bounds.2.max(mx), bounds.3.max(my));
+        }
+
+        let (min_x, min_y, max_x, max_y) = bounds;
+        let mask = (min_y..=max_y)
+            .flat_map(|row| (min_x..=max_x).map(move |column| row * width + column))
+            .map(|idx| fractions.get(&idx).copied().unwrap_or(0.0))
+            .collect();
+
+        let cell_area = self.dem.cell_area();
+        let area_m2 = members.iter().map(|idx| fractions[idx] as f64 * cell_area).sum();
+        let boundary = watershed::vectorize_cells(&self.dem, &members, |idx| fractions.get(&idx).is_some_and(|&fraction| fraction >= BOUNDARY_FRACTION));
+
+        UpstreamTrace {
+            cell: (x, y),
+            bounds,
+            mask,
+            cell_count: members.len(),
+            area_m2,
+            boundary,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::flow::FlowMethod;
+    use crate::test_support::{cyclic_pair, ramp, routed};
+
+    #[test]
+    fn traces_follow_the_flow_graph() {
+        let model = routed(ramp(6, 4, 20.0, 10.0, 0.0, 0.1), FlowMethod::D8);
+
+        let down = model.trace_downstream(0, 1);
+        assert_eq!(down.cells.last(), Some(&(5, 1)));
+        assert_eq!(down.length_m, 100.0);
+        assert_eq!(down.profile.len(), 6);
+
+        let up = model.trace_upstream(5, 1);
+        assert_eq!(up.bounds, (0, 1, 5, 1));
+        assert_eq!(up.mask, vec![1.0; 6]);
+        assert_eq!((up.cell_count, up.area_m2), (6, 1200.0));
+        assert_eq!(up.boundary.len(), 1);
+    }
+
+    #[test]
+    fn traces_from_ridges_and_outlets_are_single_cells() {
+        let model = routed(ramp(6, 4, 20.0, 10.0, 0.0, 0.1), FlowMethod::D8);
+
+        let down = model.trace_downstream(5, 2);
+        assert_eq!(down.cells, vec![(5, 2)]);
+        assert_eq!(down.length_m, 0.0);
+
+        let up = model.trace_upstream(0, 2);
+        assert_eq!(up.bounds, (0, 2, 0, 2));
+        assert_eq!(up.mask, vec![1.0]);
+        assert_eq!((up.cell_count, up.area_m2), (1, 200.0));
+    }
+
+    #[test]
+    fn upstream_shares_split_between_mfd_receivers() {
+        let model = routed(ramp(5, 5, 10.0, 10.0, 45.0_f32.to_radians(), 0.1), FlowMethod::MFD);
+        let up = model.trace_upstream(4, 4);
+        assert!((up.mask[0] - 1.0).abs() < 1e-5);
+        assert!(up.mask.iter().any(|&share| share > 0.0 && share < 1.0));
+        assert!(up.area_m2 < up.cell_count as f64 * 100.0);
+    }
+
+    #[test]
+    fn downstream_traces_stop_on_cyclic_directions() {
+        let down = cyclic_pair().trace_downstream(0, 0);
+        assert_eq!(down.cells, vec![(0, 0), (1, 0)]);
+    }
+}
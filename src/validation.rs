@@ -0,0 +1,236 @@
+use crate::flow::{FlowMethod, FlowModel};
+use crate::flow_fields::NO_DIRECTION;
+use crate::neighborhood::{self, Connectivity, Neighborhood};
+use crate::traversal;
+use serde::{Serialize, Deserialize};
+
+/// Largest relative difference between routed-out and routed-in mass that still counts as conserved
+pub const MASS_BALANCE_TOLERANCE: f64 = 1.0e-3;
+
+/// Largest deviation from 1 of a cell's summed outflow shares
+const SHARE_TOLERANCE: f32 = 1.0e-3;
+
+/// Consistency report of a flow graph and its accumulation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidationReport {
+    /// Number of cells with elevation data
+    pub valid_cell_count: usize,
+    /// Cells on a flow cycle
+    pub cycle_cells: Vec<(usize, usize)>,
+    /// Cells downstream of a cycle that therefore never receive all of their inflow
+    pub blocked_cell_count: usize,
+    /// Cells sending flow into a nodata neighbour (allowed, e.g. along coasts, but reported)
+    pub nodata_inflow_cells: Vec<(usize, usize)>,
+    /// Cells whose receivers do not resolve to a direction or whose shares do not sum to 0 or 1
+    pub dangling_receiver_cells: Vec<(usize, usize)>,
+    /// Total weight entering the graph (the valid cell count for plain accumulation)
+    pub total_input: f64,
+    /// Total accumulation leaving the graph at terminal cells, the DEM edge and nodata
+    pub total_outflow: f64,
+    /// |outflow - input| / input
+    pub mass_balance_error: f64,
+}
+
+impl ValidationReport {
+    /// True if the graph is acyclic, all receivers resolve and mass is conserved.
+    /// Flow into nodata does not make a graph invalid.
+    pub fn is_valid(&self) -> bool {
+        self.cycle_cells.is_empty()
+            && self.dangling_receiver_cells.is_empty()
+            && self.mass_balance_error <= MASS_BALANCE_TOLERANCE
+    }
+
+    /// One line per problem found, empty for a valid graph
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.cycle_cells.is_empty() {
+            problems.push(format!("{} cells on flow cycles ({} cells blocked downstream)",
+                                  self.cycle_cells.len(), self.blocked_cell_count));
+        }
+        if !self.dangling_receiver_cells.is_empty() {
+            problems.push(format!("{} cells with dangling receivers", self.dangling_receiver_cells.len()));
+        }
+        if self.mass_balance_error > MASS_BALANCE_TOLERANCE {
+            problems.push(format!("Accumulation not conserved: {:.1} in, {:.1} out ({:.3}% error)",
+                                  self.total_input, self.total_outflow, self.mass_balance_error * 100.0));
+        }
+        problems
+    }
+}
+
+impl FlowModel {
+    /// Validate the flow graph and the stored flow accumulation (one unit per cell)
+    pub fn validate(&self) -> ValidationReport {
+        let weights = vec![1.0; self.dem.width * self.dem.height];
+        self.validate_accumulation(&self.flow_accumulation, &weights)
+    }
+
+    /// Validate the flow graph of the active method and an accumulation computed from `weights`.
+    ///
+    /// Mass is conserved if everything leaving the graph — at cells without receivers,
+    /// across the DEM edge and into nodata — adds up to the total weight of the valid cells.
+    pub fn validate_accumulation(&self, accumulation: &[f32], weights: &[f32]) -> ValidationReport {
+        let width = self.dem.width;
+        let cell_count = width * self.dem.height;
+        let hood = Neighborhood::new(width, self.dem.height, Connectivity::D8);
+        let is_valid = |idx: usize| self.dem.get_elevation(idx % width, idx / width).is_some();
+        let cell = |idx: usize| (idx % width, idx / width);
+
+        let mut nodata_inflow_cells = Vec::new();
+        let mut dangling_receiver_cells = Vec::new();
+        let mut total_input = 0.0;
+        let mut total_outflow = 0.0;
+        for idx in (0..cell_count).filter(|&idx| is_valid(idx)) {
+            total_input += weights[idx] as f64;
+
+            let mut share_sum = 0.0;
+            let mut kept_share = 0.0;
+            let mut into_nodata = false;
+            for dir in 0..8 {
+                let share = self.share_towards(idx, dir);
+                if share <= 0.0 {
+                    continue;
+                }
+                share_sum += share;
+                match hood.neighbor_index(idx, dir) {
+                    Some(n_idx) if is_valid(n_idx) => kept_share += share,
+                    Some(_) => into_nodata = true,
+                    None => {}
+                }
+            }
+
+            if into_nodata {
+                nodata_inflow_cells.push(cell(idx));
+            }
+            if (share_sum > 0.0 && (share_sum - 1.0).abs() > SHARE_TOLERANCE) || self.has_unresolved_receiver(idx) {
+                dangling_receiver_cells.push(cell(idx));
+            }
+            total_outflow += accumulation[idx] as f64 * (1.0 - kept_share as f64).max(0.0);
+        }
+
+        let (cycle_cells, blocked_cell_count) = self.find_cycles();
+        let mass_balance_error = if total_input > 0.0 {
+            (total_outflow - total_input).abs() / total_input
+        } else {
+            0.0
+        };
+
+        let report = ValidationReport {
+            valid_cell_count: (0..cell_count).filter(|&idx| is_valid(idx)).count(),
+            cycle_cells: cycle_cells.into_iter().map(cell).collect(),
+            blocked_cell_count,
+            nodata_inflow_cells,
+            dangling_receiver_cells,
+            total_input,
+            total_outflow,
+            mass_balance_error,
+        };
+        println!("Validated {:?} flow graph: {}", self.flow_method,
+                 if report.is_valid() { "ok".to_string() } else { report.problems().join("; ") });
+        report
+    }
+
+    /// Cells on cycles of the receiver graph, plus the number of cells only blocked by them
+    fn find_cycles(&self) -> (Vec<usize>, usize) {
+        let width = self.dem.width;
+        let cell_count = width * self.dem.height;
+        let receivers = |idx: usize| self.receivers(idx).map(|(downstream_idx, _)| downstream_idx);
+
+        // Everything that settles in topological order is acyclic
+        let (settled, _) = traversal::settle_topological(cell_count, |_| true, receivers, |_, _: &[bool]| true);
+        let mut unsettled: Vec<bool> = settled.iter().map(|&is_settled| !is_settled).collect();
+
+        // Peel unsettled cells whose receivers all settled or were peeled; the rest lie on cycles
+        let mut pending: Vec<u8> = (0..cell_count)
+            .map(|idx| if unsettled[idx] { receivers(idx).filter(|&d| unsettled[d]).count() as u8 } else { 0 })
+            .collect();
+        let mut peel: Vec<usize> = (0..cell_count).filter(|&idx| unsettled[idx] && pending[idx] == 0).collect();
+        let mut blocked_cell_count = 0;
+        while let Some(idx) = peel.pop() {
+            unsettled[idx] = false;
+            blocked_cell_count += 1;
+            for (upstream_idx, _) in self.donors(idx) {
+                if unsettled[upstream_idx] {
+                    pending[upstream_idx] -= 1;
+                    if pending[upstream_idx] == 0 {
+                        peel.push(upstream_idx);
+                    }
+                }
+            }
+        }
+
+        let cycle_cells: Vec<usize> = (0..cell_count).filter(|&idx| unsettled[idx]).collect();
+        (cycle_cells, blocked_cell_count)
+    }
+
+    /// Whether a D∞ cell stores a receiver that is not a direction index
+    fn has_unresolved_receiver(&self, idx: usize) -> bool {
+        match &self.dinf_flow {
+            Some(dinf) if self.flow_method == FlowMethod::DInf => {
+                [dinf.receiver_a[idx], dinf.receiver_b[idx]].iter()
+                    .any(|&dir| dir != NO_DIRECTION && dir as usize >= neighborhood::OFFSETS.len())
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::flow::{FlowDirection, FlowMethod};
+    use crate::test_support::{ramp, routed};
+
+    #[test]
+    fn validation_detects_cycles_and_lost_mass() {
+        let model = routed(ramp(6, 4, 10.0, 10.0, 30.0_f32.to_radians(), 0.1), FlowMethod::MFD);
+        let report = model.validate();
+        assert!(report.is_valid(), "{:?}", report.problems());
+        assert!((report.total_outflow - 24.0).abs() < 1e-3);
+
+        // Two cells draining into each other
+        let mut model = routed(ramp(6, 4, 10.0, 10.0, 30.0_f32.to_radians(), 0.1), FlowMethod::D8);
+        model.flow_directions[6 + 1] = FlowDirection::East;
+        model.flow_directions[6 + 2] = FlowDirection::West;
+        model.compute_flow_accumulation();
+        let report = model.validate();
+        assert_eq!(report.cycle_cells, vec![(1, 1), (2, 1)]);
+        assert!(!report.is_valid());
+        assert_eq!(report.problems().len(), 2);
+    }
+
+    #[test]
+    fn flow_into_nodata_is_reported_but_valid() {
+        let mut dem = ramp(6, 4, 10.0, 10.0, 0.0, 0.1);
+        dem.data[6 + 3] = f32::NAN;
+        let mut model = routed(dem, FlowMethod::D8);
+        // Routing steers around nodata; point one cell into the hole as an imported grid might
+        model.flow_directions[6 + 2] = FlowDirection::East;
+        model.compute_flow_accumulation();
+        let report = model.validate();
+        assert_eq!(report.valid_cell_count, 23);
+        assert_eq!(report.nodata_inflow_cells, vec![(2, 1)]);
+        assert!(report.is_valid(), "{:?}", report.problems());
+        assert_eq!(report.total_input, 23.0);
+    }
+
+    #[test]
+    fn validation_detects_dangling_receivers_and_wrong_totals() {
+        // MFD shares of one cell no longer summing to one
+        let mut model = routed(ramp(6, 4, 10.0, 10.0, 30.0_f32.to_radians(), 0.1), FlowMethod::MFD);
+        model.mfd_flow_proportions.as_mut().unwrap()[7] = [0.3, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        assert_eq!(model.validate().dangling_receiver_cells, vec![(1, 1)]);
+
+        // D∞ receiver that is not a direction index
+        let mut model = routed(ramp(6, 4, 10.0, 10.0, 30.0_f32.to_radians(), 0.1), FlowMethod::DInf);
+        model.dinf_flow.as_mut().unwrap().receiver_a[7] = 9;
+        let report = model.validate();
+        assert_eq!(report.dangling_receiver_cells, vec![(1, 1)]);
+        assert!(!report.is_valid());
+
+        // Accumulation of other weights than the ones validated against
+        let model = routed(ramp(6, 4, 10.0, 10.0, 0.0, 0.1), FlowMethod::D8);
+        let report = model.validate_accumulation(&model.flow_accumulation, &[2.0; 24]);
+        assert!((report.mass_balance_error - 0.5).abs() < 1e-9);
+        assert!(!report.is_valid());
+    }
+}
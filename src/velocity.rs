@@ -0,0 +1,289 @@
+use crate::flow::{FlowModel, StreamThreshold};
+use crate::polygon::Polygon;
+use crate::watershed;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, VecDeque};
+
+/// Manning's roughness coefficient for overland flow
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Roughness {
+    /// Same n for every cell
+    Uniform(f32),
+    /// One n per cell
+    PerCell(Vec<f32>),
+    /// Land-cover class per cell looked up in a table; classes missing from the table use `default_n`
+    LandCover {
+        classes: Vec<u16>,
+        table: HashMap<u16, f32>,
+        default_n: f32,
+    },
+}
+
+impl Roughness {
+    /// Manning's n of a cell
+    fn at(&self, idx: usize) -> f32 {
+        match self {
+            Roughness::Uniform(n) => *n,
+            Roughness::PerCell(values) => values[idx],
+            Roughness::LandCover { classes, table, default_n } => {
+                table.get(&classes[idx]).copied().unwrap_or(*default_n)
+            }
+        }
+    }
+
+    /// Number of cells the roughness grid covers, None for uniform roughness
+    fn len(&self) -> Option<usize> {
+        match self {
+            Roughness::Uniform(_) => None,
+            Roughness::PerCell(values) => Some(values.len()),
+            Roughness::LandCover { classes, .. } => Some(classes.len()),
+        }
+    }
+}
+
+/// Parameters of the Manning velocity model
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ManningParameters {
+    /// Cells at or above this threshold carry channel flow, all others sheet flow
+    pub channel_threshold: StreamThreshold,
+    /// Manning's n of channel cells (overland cells use the `Roughness`)
+    pub channel_n: f32,
+    /// Hydraulic radius of sheet flow in m
+    pub sheet_flow_depth_m: f32,
+    /// Channel hydraulic radius in m at 1 km² contributing area
+    pub channel_depth_coefficient: f32,
+    /// Exponent of the contributing area (km²) in the channel hydraulic radius
+    pub channel_depth_exponent: f32,
+    /// Slope used where the terrain is flatter, so flats still drain
+    pub min_slope: f32,
+    /// Velocity limits in m/s
+    pub min_velocity: f32,
+    pub max_velocity: f32,
+}
+
+impl Default for ManningParameters {
+    fn default() -> Self {
+        ManningParameters {
+            channel_threshold: StreamThreshold::Area(1.0e6),
+            channel_n: 0.035,
+            sheet_flow_depth_m: 0.01,
+            channel_depth_coefficient: 0.3,
+            channel_depth_exponent: 0.3,
+            min_slope: 1.0e-4,
+            min_velocity: 0.01,
+            max_velocity: 5.0,
+        }
+    }
+}
+
+/// Band of equal travel time to an outlet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IsochroneBand {
+    /// 1-based id as stored in `Isochrones::labels`
+    pub id: u32,
+    /// Travel time range [min_time_s, max_time_s) in s
+    pub min_time_s: f32,
+    pub max_time_s: f32,
+    pub cell_count: usize,
+    /// Area of the band in m² (the time-area histogram)
+    pub area_m2: f64,
+    /// Area reaching the outlet within `max_time_s`, in m²
+    pub cumulative_area_m2: f64,
+    /// Outline of the band in world coordinates
+    pub boundary: Vec<Polygon>,
+}
+
+/// Isochrone band of every cell plus the band table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Isochrones {
+    pub outlet: (usize, usize),
+    pub interval_s: f32,
+    /// Band id per cell (0 for cells that do not drain to the outlet)
+    pub labels: Vec<u32>,
+    pub bands: Vec<IsochroneBand>,
+}
+
+impl FlowModel {
+    /// Flow velocity of every cell in m/s from Manning's equation, v = R^(2/3) · S^(1/2) / n.
+    ///
+    /// Cells below the channel threshold carry sheet flow with a fixed hydraulic radius and
+    /// the overland roughness; channel cells use a hydraulic radius growing with contributing
+    /// area and the channel roughness. Nodata cells get 0.
+    pub fn compute_velocities(&self, roughness: &Roughness, params: &ManningParameters) -> Result<Vec<f32>, String> {
+        let width = self.dem.width;
+        let cell_count = width * self.dem.height;
+        if let Some(len) = roughness.len().filter(|&len| len != cell_count) {
+            return Err(format!("Roughness grid has {} values, expected {} ({}x{})",
+                               len, cell_count, width, self.dem.height));
+        }
+
+        Ok(self.manning_velocities(|idx| roughness.at(idx), params))
+    }
+
+    /// `compute_velocities` with the same overland roughness `n` for every cell, which
+    /// needs no roughness grid and therefore cannot fail
+    pub fn uniform_velocities(&self, n: f32, params: &ManningParameters) -> Vec<f32> {
+        self.manning_velocities(|_| n, params)
+    }
+
+    fn manning_velocities(&self, overland_n: impl Fn(usize) -> f32, params: &ManningParameters) -> Vec<f32> {
+        let width = self.dem.width;
+        let is_channel = self.stream_mask(params.channel_threshold);
+        let area_km2 = self.contributing_area_km2();
+
+        (0..width * self.dem.height)
+            .map(|idx| {
+                if self.dem.get_elevation(idx % width, idx / width).is_none() {
+                    return 0.0;
+                }
+
+                let (n, radius) = if is_channel[idx] {
+                    (params.channel_n, params.channel_depth_coefficient * area_km2[idx].powf(params.channel_depth_exponent))
+                } else {
+                    (overland_n(idx), params.sheet_flow_depth_m)
+                };
+                let slope = self.slopes[idx].max(params.min_slope);
+                let velocity = radius.powf(2.0 / 3.0) * slope.sqrt() / n.max(f32::EPSILON);
+                velocity.clamp(params.min_velocity, params.max_velocity)
+            })
+            .collect()
+    }
+
+    /// Travel time in s from every cell to `outlet` along the downstream path, crossing
+    /// each cell at its own velocity. None for cells that do not drain to the outlet.
+    pub fn travel_time_to(&self, outlet: (usize, usize), velocities: &[f32]) -> Vec<Option<f32>> {
+        let width = self.dem.width;
+        let outlet_idx = outlet.1 * width + outlet.0;
+
+        let mut times: Vec<Option<f64>> = vec![None; width * self.dem.height];
+        times[outlet_idx] = Some(0.0);
+        let mut queue = VecDeque::from([outlet_idx]);
+        while let Some(idx) = queue.pop_front() {
+            let time = times[idx].unwrap_or(0.0);
+            for upstream_idx in self.upstream_indices(idx) {
+                // Each cell has a single downstream path, so only a cycle can lead back to a timed cell
+                if times[upstream_idx].is_some() {
+                    continue;
+                }
+                let velocity = velocities[upstream_idx].max(f32::EPSILON) as f64;
+                times[upstream_idx] = Some(time + self.step_length(upstream_idx, idx) / velocity);
+                queue.push_back(upstream_idx);
+            }
+        }
+
+        times.into_iter().map(|time| time.map(|t| t as f32)).collect()
+    }
+
+    /// Group the cells draining to `outlet` into bands of `interval_s` seconds travel time.
+    /// The band areas form the time-area histogram of the watershed.
+    pub fn isochrones(&self, outlet: (usize, usize), velocities: &[f32], interval_s: f32) -> Isochrones {
+        let times = self.travel_time_to(outlet, velocities);
+        let interval_s = interval_s.max(f32::EPSILON);
+
+        let labels: Vec<u32> = times.iter()
+            .map(|time| time.map_or(0, |t| (t / interval_s) as u32 + 1))
+            .collect();
+        let band_count = labels.iter().copied().max().unwrap_or(0) as usize;
+
+        let cell_area = self.dem.cell_area();
+        let mut cell_counts = vec![0usize; band_count];
+        for &id in labels.iter().filter(|&&id| id != 0) {
+            cell_counts[(id - 1) as usize] += 1;
+        }
+
+        let mut cumulative_area_m2 = 0.0;
+        let bands = watershed::vectorize_labels(&self.dem, &labels, band_count)
+            .into_iter()
+            .enumerate()
+            .map(|(i, boundary)| {
+                let area_m2 = cell_counts[i] as f64 * cell_area;
+                cumulative_area_m2 += area_m2;
+                IsochroneBand {
+                    id: i as u32 + 1,
+                    min_time_s: i as f32 * interval_s,
+                    max_time_s: (i + 1) as f32 * interval_s,
+                    cell_count: cell_counts[i],
+                    area_m2,
+                    cumulative_area_m2,
+                    boundary,
+                }
+            })
+            .collect();
+
+        println!("Computed {} isochrone bands of {} s for outlet ({}, {})", band_count, interval_s, outlet.0, outlet.1);
+        Isochrones { outlet, interval_s, labels, bands }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::FlowMethod;
+    use crate::test_support::{cyclic_pair, ramp, routed};
+
+    /// Eastward ramp with slope 0.1 on 20 m × 10 m cells; every cell carries sheet flow
+    fn eastward() -> FlowModel {
+        routed(ramp(6, 4, 20.0, 10.0, 0.0, 0.1), FlowMethod::D8)
+    }
+
+    fn sheet_flow_velocity(n: f32) -> f32 {
+        0.01_f32.powf(2.0 / 3.0) * 0.1_f32.sqrt() / n
+    }
+
+    #[test]
+    fn manning_travel_times_and_isochrones() {
+        let model = eastward();
+        let params = ManningParameters::default();
+        let velocities = model.compute_velocities(&Roughness::Uniform(0.1), &params).unwrap();
+        assert_eq!(model.uniform_velocities(0.1, &params), velocities);
+        let expected = sheet_flow_velocity(0.1);
+        assert!((velocities[6] - expected).abs() < 1e-5);
+        assert!(model.compute_velocities(&Roughness::PerCell(vec![0.1; 3]), &params).is_err());
+
+        // Only row 1 drains to its edge cell, 20 m per step
+        let times = model.travel_time_to((5, 1), &velocities);
+        assert_eq!(times[11], Some(0.0));
+        assert!((times[6].unwrap() - 100.0 / expected).abs() < 0.1);
+        assert_eq!(times[0], None);
+
+        let isochrones = model.isochrones((5, 1), &velocities, 300.0);
+        let total: f64 = isochrones.bands.iter().map(|band| band.area_m2).sum();
+        assert_eq!(total, 1200.0);
+        assert_eq!(isochrones.bands.last().unwrap().cumulative_area_m2, 1200.0);
+        assert_eq!(isochrones.labels[6], 3);
+    }
+
+    #[test]
+    fn roughness_lookup_clamps_and_nodata() {
+        let mut dem = ramp(6, 4, 20.0, 10.0, 0.0, 0.1);
+        dem.data[0] = f32::NAN;
+        let model = routed(dem, FlowMethod::D8);
+        let params = ManningParameters { min_velocity: 0.05, max_velocity: 0.2, ..ManningParameters::default() };
+
+        // Class 1 is in the table, class 2 falls back to the default
+        let classes: Vec<u16> = (0..24).map(|idx| if idx % 6 < 3 { 1 } else { 2 }).collect();
+        let roughness = Roughness::LandCover { classes, table: HashMap::from([(1, 0.2)]), default_n: 0.02 };
+        let velocities = model.compute_velocities(&roughness, &params).unwrap();
+        assert_eq!(velocities[0], 0.0);
+        assert!((velocities[7] - sheet_flow_velocity(0.2).max(0.05)).abs() < 1e-6);
+        assert_eq!(velocities[10], 0.2);
+        assert!(velocities[1..].iter().all(|&v| (0.05..=0.2).contains(&v)));
+    }
+
+    #[test]
+    fn an_outlet_without_upstream_cells_is_a_single_band() {
+        let model = eastward();
+        let isochrones = model.isochrones((0, 2), &[1.0; 24], 60.0);
+        assert_eq!(isochrones.bands.len(), 1);
+        assert_eq!((isochrones.bands[0].cell_count, isochrones.bands[0].area_m2), (1, 200.0));
+        assert_eq!(isochrones.labels.iter().filter(|&&id| id != 0).count(), 1);
+    }
+
+    #[test]
+    fn travel_times_stop_on_cyclic_directions() {
+        let model = cyclic_pair();
+        assert_eq!(model.travel_time_to((1, 0), &[2.0, 2.0]), vec![Some(5.0), Some(0.0)]);
+        let isochrones = model.isochrones((1, 0), &[2.0, 2.0], 10.0);
+        assert_eq!(isochrones.labels, vec![1, 1]);
+    }
+}
@@ -1,7 +1,11 @@
 use crate::flow::FlowModel;
-use crate::flow::FlowDirection;
+use crate::flow::{FlowDirection, FlowMethod, StreamThreshold};
+use crate::velocity::ManningParameters;
 use serde::{Serialize, Deserialize};
 
+/// Manning's n of overland flow used for visualization velocities (short grass)
+const DEFAULT_OVERLAND_N: f32 = 0.15;
+
 /// Data structure for particle-based water flow visualization
 #[derive(Serialize, Deserialize)]
 pub struct WaterFlowVisualizationData {
@@ -13,7 +17,7 @@ pub struct WaterFlowVisualizationData {
     pub flow_accumulation: Vec<f32>,
     /// Slope values for each cell
     pub slopes: Vec<f32>,
-    /// Flow velocity vectors for each cell (x, y components - 2 values per cell)
+    /// Flow velocity vectors in m/s for each cell (x, y components - 2 values per cell)
     pub velocities: Vec<f32>,
     /// Suggested particle spawn points (x, y coordinates) for high-flow areas
     pub spawn_points: Vec<(usize, usize)>,
@@ -54,6 +58,9 @@ pub fn generate_visualization_data(flow_model: &FlowModel) -> WaterFlowVisualiza
     let max_flow = flow_model.flow_accumulation.iter()
         .fold(0.0_f32, |max_val, &val| max_val.max(val));
     
+    // Flow velocities from Manning's equation with default roughness
+    let velocities = flow_model.uniform_velocities(DEFAULT_OVERLAND_N, &ManningParameters::default());
+    
     // Threshold for identifying major streams (top 1% of flow)
     let stream_threshold = max_flow * 0.01;
     
@@ -70,8 +77,7 @@ pub fn generate_visualization_data(flow_model: &FlowModel) -> WaterFlowVisualiza
                 continue;
             }
             
-            // Get slope and flow values
-            let slope = flow_model.slopes[idx];
+            // Get flow value
             let flow = flow_model.flow_accumulation[idx];
             
             // Get flow direction to determine velocity vector components
@@ -80,28 +86,18 @@ pub fn generate_visualization_data(flow_model: &FlowModel) -> WaterFlowVisualiza
             // Convert direction to offsets
             let (dx, dy) = direction.get_offset();
             
-            // Convert offsets to unit vector
+            // Convert offsets to unit vector; D∞ has a continuous angle we can use directly
             let length = ((dx * dx + dy * dy) as f32).sqrt();
-            let (dx_norm, dy_norm) = if length > 0.0 {
-                (dx as f32 / length, dy as f32 / length)
-            } else {
-                (0.0, 0.0)
+            let (dx_norm, dy_norm) = match &flow_model.dinf_flow {
+                Some(dinf) if length > 0.0 && flow_model.flow_method == FlowMethod::DInf => {
+                    (dinf.angles[idx].cos(), dinf.angles[idx].sin())
+                }
+                _ if length > 0.0 => (dx as f32 / length, dy as f32 / length),
+                _ => (0.0, 0.0),
             };
             
-            // Calculate velocity magnitude based on a simplified velocity = f(slope, flow) model
-            // This is an approximation of Manning's equation for water velocity
-            let velocity_magnitude = if slope > 0.0 && flow > 1.0 {
-                // Base velocity on slope (steeper = faster)
-                let slope_factor = slope.powf(0.5);
-                
-                // Flow factor (more flow = higher velocity due to hydraulic radius)
-                let flow_factor = (flow / max_flow).powf(0.4);
-                
-                // Combine factors - this is a simplified model, not physically accurate
-                slope_factor * flow_factor * 10.0 // Scale factor
-            } else {
-                0.0
-            };
+            // Manning velocity in m/s
+            let velocity_magnitude = velocities[idx];
             
             // Set velocity vector components
             viz_data.velocities[vel_idx] = dx_norm * velocity_magnitude;     // x-component
@@ -146,37 +142,15 @@ pub fn generate_visualization_data(flow_model: &FlowModel) -> WaterFlowVisualiza
 /// Determine if a cell is a significant stream feature (e.g., junction or main channel)
 fn is_stream_feature(flow_model: &FlowModel, x: usize, y: usize) -> bool {
     let width = flow_model.dem.width;
-    let height = flow_model.dem.height;
     let idx = y * width + x;
     let cell_flow = flow_model.flow_accumulation[idx];
     
-    // Check all 8 neighbors
-    let neighbors = [
-        (1, 0),    // East
-        (1, 1),    // Southeast
-        (0, 1),    // South
-        (-1, 1),   // Southwest
-        (-1, 0),   // West
-        (-1, -1),  // Northwest
-        (0, -1),   // North
-        (1, -1),   // Northeast
-    ];
-    
     // Count neighbors with significant flow
     let mut inflow_count = 0;
     let mut max_inflow: f32 = 0.0;
     
-    for &(dx, dy) in &neighbors {
-        let nx = x as isize + dx;
-        let ny = y as isize + dy;
-        
-        // Skip if outside bounds
-        if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
-            continue;
-        }
-        
-        let nx = nx as usize;
-        let ny = ny as usize;
+    // Check all neighbors in the model's neighborhood
+    for (_, (nx, ny)) in flow_model.neighborhood().neighbors(x, y) {
         let n_idx = ny * width + nx;
         
         // Get flow and direction of this neighbor
@@ -203,29 +177,18 @@ fn is_stream_feature(flow_model: &FlowModel, x: usize, y: usize) -> bool {
 }
 
 /// Generate a list of stream polylines for visualization
-pub fn generate_stream_network(flow_model: &FlowModel, threshold_percentile: f32, smooth_iterations: usize) -> Vec<Vec<(usize, usize)>> {
-    println!("Generating stream network with threshold {}", threshold_percentile);
+pub fn generate_stream_network(flow_model: &FlowModel, threshold: StreamThreshold, smooth_iterations: usize) -> Vec<Vec<(usize, usize)>> {
+    println!("Generating stream network with threshold {:?}", threshold);
     
     let width = flow_model.dem.width;
     let height = flow_model.dem.height;
     
-    // Calculate max flow accumulation
-    let max_flow = flow_model.flow_accumulation.iter()
-        .fold(0.0_f32, |max_val, &val| max_val.max(val));
-    
-    // Calculate threshold
-    let threshold = max_flow * threshold_percentile;
-    
-    // Find all cells above threshold
-    let mut stream_cells = Vec::new();
-    for y in 0..height {
-        for x in 0..width {
-            let idx = y * width + x;
-            if flow_model.flow_accumulation[idx] >= threshold {
-                stream_cells.push((x, y));
-            }
-        }
-    }
+    // Find all cells meeting the stream initiation criterion
+    let is_stream = flow_model.stream_mask(threshold);
+    let stream_cells: Vec<(usize, usize)> = (0..width * height)
+        .filter(|&idx| is_stream[idx])
+        .map(|idx| (idx % width, idx / width))
+        .collect();
     
     println!("Found {} cells above threshold", stream_cells.len());
     
@@ -261,8 +224,8 @@ pub fn generate_stream_network(flow_model: &FlowModel, threshold_percentile: f32
                 let (dx, dy) = downstream;
                 let d_idx = dy * width + dx;
                 
-                // Stop if downstream is already visited or below threshold
-                if visited[d_idx] || flow_model.flow_accumulation[d_idx] < threshold {
+                // Stop if downstream is already visited or not a stream cell
+                if visited[d_idx] || !is_stream[d_idx] {
                     break;
                 }
                 
@@ -334,12 +297,12 @@ pub fn smooth_stream_polylines(polylines: &mut Vec<Vec<(usize, usize)>>, iterati
 }
 
 /// Generate a higher quality stream network with less downsampling
-pub fn generate_high_quality_streams(flow_model: &FlowModel, threshold_percentile: f32) -> Vec<Vec<(usize, usize)>> {
+pub fn generate_high_quality_streams(flow_model: &FlowModel, threshold: StreamThreshold) -> Vec<Vec<(usize, usize)>> {
     // Add logging here
     #[cfg(target_arch = "wasm32")]
-    web_sys::console::log_1(&format!("Generating high-quality streams with percentile: {}", threshold_percentile).into());
+    web_sys::console::log_1(&format!("Generating high-quality streams with threshold: {:?}", threshold).into());
     #[cfg(not(target_arch = "wasm32"))]
-    println!("Generating high-quality streams with percentile: {}", threshold_percentile);
+    println!("Generating high-quality streams with threshold: {:?}", threshold);
 
     // Calculate max flow accumulation for logging purposes
     let max_flow = flow_model.flow_accumulation.iter()
@@ -350,7 +313,7 @@ pub fn generate_high_quality_streams(flow_model: &FlowModel, threshold_percentil
     println!("Max flow accumulation: {}", max_flow);
     
     // Calculate the actual threshold value
-    let actual_threshold = max_flow * threshold_percentile;
+    let actual_threshold = threshold.accumulation_threshold(flow_model);
     #[cfg(target_arch = "wasm32")]
     web_sys::console::log_1(&format!("Actual threshold value: {}", actual_threshold).into());
     #[cfg(not(target_arch = "wasm32"))]
@@ -358,7 +321,7 @@ pub fn generate_high_quality_streams(flow_model: &FlowModel, threshold_percentil
 
 
     // Extract high-quality streams using the dedicated method
-    let polylines = flow_model.extract_high_quality_streams(threshold_percentile);
+    let polylines = flow_model.extract_high_quality_streams(threshold);
     
     // Log information about the result
     #[cfg(target_arch = "wasm32")]
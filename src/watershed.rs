@@ -0,0 +1,448 @@
+use crate::dem::DigitalElevationModel;
+use crate::flow::{FlowMethod, FlowModel};
+use crate::polygon::Polygon;
+use serde::{Serialize, Deserialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+
+/// Share of its flow a cell must send to the outlet to lie inside the boundary polygon
+pub const BOUNDARY_FRACTION: f32 = 0.5;
+
+/// Catchment upstream of a pour point
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Watershed {
+    /// Pour point as given, in world coordinates
+    pub pour_point: (f64, f64),
+    /// Outlet cell after snapping to the highest accumulation within the search radius
+    pub outlet: (usize, usize),
+    /// Share (0-1) of each cell's flow that passes through the outlet.
+    /// Always 0 or 1 for D8; fractional along divides for D∞ and MFD.
+    pub mask: Vec<f32>,
+    /// Number of cells that contribute any flow
+    pub cell_count: usize,
+    /// Contributing area in m², with every cell weighted by its share
+    pub area_m2: f64,
+    /// Outline in world coordinates of the cells sending at least half of their flow to the outlet
+    pub boundary: Vec<Polygon>,
+}
+
+impl FlowModel {
+    /// Delineate the watershed of every pour point (world coordinates).
+    /// Each point is snapped to the highest-accumulation cell within `snap_radius` map
+    /// units; points outside the DEM are skipped.
+    pub fn delineate_watersheds(&self, pour_points: &[(f64, f64)], snap_radius: f64) -> Vec<Watershed> {
+        pour_points.iter()
+            .filter_map(|&point| {
+                let watershed = self.delineate_watershed(point, snap_radius);
+                if watershed.is_none() {
+                    println!("Pour point ({}, {}) lies outside the DEM", point.0, point.1);
+                }
+                watershed
+            })
+            .collect()
+    }
+
+    /// Delineate the watershed upstream of a single pour point (world coordinates)
+    pub fn delineate_watershed(&self, pour_point: (f64, f64), snap_radius: f64) -> Option<Watershed> {
+        let (x, y) = self.dem.cell_at(pour_point.0, pour_point.1)?;
+        let outlet = self.snap_to_stream(x, y, snap_radius);
+        let mask = self.upstream_fractions(outlet.1 * self.dem.width + outlet.0);
+
+        let cell_area = self.dem.cell_area();
+        let cell_count = mask.iter().filter(|&&fraction| fraction > 0.0).count();
+        let area_m2 = mask.iter().map(|&fraction| fraction as f64 * cell_area).sum();
+
+        let inside: Vec<bool> = mask.iter().map(|&fraction| fraction >= BOUNDARY_FRACTION).collect();
+        let boundary = vectorize_mask(&self.dem, &inside);
+
+        println!("Watershed at cell ({}, {}): {} cells, {:.3} km²",
+                 outlet.0, outlet.1, cell_count, area_m2 / 1.0e6);
+
+        Some(Watershed { pour_point, outlet, mask, cell_count, area_m2, boundary })
+    }
+
+    /// Cell with the highest flow accumulation within `radius` map units of (x, y).
+    /// Ties go to the cell closest to the starting point.
+    pub fn snap_to_stream(&self, x: usize, y: usize, radius: f64) -> (usize, usize) {
+        let width = self.dem.width;
+        let (x_res, y_res) = (self.dem.x_resolution, self.dem.y_resolution);
+        let reach_x = (radius / x_res).floor().max(0.0) as usize;
+        let reach_y = (radius / y_res).floor().max(0.0) as usize;
+
+        let mut best = (x, y);
+        let mut best_key = (self.flow_accumulation[y * width + x], 0.0);
+
+        for ny in y.saturating_sub(reach_y)..=(y + reach_y).min(self.dem.height - 1) {
+            for nx in x.saturating_sub(reach_x)..=(x + reach_x).min(width - 1) {
+                let distance = ((nx as f64 - x as f64) * x_res).hypot((ny as f64 - y as f64) * y_res);
+                if distance > radius || self.dem.get_elevation(nx, ny).is_none() {
+                    continue;
+                }
+
+                let accumulation = self.flow_accumulation[ny * width + nx];
+                if accumulation > best_key.0 || (accumulation == best_key.0 && distance < best_key.1) {
+                    best = (nx, ny);
+                    best_key = (accumulation, distance);
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Share of every cell's flow that eventually passes through `outlet_idx`.
+    ///
+    /// Upstream cells are found by walking donor links from the outlet. The shares are
+    /// then resolved downstream-first: a cell's share is the proportion-weighted share
+    /// of its receivers, so D8 yields 0/1 and D∞/MFD yield fractions along divides.
+    pub fn upstream_fractions(&self, outlet_idx: usize) -> Vec<f32> {
+        let mut fractions = vec![0.0f32; self.dem.width * self.dem.height];
+        for (idx, fraction) in self.upstream_area(outlet_idx).0 {
+            fractions[idx] = fraction;
+        }
+        fractions
+    }
+
+    /// `upstream_fractions` of the upstream cells only, plus their indices outlet first.
+    /// Memory scales with the upstream area rather than the grid.
+    pub fn upstream_area(&self, outlet_idx: usize) -> (HashMap<usize, f32>, Vec<usize>) {
+        // Collect the upstream area breadth-first, using the member list as the queue
+        let mut fractions = HashMap::from([(outlet_idx, 1.0f32)]);
+        let mut members = vec![outlet_idx];
+        let mut next = 0;
+        while let Some(&idx) = members.get(next) {
+            next += 1;
+            for (upstream_idx, _) in self.donors(idx) {
+                if let Entry::Vacant(entry) = fractions.entry(upstream_idx) {
+                    entry.insert(1.0);
+                    members.push(upstream_idx);
+                }
+            }
+        }
+
+        // With a single receiver per cell everything upstream drains through the outlet
+        let divergent = matches!(
+            (self.flow_method, &self.dinf_flow, &self.mfd_flow_proportions),
+            (FlowMethod::DInf, Some(_), _) | (FlowMethod::MFD, _, Some(_))
+        );
+        if !divergent {
+            return (fractions, members);
+        }
+
+        // Receivers inside the upstream area that each cell still waits for
+        let mut pending: HashMap<usize, u8> = HashMap::with_capacity(members.len());
+        for &idx in &members[1..] {
+            let count = self.receivers(idx)
+                .filter(|(downstream_idx, _)| fractions.contains_key(downstream_idx))
+                .count() as u8;
+            pending.insert(idx, count);
+        }
+
+        // Resolve shares downstream-first, starting at the outlet
+        for &idx in &members[1..] {
+            fractions.insert(idx, 0.0);
+        }
+        let mut queue = VecDeque::from([outlet_idx]);
+        while let Some(idx) = queue.pop_front() {
+            for (upstream_idx, _) in self.donors(idx) {
+                let Some(waiting) = pending.get_mut(&upstream_idx) else {
+                    continue;
+                };
+                *waiting -= 1;
+                if *waiting == 0 {
+                    let fraction = self.receivers(upstream_idx)
+                        .map(|(downstream_idx, proportion)| proportion * fractions.get(&downstream_idx).copied().unwrap_or(0.0))
+                        .sum::<f32>()
+                        .min(1.0);
+                    fractions.insert(upstream_idx, fraction);
+                    queue.push_back(upstream_idx);
+                }
+            }
+        }
+
+        (fractions, members)
+    }
+}
+
+/// Trace the outline of a cell mask along cell edges.
+///
+/// Returns one polygon per connected region (cells touching only at a corner form
+/// separate polygons), with enclosed gaps as holes, in world coordinates.
+pub fn vectorize_mask(dem: &DigitalElevationModel, inside: &[bool]) -> Vec<Polygon> {
+    let labels: Vec<u32> = inside.iter().map(|&is_inside| is_inside as u32).collect();
+    vectorize_labels(dem, &labels, 1).pop().unwrap_or_default()
+}
+
+/// Trace the outlines of every label 1..=label_count in one pass over the grid.
+/// Label 0 is treated as background. Entry `i` of the result holds the polygons of label `i + 1`.
+pub fn vectorize_labels(dem: &DigitalElevationModel, labels: &[u32], label_count: usize) -> Vec<Vec<Polygon>> {
+    let width = dem.width;
+    let height = dem.height;
+    let label_at = |x: isize, y: isize| {
+        if x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height {
+            labels[y as usize * width + x as usize]
+        } else {
+            0
+        }
+    };
+
+    // Directed boundary edges between grid corners, walking clockwise on screen
+    // (y pointing down) so the labelled cells are always on the right
+    let mut edges: Vec<EdgeMap> = vec![HashMap::new(); label_count];
+    for y in 0..height {
+        for x in 0..width {
+            let label = labels[y * width + x];
+            if label == 0 || label as usize > label_count {
+                continue;
+            }
+            add_cell_edges(&mut edges[label as usize - 1], x, y, |nx, ny| label_at(nx, ny) == label);
+        }
+    }
+
+    edges.into_iter().map(|label_edges| polygons_from_edges(dem, label_edges)).collect()
+}
+
+/// Trace the outline of the cells (grid indices) for which `inside` holds, like `vectorize_mask`
+/// but in time proportional to the number of cells rather than the grid size
+pub fn vectorize_cells(dem: &DigitalElevationModel, cells: &[usize], inside: impl Fn(usize) -> bool) -> Vec<Polygon> {
+    let width = dem.width;
+    let is_inside = |x: isize, y: isize| {
+        x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < dem.height
+            && inside(y as usize * width + x as usize)
+    };
+
+    let mut edges = EdgeMap::new();
+    for &idx in cells.iter().filter(|&&idx| inside(idx)) {
+        add_cell_edges(&mut edges, idx % width, idx / width, is_inside);
+    }
+    polygons_from_edges(dem, edges)
+}
+
+/// Add the edges of cell (x, y) that border a cell for which `same` is false.
+/// Edges run clockwise on screen (y pointing down) so the cell is always on the right.
+fn add_cell_edges(edges: &mut EdgeMap, x: usize, y: usize, same: impl Fn(isize, isize) -> bool) {
+    let (xi, yi) = (x as isize, y as isize);
+    if !same(xi, yi - 1) {
+        edges.entry((x, y)).or_default().push((x + 1, y));
+    }
+    if !same(xi + 1, yi) {
+        edges.entry((x + 1, y)).or_default().push((x + 1, y + 1));
+    }
+    if !same(xi, yi + 1) {
+        edges.entry((x + 1, y + 1)).or_default().push((x, y + 1));
+    }
+    if !same(xi - 1, yi) {
+        edges.entry((x, y + 1)).or_default().push((x, y));
+    }
+}
+
+/// Outgoing boundary edges per grid corner
+type EdgeMap = HashMap<(usize, usize), Vec<(usize, usize)>>;
+
+/// Chain boundary edges into rings and group them into polygons with holes
+fn polygons_from_edges(dem: &DigitalElevationModel, mut edges: EdgeMap) -> Vec<Polygon> {
+    // Chain edges into closed rings
+    let mut rings: Vec<Vec<(usize, usize)>> = Vec::new();
+    let mut starts: Vec<(usize, usize)> = edges.keys().copied().collect();
+    starts.sort_unstable_by_key(|&(x, y)| (y, x));
+    for start in starts {
+        while let Some(first) = edges.get_mut(&start).and_then(|targets| targets.pop()) {
+            let mut ring = vec![start];
+            let mut previous = start;
+            let mut current = first;
+
+            while current != start {
+                ring.push(current);
+                let next = take_next_edge(&mut edges, previous, current);
+                previous = current;
+                current = match next {
+                    Some(next) => next,
+                    None => break, // Unreachable for a consistent edge set
+                };
+            }
+
+            rings.push(remove_collinear(ring));
+        }
+    }
+
+    // Clockwise rings (positive area with y down) are outlines, the others are holes
+    let (outlines, holes): (Vec<_>, Vec<_>) = rings.into_iter().partition(|ring| signed_area(ring) > 0.0);
+    let as_polygon = |ring: &[(usize, usize)]| Polygon {
+        exterior: ring.iter().map(|&(x, y)| (x as f64, y as f64)).collect(),
+        holes: Vec::new(),
+    };
+    let outline_polygons: Vec<Polygon> = outlines.iter().map(|ring| as_polygon(ring)).collect();
+    let mut hole_lists: Vec<Vec<Vec<(usize, usize)>>> = vec![Vec::new(); outlines.len()];
+
+    for hole in holes {
+        // The midpoint of a vertical hole edge is never on a horizontal grid line
+        let probe = hole.iter()
+            .zip(hole.iter().cycle().skip(1))
+            .find(|(a, b)| a.0 == b.0)
+            .map(|(a, b)| (a.0 as f64, (a.1 + b.1) as f64 / 2.0));
+        let Some((px, py)) = probe else { continue };
+
+        // Assign the hole to the smallest outline around it
+        let owner = (0..outlines.len())
+            .filter(|&i| outline_polygons[i].contains(px, py))
+            .min_by(|&a, &b| signed_area(&outlines[a]).total_cmp(&signed_area(&outlines[b])));
+        if let Some(owner) = owner {
+            hole_lists[owner].push(hole);
+        }
+    }
+
+    let to_world = |ring: &[(usize, usize)]| -> Vec<(f64, f64)> {
+        ring.iter().map(|&(x, y)| dem.grid_to_geo(x, y)).collect()
+    };
+    outlines.iter()
+        .zip(hole_lists)
+        .map(|(outline, holes)| Polygon {
+            exterior: to_world(outline),
+            holes: holes.iter().map(|hole| to_world(hole)).collect(),
+        })
+        .collect()
+}
+
+/// Take the outgoing edge at `current`, preferring a right turn, then straight, then left.
+/// Turning right keeps regions that touch at a corner in separate rings.
+fn take_next_edge(edges: &mut EdgeMap, previous: (usize, usize), current: (usize, usize)) -> Option<(usize, usize)> {
+    let targets = edges.get_mut(&current)?;
+    let heading = (current.0 as isize - previous.0 as isize, current.1 as isize - previous.1 as isize);
+    let turns = [(-heading.1, heading.0), heading, (heading.1, -heading.0)];
+
+    let position = turns.iter().find_map(|turn| {
+        targets.iter().position(|&(tx, ty)| {
+            (tx as isize - current.0 as isize, ty as isize - current.1 as isize) == *turn
+        })
+    })?;
+    Some(targets.swap_remove(position))
+}
+
+/// Drop vertices that lie on a straight line between their neighbors
+fn remove_collinear(ring: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    let n = ring.len();
+    if n < 4 {
+        return ring;
+    }
+
+    (0..n)
+        .filter(|&i| {
+            let (a, b, c) = (ring[(i + n - 1) % n], ring[i], ring[(i + 1) % n]);
+            let cross = (b.0 as isize - a.0 as isize) * (c.1 as isize - b.1 as isize) -
+                (b.1 as isize - a.1 as isize) * (c.0 as isize - b.0 as isize);
+            cross != 0
+        })
+        .map(|i| ring[i])
+        .collect()
+}
+
+/// Shoelace area of a ring in grid units (positive for clockwise on screen)
+fn signed_area(ring: &[(usize, usize)]) -> f64 {
+    let n = ring.len();
+    (0..n)
+        .map(|i| {
+            let (a, b) = (ring[i], ring[(i + 1) % n]);
+            a.0 as f64 * b.1 as f64 - b.0 as f64 * a.1 as f64
+        })
+        .sum::<f64>() / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_dem(width: usize, height: usize) -> DigitalElevationModel {
+        DigitalElevationModel::new(width, height, 1.0, vec![10.0; width * height])
+    }
+
+    fn mask(width: usize, rows: &[&str]) -> Vec<bool> {
+        let inside: Vec<bool> = rows.iter().flat_map(|row| row.chars().map(|c| c == '#')).collect();
+        assert_eq!(inside.len(), width * rows.len());
+        inside
+    }
+
+    #[test]
+    fn ring_of_cells_has_one_hole() {
+        let dem = unit_dem(5, 5);
+        let polygons = vectorize_mask(&dem, &mask(5, &[".....", ".###.", ".#.#.", ".###.", "....."]));
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(polygons[0].exterior, vec![(1.0, 1.0), (4.0, 1.0), (4.0, 4.0), (1.0, 4.0)]);
+        assert_eq!(polygons[0].holes, vec![vec![(2.0, 2.0), (2.0, 3.0), (3.0, 3.0), (3.0, 2.0)]]);
+        assert!(polygons[0].contains(1.5, 2.5) && !polygons[0].contains(2.5, 2.5));
+    }
+
+    #[test]
+    fn regions_touching_at_a_corner_are_separate_parts() {
+        let dem = unit_dem(4, 3);
+        let polygons = vectorize_mask(&dem, &mask(4, &["##..", "##..", "..##"]));
+        let exteriors: Vec<_> = polygons.iter().map(|polygon| polygon.exterior.clone()).collect();
+        assert_eq!(exteriors, vec![
+            vec![(0.0, 0.0), (2.0, 0.0), (2.0, 2.0), (0.0, 2.0)],
+            vec![(2.0, 2.0), (4.0, 2.0), (4.0, 3.0), (2.0, 3.0)],
+        ]);
+        assert!(polygons.iter().all(|polygon| polygon.holes.is_empty()));
+
+        // Labels are traced separately; cells of one label in two places give two parts
+        let labels = [1, 1, 2, 1, 0, 2, 2, 2, 1];
+        let by_label = vectorize_labels(&unit_dem(3, 3), &labels, 2);
+        assert_eq!(by_label.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 2]);
+        assert_eq!(by_label[0][1].exterior, vec![(2.0, 2.0), (3.0, 2.0), (3.0, 3.0), (2.0, 3.0)]);
+
+        // The cell-list tracer agrees with the full-grid one
+        let cells: Vec<usize> = (0..9).collect();
+        let by_cells = vectorize_cells(&unit_dem(3, 3), &cells, |idx| labels[idx] == 2);
+        let outlines = |polygons: &[Polygon]| polygons.iter().map(|polygon| polygon.exterior.clone()).collect::<Vec<_>>();
+        assert_eq!(outlines(&by_cells), outlines(&by_label[1]));
+    }
+
+    #[test]
+    fn watershed_boundary_follows_the_divide() {
+        // Two valleys along columns 1 and 4 falling to the south, split between columns 2 and 3
+        let data = (0..6 * 4)
+            .map(|idx| {
+                let (x, y) = ((idx % 6) as f32, (idx / 6) as f32);
+                let valley = if x < 3.0 { 1.0 } else { 4.0 };
+                10.0 + 2.0 * (x - valley).abs() + (3.0 - y)
+            })
+            .collect();
+        let mut model = FlowModel::new(DigitalElevationModel::new(6, 4, 1.0, data));
+        model.compute_flow_directions();
+        model.compute_flow_accumulation();
+
+        let watershed = model.delineate_watershed((1.5, 3.5), 0.0).unwrap();
+        assert_eq!((watershed.outlet, watershed.cell_count, watershed.area_m2), ((1, 3), 12, 12.0));
+        assert_eq!(watershed.boundary.len(), 1);
+        assert_eq!(watershed.boundary[0].exterior, vec![(0.0, 0.0), (3.0, 0.0), (3.0, 4.0), (0.0, 4.0)]);
+        assert!(watershed.boundary[0].holes.is_empty());
+    }
+
+    #[test]
+    fn sparse_upstream_trace_matches_the_full_grid_shares() {
+        use crate::flow::MfdOptions;
+
+        // Cone draining radially: MFD splits flow between neighbouring outlets
+        let data = (0..7 * 7)
+            .map(|idx| {
+                let (x, y) = ((idx % 7) as f32 - 3.0, (idx / 7) as f32 - 3.0);
+                20.0 - x.hypot(y)
+            })
+            .collect();
+        let mut model = FlowModel::new(DigitalElevationModel::new(7, 7, 1.0, data));
+        model.compute_flow_directions_mfd(MfdOptions::default());
+        model.compute_flow_accumulation();
+
+        let outlet = 5 * 7 + 4;
+        let fractions = model.upstream_fractions(outlet);
+        assert!(fractions.iter().any(|&fraction| fraction > 0.0 && fraction < 1.0));
+
+        let trace = model.trace_upstream(4, 5);
+        let (min_x, min_y, max_x, max_y) = trace.bounds;
+        let cropped: Vec<f32> = (min_y..=max_y)
+            .flat_map(|y| (min_x..=max_x).map(move |x| y * 7 + x))
+            .map(|idx| fractions[idx])
+            .collect();
+        assert_eq!(trace.mask, cropped);
+        let total: f64 = fractions.iter().map(|&fraction| fraction as f64).sum();
+        assert!((trace.area_m2 - total).abs() < 1e-6);
+    }
+}